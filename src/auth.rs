@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{Engine as _, engine::general_purpose};
+use rand::Rng;
+use ring::hmac;
+
+// --- RELAY SECRET ---
+// Client and relay share a secret, and every carrier opens with proof of it:
+// [time u64][nonce 16][HMAC-SHA256 tag], the tag over what the proof is for
+// (a RESUME and its ticket, a request path, a DNS session), the time and the
+// nonce. The relay takes a proof only within `FRESH` of its own clock and
+// only once, so a handshake recorded and replayed by a prober gets the decoy
+// like any other stranger. The stream key comes from the same secret.
+
+/// Bytes a proof takes on the wire
+pub const PROOF_SIZE: usize = 8 + 16 + 32;
+pub type Proof = [u8; PROOF_SIZE];
+
+// What a proof is for, so one made for one thing never passes for another
+pub const RESUME: &str = "resume";
+pub const REQUEST: &str = "request";
+pub const DNS: &str = "dns";
+
+// What a derived key is for
+pub const STREAM_KEY: &str = "stream";
+
+// Anything shorter is a password, not a key
+const MIN_SECRET: usize = 16;
+// How far a proof's clock may be from ours, and so how long a nonce is kept
+const FRESH: u64 = 120;
+// Time and nonce: what the tag covers besides purpose and subject
+const STAMP: usize = 8 + 16;
+// The cookie an HTTP carrier's requests bring their proof in
+const COOKIE: &str = "sid";
+
+/// The secret a relay and its clients share
+#[derive(Clone)]
+pub struct Secret {
+    key: hmac::Key,
+    // Nonces of proofs taken, with their time, until they are too old to pass anyway
+    seen: Arc<Mutex<HashMap<[u8; 16], u64>>>,
+}
+
+impl Secret {
+    pub fn new(secret: &str) -> io::Result<Self> {
+        if secret.len() < MIN_SECRET {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the secret needs at least {} characters", MIN_SECRET)));
+        }
+        Ok(Secret { key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), seen: Arc::default() })
+    }
+
+    /// A 256-bit key for `purpose`
+    pub fn derive(&self, purpose: &str) -> [u8; 32] {
+        let tag = hmac::sign(&self.key, format!("proteus key {}", purpose).as_bytes());
+        tag.as_ref().try_into().unwrap()
+    }
+
+    /// A fresh proof that we know the secret, for `purpose` and `subject`
+    pub fn prove(&self, purpose: &str, subject: &[u8]) -> Proof {
+        let mut proof = [0u8; PROOF_SIZE];
+        proof[..8].copy_from_slice(&now().to_be_bytes());
+        rand::rng().fill(&mut proof[8..STAMP]);
+        let tag = hmac::sign(&self.key, &signed(purpose, subject, &proof[..STAMP]));
+        proof[STAMP..].copy_from_slice(tag.as_ref());
+        proof
+    }
+
+    /// Whether `proof` is a current one for `purpose` and `subject`. Shown
+    /// twice, it passes twice: for carriers whose requests get retried (DNS).
+    pub fn verify(&self, proof: &[u8], purpose: &str, subject: &[u8]) -> bool {
+        if proof.len() != PROOF_SIZE { return false; }
+        let (stamp, tag) = proof.split_at(STAMP);
+        let time = u64::from_be_bytes(stamp[..8].try_into().unwrap());
+        now().abs_diff(time) <= FRESH && hmac::verify(&self.key, &signed(purpose, subject, stamp), tag).is_ok()
+    }
+
+    /// `verify`, but only the first time the proof is shown
+    pub fn accept(&self, proof: &[u8], purpose: &str, subject: &[u8]) -> bool {
+        if !self.verify(proof, purpose, subject) { return false; }
+        let time = u64::from_be_bytes(proof[..8].try_into().unwrap());
+        let nonce: [u8; 16] = proof[8..STAMP].try_into().unwrap();
+        let now = now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, &mut seen_at| now.abs_diff(seen_at) <= FRESH);
+        seen.insert(nonce, time).is_none()
+    }
+
+    /// A `Cookie` header for a request to `path`, with a fresh proof in it
+    pub fn cookie(&self, path: &str) -> String {
+        format!("{}={}", COOKIE, general_purpose::URL_SAFE_NO_PAD.encode(self.prove(REQUEST, path.as_bytes())))
+    }
+
+    /// Whether a request to `path` brought a `Cookie` header from `cookie`
+    /// (and brought it first)
+    pub fn admits(&self, path: &str, cookie: &str) -> bool {
+        cookie.split(';')
+            .find_map(|cookie| cookie.trim().strip_prefix(COOKIE)?.strip_prefix('='))
+            .and_then(|proof| general_purpose::URL_SAFE_NO_PAD.decode(proof).ok())
+            .is_some_and(|proof| self.accept(&proof, REQUEST, path.as_bytes()))
+    }
+}
+
+/// What a tag covers: purpose, time and nonce, subject
+fn signed(purpose: &str, subject: &[u8], stamp: &[u8]) -> Vec<u8> {
+    let mut message = purpose.as_bytes().to_vec();
+    message.push(0);
+    message.extend_from_slice(stamp);
+    message.extend_from_slice(subject);
+    message
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
@@ -1,10 +1,9 @@
 use tokio::net::UdpSocket;
-use bincode;
 use proteus_core::{ProteusPacket, SERVER_ADDR, SYMBOL_SIZE}; // FIX: Use SYMBOL_SIZE
 use raptorq::Encoder;
 use chacha20poly1305::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
-    XChaCha20Poly1305, XNonce
+    XChaCha20Poly1305
 };
 use std::time::Duration;
 
@@ -54,13 +53,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         print!(">"); 
 
         // Check Feedback (Don't crash if connection refused initially)
-        if let Ok(len) = socket.try_recv(&mut buf) {
-            if let Ok(ProteusPacket::Control { is_complete, .. }) = bincode::deserialize(&buf[..len]) {
-                if is_complete {
-                    println!("\n[√] Server signaled completion! Stopping.");
-                    break;
-                }
-            }
+        if let Ok(len) = socket.try_recv(&mut buf)
+            && let Ok(ProteusPacket::Control { is_complete: true, .. }) = bincode::deserialize(&buf[..len]) {
+            println!("\n[√] Server signaled completion! Stopping.");
+            break;
         }
         
         // Slow down slightly to see the progress
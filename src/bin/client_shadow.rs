@@ -1,4 +1,4 @@
-use std::io::Read;
+use std::io::{Read, Write};
 use std::net::TcpStream;
 use proteus_core::SYMBOL_SIZE;
 use raptorq::{Decoder, ObjectTransmissionInformation, EncodingPacket};
@@ -8,13 +8,17 @@ use chacha20poly1305::{
 };
 
 fn main() {
-    println!("--- PROTEUS CLIENT v2 (DYNAMIC HANDSHAKE) ---");
+    println!("--- PROTEUS CLIENT v3 (MULTI-CLIENT NODE) ---");
+    // Which object to fetch from the node's catalog
+    let object = std::env::args().nth(1).unwrap_or_else(|| "welcome".to_string());
     println!("Connecting to 10.0.0.2:80...");
 
     let mut stream = TcpStream::connect("10.0.0.2:80")
         .expect("Could not connect. Is the node running?");
 
-    println!("[CONNECTED] Waiting for Handshake...");
+    // --- STEP 0: ASK FOR AN OBJECT ---
+    stream.write_all(format!("{}\n", object).as_bytes()).expect("Failed to send request!");
+    println!("[CONNECTED] Requested '{}'. Waiting for Handshake...", object);
 
     // --- STEP 1: READ THE HANDSHAKE ---
     // We expect exactly 8 bytes (u64) defining the file size
@@ -79,7 +83,6 @@ fn main() {
                             }
                         } else {
                             print!("."); 
-                            use std::io::Write;
                             std::io::stdout().flush().unwrap();
                         }
 
@@ -1,231 +1,508 @@
 use clap::{Parser, Subcommand};
-use proteus_core::{vpn, transport, oracle, SYMBOL_SIZE, framing};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::io::{BufRead, BufReader, Write, Read};
-use std::convert::TryInto;
-use std::time::Instant;
+use proteus_core::{vpn, egress, socks, http_proxy, forward, tun2socks, split, dns, killswitch, resume, cloak, websocket, tls, dns_tunnel, http2, decoy, shape, auth, pairing};
+use proteus_core::cloak::{Cloak, Sniffed, Unwrapped};
+use proteus_core::failover::CarrierSpec;
+use proteus_core::profile::Profile;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread; // Needed for server threads
-use raptorq::{Encoder, Decoder, ObjectTransmissionInformation, EncodingPacket};
-use base64::{Engine as _, engine::general_purpose};
-use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    XChaCha20Poly1305, XNonce
-};
-
-const PACKET_TARGET_SIZE: usize = 400; 
 
 #[derive(Parser)]
 #[command(name = "Proteus")]
 #[command(version = "1.0.0")]
 #[command(about = "Proteus: The Unbreakable Tank (Production)", long_about = None)]
 struct Cli {
+    /// What the carrier looks like on the wire (the relay understands them all)
+    #[arg(long, global = true, default_value = cloak::DEFAULT_CLOAK)]
+    cloak: String,
+    /// Load a traffic profile as an extra cloak; repeat for each file
+    #[arg(long = "profile", global = true)]
+    profiles: Vec<PathBuf>,
+    /// The relay's key pin (`sha256/...`, printed by the relay); needed for tls:// and wss:// targets
+    #[arg(long, global = true)]
+    pin: Option<String>,
+    /// Server name to send in the TLS handshake instead of the relay's host
+    #[arg(long, global = true)]
+    sni: Option<String>,
+    /// The secret the relay and its clients share (at least 16 characters)
+    #[arg(long, global = true, env = "PROTEUS_SECRET", hide_env_values = true)]
+    secret: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Send { target: String, #[arg(short, long)] message: String, #[arg(long, action)] tcp: bool },
+    Send {
+        target: String,
+        #[arg(short, long)] message: String,
+        #[arg(long, action)] tcp: bool,
+        /// Bond this uplink (local IP or interface name); repeat for each one
+        #[arg(long)] via: Vec<String>,
+        /// Fall back to this carrier (tcp://HOST:PORT or udp://HOST:PORT) if the
+        /// ones before it fail; repeat in order of preference
+        #[arg(long)] fallback: Vec<CarrierSpec>,
+    },
     Recv { #[arg(short, long, default_value_t = 9000)] port: u16 },
-    Vpn { target: String },
-    Relay { #[arg(short, long, default_value_t = 9000)] port: u16 },
+    Vpn {
+        target: String,
+        /// Tunnel only these (CIDR, address or domain); everything else goes direct
+        #[arg(long)] include: Vec<String>,
+        /// Never tunnel these
+        #[arg(long)] exclude: Vec<String>,
+        /// Drop traffic to these
+        #[arg(long)] block: Vec<String>,
+        /// Leave routing to the operator
+        #[arg(long, action)] no_routes: bool,
+        /// Firewall off every path but the tunnel for traffic that belongs in it
+        #[arg(long, action)] kill_switch: bool,
+    },
+    Relay {
+        #[arg(short, long, default_value_t = 9000)] port: u16,
+        /// Also accept TLS on the port, with this PEM certificate chain
+        #[arg(long, requires = "tls_key")] tls_cert: Option<PathBuf>,
+        /// The certificate's PEM private key
+        #[arg(long, requires = "tls_cert")] tls_key: Option<PathBuf>,
+        /// Also carry sessions over DNS, answering as the authoritative server for this domain
+        #[arg(long)] dns_domain: Option<String>,
+        /// Where to answer DNS (UDP)
+        #[arg(long, default_value = "0.0.0.0:53")] dns_listen: String,
+        /// Hand connections that are not ours to this web server (HOST:PORT)
+        /// instead of the built-in site
+        #[arg(long)] decoy: Option<String>,
+        /// The path WebSocket and HTTP/2 clients ask for (ws://HOST:PORT/PATH);
+        /// requests for any other get the decoy
+        #[arg(long, default_value = "/api/stream")] path: String,
+        /// Let clients reach the relay's loopback, link-local and private networks
+        #[arg(long, action)] allow_private: bool,
+        /// Let clients have the relay listen here for reverse forwards (-R):
+        /// ADDR:PORT or ADDR:FIRST-LAST; repeat for each one
+        #[arg(long)] allow_bind: Vec<egress::BindRule>,
+    },
+    Socks {
+        #[arg(short, long, default_value = "127.0.0.1:1080")] listen: String,
+        /// Also answer plain DNS here (e.g. 127.0.0.1:5353), resolved by the relay
+        #[arg(long)] dns: Option<String>,
+        target: String,
+    },
+    Http { #[arg(short, long, default_value = "127.0.0.1:8118")] listen: String, #[arg(long)] auth: Option<String>, target: String },
+    Forward { #[arg(short = 'L', long = "local")] local: Vec<String>, #[arg(short = 'R', long = "remote")] remote: Vec<String>, target: String },
+    /// Work with traffic profiles
+    Profile { #[command(subcommand)] action: ProfileAction },
+}
+
+#[derive(Subcommand)]
+enum ProfileAction {
+    /// Validate profile files and test-drive them with full-size frames
+    Check { #[arg(required = true)] files: Vec<PathBuf> },
 }
 
 fn main() {
     let cli = Cli::parse();
+    if let Commands::Profile { action: ProfileAction::Check { files } } = &cli.command {
+        check_profiles(files);
+        return;
+    }
+
+    let mut cloaks = cloak::Registry::builtin();
+    for path in &cli.profiles {
+        let profile = Profile::load(path).unwrap_or_else(|e| panic!("Bad profile {}", e));
+        cloaks.add(Arc::new(profile)).unwrap_or_else(|e| panic!("Bad profile {}: {}", path.display(), e));
+    }
+    let cloak = cloaks.get(&cli.cloak)
+        .unwrap_or_else(|| panic!("Unknown cloak '{}'. Available: {}", cli.cloak, cloaks.names().join(", ")));
+    let tls = cli.pin.as_deref().map(|pin| tls::Client::pinned(pin, cli.sni.clone()).unwrap_or_else(|e| panic!("Bad --pin: {}", e)));
+    let secret = cli.secret.as_deref().map(|secret| auth::Secret::new(secret).unwrap_or_else(|e| panic!("Bad --secret: {}", e)));
+    let secret = || secret.clone().expect("Give the relay's secret with --secret (or PROTEUS_SECRET)");
+    let relay = |target: &String| resume::Relay::new(target.clone(), cloak.clone(), tls.clone(), secret())
+        .unwrap_or_else(|e| panic!("Cannot use {}: {}", target, e));
     match &cli.command {
-        Commands::Send { target, message, tcp } => proteus_core::client::start_sender(target.clone(), message.clone(), *tcp),
+        Commands::Send { target, message, tcp, via, fallback } => proteus_core::client::start_sender(target.clone(), message.clone(), *tcp, via.clone(), fallback.clone(), cloak),
         Commands::Recv { .. } => println!("Use 'proteus relay' instead."),
-        Commands::Vpn { target } => run_smart_client(target.clone()), 
-        Commands::Relay { port } => run_relay_server(*port),
+        Commands::Vpn { target, include, exclude, block, no_routes, kill_switch } => run_smart_client(relay(target), include, exclude, block, *no_routes, *kill_switch),
+        Commands::Relay { port, tls_cert, tls_key, dns_domain, dns_listen, decoy, path, allow_private, allow_bind } => {
+            let tls = tls_cert.as_ref().zip(tls_key.as_ref())
+                .map(|(cert, key)| tls::Server::load(cert, key).unwrap_or_else(|e| panic!("Bad TLS certificate: {}", e)));
+            let dns = dns_domain.as_ref().map(|domain| (dns_listen.clone(), domain.clone()));
+            let decoy = decoy.clone().map_or(decoy::Decoy::Site, decoy::Decoy::Backend);
+            let policy = egress::Policy { allow_private: *allow_private, binds: allow_bind.clone() };
+            // Stream sessions outlive their carriers: a client may come back on a new one
+            let sessions = resume::Sessions::new(secret(), path, Arc::new(policy));
+            run_relay_server(*port, cloaks, tls, dns, decoy, sessions)
+        }
+        Commands::Socks { listen, dns, target } => run_socks_client(listen.clone(), dns.clone(), relay(target)),
+        Commands::Http { listen, auth, target } => run_http_client(listen.clone(), auth.clone(), relay(target)),
+        Commands::Forward { local, remote, target } => run_forward_client(local, remote, relay(target)),
+        Commands::Profile { .. } => unreachable!("handled above"),
+    }
+}
+
+// --- PROFILES ---
+fn check_profiles(files: &[PathBuf]) {
+    let builtin = cloak::Registry::builtin();
+    let mut failed = false;
+    for path in files {
+        let profile = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Profile::parse(&text));
+        let mut problems = match &profile {
+            Ok(profile) => profile.problems(),
+            Err(e) => vec![e.clone()],
+        };
+        if let Ok(profile) = &profile && builtin.get(profile.name()).is_some() {
+            problems.push(format!("name '{}' is taken by a built-in cloak", profile.name()));
+        }
+
+        if problems.is_empty() {
+            println!("[PROFILE] {}: OK", path.display());
+        } else {
+            failed = true;
+            println!("[PROFILE] {}: {} problem(s)", path.display(), problems.len());
+            for problem in problems { println!("  - {}", problem); }
+        }
     }
+    if failed { std::process::exit(1); }
 }
 
 // --- CLIENT (TANK) ---
-fn run_smart_client(target: String) {
+fn run_smart_client(relay: resume::Relay, include: &[String], exclude: &[String], block: &[String], no_routes: bool, kill_switch: bool) {
     println!("--- PROTEUS TANK CLIENT ---");
-    let vpn = vpn::ProteusVpn::new();
-    let mut brain = oracle::NetworkOracle::new();
-    println!("[BRAIN] Oracle Online. Learning Network Dynamics...");
+    let vpn = Arc::new(vpn::ProteusVpn::new());
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
 
-    let stream = TcpStream::connect(&target).expect("Connection Failed");
-    stream.set_nodelay(true).ok();
-    stream.set_nonblocking(true).ok();
-    
-    let transport_stream = Arc::new(Mutex::new(stream));
-    let transport = transport::TransportType::Tcp(transport_stream.clone());
-    let key_bytes = [0u8; 32];
-    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
-
-    let mut buf = [0u8; 1500];
-    let mut seq = 0;
-    let mut last_feedback = Instant::now();
-
-    loop {
-        // 1. READ FEEDBACK / INCOMING DATA (From Server)
-        {
-            let mut conn = transport_stream.lock().unwrap();
-            let mut inc_buf = [0u8; 2048]; // Bigger buffer for incoming downloads
-            match conn.read(&mut inc_buf) {
-                Ok(n) if n > 0 => {
-                    let data = &inc_buf[..n];
-                    // If it's the simple "OK" ACK
-                    if data == b"OK" {
-                         let now = Instant::now();
-                         brain.update(now.duration_since(last_feedback));
-                         last_feedback = now;
-                    } else {
-                        // IT IS DATA FROM THE INTERNET!
-                        // In a real full impl, we would decrypt this too.
-                        // For this demo, we assume the downlink is raw for speed/simplicity
-                        // or we just dump it to the TUN.
-                        vpn.write(data).ok();
+    runtime.block_on(async {
+        // Resolve domain rules before any route points DNS into the tunnel
+        let rules = Arc::new(split::SplitRules::build(include, exclude, block).await.expect("Bad split rule"));
+        let relay_addr = tokio::net::lookup_host(relay.address()).await.ok().and_then(|mut addrs| addrs.next()).expect("Cannot resolve relay");
+
+        let target = relay.target.clone();
+        let transport = if relay.over_udp() { killswitch::Transport::Udp } else { killswitch::Transport::Tcp };
+        let (mux, mut incoming) = egress::open_session(relay).await.expect("Connection Failed");
+        println!("[SESSION] Tunnel to {} established.", target);
+        println!("[DNS] Point your resolver at {} to keep lookups in the tunnel.", dns::RESOLVER_ADDR);
+
+        // Armed before any route points into the TUN, so there is no window to leak through
+        let kill_switch = if kill_switch {
+            Some(killswitch::KillSwitch::install(&rules, vpn::TUN_NAME, relay_addr, transport).expect("Failed to arm kill switch"))
+        } else { None };
+
+        let routes = if no_routes { None } else {
+            Some(split::SplitRoutes::install(&rules, vpn::TUN_NAME, relay_addr.ip()).await.expect("Failed to install routes"))
+        };
+
+        let (packets_in_tx, packets_in) = tokio::sync::mpsc::channel::<Vec<u8>>(256);
+        let (packets_out, mut packets_out_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(256);
+
+        // 1. READ KERNEL (Outgoing): the TUN blocks, so it gets its own thread
+        let tun_reader = vpn.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; tun2socks::MTU];
+            loop {
+                match tun_reader.read(&mut buf) {
+                    Ok(size) if size > 0 => {
+                        // Every packet is checked: Direct/Block destinations never reach the relay.
+                        // The virtual resolver lives in the tunnel whatever the rules say.
+                        let policy = split::packet_destination(&buf[..size])
+                            .map(|dst| if dst == dns::RESOLVER_ADDR { split::Policy::Tunnel } else { rules.decide(dst) });
+                        if policy != Some(split::Policy::Tunnel) { continue; }
+                        if packets_in_tx.blocking_send(buf[..size].to_vec()).is_err() { break; }
                     }
-                },
-                _ => {}
+                    Ok(_) => {}
+                    Err(e) => println!("TUN Error: {}", e),
+                }
             }
-        }
+        });
+
+        // 2. WRITE KERNEL (Incoming): whatever the stack answers with
+        let tun_writer = vpn.clone();
+        tokio::task::spawn_blocking(move || {
+            while let Some(packet) = packets_out_rx.blocking_recv() {
+                tun_writer.write(&packet).ok();
+            }
+        });
+
+        // 3. TERMINATE FLOWS LOCALLY, SHIP ONLY PAYLOAD
+        let stack = tun2socks::NetStack::new(tun2socks::Egress::Mux(mux)).run(packets_in, packets_out);
+        tokio::pin!(stack);
+        // The relay never opens streams towards a VPN client: `None` means the carrier died
+        let tunnel_down = async { while incoming.accept().await.is_some() {} };
 
-        // 2. READ KERNEL (Outgoing)
-        match vpn.read(&mut buf) {
-            Ok(size) if size > 0 => {
-                let packet_data = &buf[..size];
-                let loss_ratio = brain.loss_rate; 
-                let redundant_packets = if loss_ratio > 0.1 { 2 } else { 1 };
-                
-                if seq % 50 == 0 {
-                   // Only log occasionally to keep terminal clean
-                   println!("[STATUS] Loss: {:.2} | RTT: {:?} | Redundancy: {}x", loss_ratio, brain.smoothed_rtt, redundant_packets);
+        tokio::select! {
+            _ = &mut stack => {},
+            _ = tunnel_down => {
+                println!("\n[TUNNEL] DOWN: lost the session to {}.", target);
+                if kill_switch.is_some() {
+                    // Fail closed: keep blocking until the user decides
+                    println!("[KILLSWITCH] Tunnel traffic stays blocked. Ctrl-C to disarm and exit.");
+                    tokio::select! {
+                        _ = &mut stack => {},
+                        _ = tokio::signal::ctrl_c() => println!("\n[SHUTDOWN] Interrupted."),
+                    }
                 }
+            }
+            _ = tokio::signal::ctrl_c() => println!("\n[SHUTDOWN] Interrupted."),
+        }
+        if let Some(routes) = routes { routes.remove().await; }
+        if let Some(kill_switch) = kill_switch { kill_switch.remove(); }
+    });
+}
+
+// --- SOCKS5 PROXY (NO ROOT) ---
+fn run_socks_client(listen: String, dns_listen: Option<String>, relay: resume::Relay) {
+    println!("--- PROTEUS SOCKS5 PROXY ---");
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
 
-                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-                let encrypted = cipher.encrypt(&nonce, packet_data).unwrap();
-                let mut blob = nonce.to_vec();
-                blob.extend(encrypted);
-                let length = blob.len() as u16;
-                let mut final_payload = length.to_be_bytes().to_vec();
-                final_payload.extend(blob);
-                
-                if final_payload.len() < PACKET_TARGET_SIZE {
-                    let padding = PACKET_TARGET_SIZE - final_payload.len();
-                    final_payload.extend(std::iter::repeat(0).take(padding));
+    runtime.block_on(async {
+        let target = relay.target.clone();
+        let (mux, _incoming) = egress::open_session(relay).await.expect("Connection Failed");
+        println!("[SESSION] Tunnel to {} established.", target);
+        if let Some(dns_listen) = dns_listen {
+            let resolver = Arc::new(dns::DnsResolver::new(mux.clone()));
+            tokio::spawn(async move {
+                if let Err(e) = dns::serve_local(&dns_listen, resolver).await {
+                    println!("DNS Error: {}", e);
                 }
+            });
+        }
+        if let Err(e) = socks::run_socks(&listen, mux).await {
+            println!("SOCKS Error: {}", e);
+        }
+    });
+}
+
+// --- HTTP CONNECT PROXY (NO ROOT) ---
+fn run_http_client(listen: String, auth: Option<String>, relay: resume::Relay) {
+    println!("--- PROTEUS HTTP PROXY ---");
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
+
+    runtime.block_on(async {
+        let target = relay.target.clone();
+        let (mux, _incoming) = egress::open_session(relay).await.expect("Connection Failed");
+        println!("[SESSION] Tunnel to {} established.", target);
+        if let Err(e) = http_proxy::run_http_proxy(&listen, mux, auth).await {
+            println!("HTTP Proxy Error: {}", e);
+        }
+    });
+}
 
-                let encoder = Encoder::with_defaults(&final_payload, SYMBOL_SIZE);
-                let packets = encoder.get_encoded_packets(redundant_packets);
+// --- STATIC PORT FORWARDING (NO ROOT) ---
+fn run_forward_client(local: &[String], remote: &[String], relay: resume::Relay) {
+    println!("--- PROTEUS PORT FORWARDER ---");
+    let parse = |specs: &[String]| -> Vec<forward::ForwardSpec> {
+        specs.iter()
+            .map(|spec| forward::ForwardSpec::parse(spec).unwrap_or_else(|| panic!("Invalid forward '{}'. Use [bind:]port:host:hostport", spec)))
+            .collect()
+    };
+    let (local, remote) = (parse(local), parse(remote));
+    if local.is_empty() && remote.is_empty() {
+        println!("Nothing to forward. Give at least one -L or -R.");
+        return;
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
 
-                for packet in packets {
-                    let symbol_data = packet.serialize();
-                    let header = framing::PacketHeader::new(seq);
-                    let header_bytes = header.to_bytes();
-                    let mut wire_data = header_bytes.to_vec();
-                    wire_data.extend(symbol_data);
+    runtime.block_on(async {
+        let target = relay.target.clone();
+        let (mux, incoming) = egress::open_session(relay).await.expect("Connection Failed");
+        println!("[SESSION] Tunnel to {} established.", target);
 
-                    let b64_data = general_purpose::STANDARD.encode(&wire_data);
-                    let http_packet = format!("GET /search?q={}&seq={} HTTP/1.1\n", b64_data, seq);
+        // Control streams must outlive the loop below, or the relay stops listening
+        let mut controls = Vec::new();
+        for spec in &remote {
+            match forward::request_remote(spec, &mux).await {
+                Ok(control) => controls.push(control),
+                Err(e) => println!("[FORWARD] Relay refused {}: {}", spec.bind, e),
+            }
+        }
+        tokio::spawn(forward::serve_remote(incoming, remote));
 
-                    if let Err(_) = transport.send(http_packet.as_bytes(), &target) { break; }
+        for spec in local {
+            let mux = mux.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forward::run_local(spec, mux).await {
+                    println!("Forward Error: {}", e);
                 }
-                seq += 1;
-            },
-            Ok(_) => {}, 
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                std::thread::sleep(brain.get_pacing_interval());
-            },
-            Err(e) => println!("TUN Error: {}", e),
+            });
         }
-    }
+
+        tokio::signal::ctrl_c().await.ok();
+        drop(controls);
+    });
 }
 
 // --- SERVER (GATEWAY) ---
-fn run_relay_server(port: u16) {
+fn run_relay_server(port: u16, cloaks: cloak::Registry, tls: Option<tls::Server>, dns: Option<(String, String)>, decoy: decoy::Decoy, sessions: resume::Sessions) {
     println!("--- PROTEUS GATEWAY SERVER ---");
     
-    // 1. OPEN TUN DEVICE (Shared System Interface)
-    let vpn_dev = Arc::new(vpn::ProteusVpn::new());
+    // No TUN and no iptables: every session egresses through our own sockets
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
     
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).expect("Failed to bind");
-    let key_bytes = [0u8; 32];
-    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
-    let config = ObjectTransmissionInformation::new(PACKET_TARGET_SIZE as u64, SYMBOL_SIZE as u16, 1, 1, 1);
     
     println!("[LISTENING] Gateway Active on Port {}", port);
+    if let Some(tls) = &tls {
+        println!("[TLS] Accepting TLS too. Clients pin: {}", tls.pin());
+    }
+    println!("[DECOY] Connections that are not ours get {}.", decoy);
+    println!("[WEBSOCKET] WebSocket and HTTP/2 clients ask for {}.", sessions.path());
+    let policy = sessions.policy();
+    if policy.allow_private {
+        println!("[EGRESS] Clients may reach private and loopback addresses.");
+    }
+    for rule in &policy.binds {
+        println!("[EGRESS] Clients may listen on {}.", rule);
+    }
+    if let Some((listen, domain)) = dns {
+        let mut carriers = runtime.block_on(dns_tunnel::serve(&listen, &domain, sessions.secret().clone())).expect("Failed to bind DNS");
+        let sessions = sessions.clone();
+        runtime.spawn(async move {
+            while let Some(carrier) = carriers.recv().await {
+                println!("[DNS TUNNEL] New tunnel session.");
+                let sessions = sessions.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_framed(carrier, sessions).await {
+                        println!("[DNS TUNNEL] {}", e);
+                    }
+                });
+            }
+        });
+    }
 
     for stream in listener.incoming() {
         match stream {
             Ok(socket) => {
                 println!("[NEW TANK CONNECTED] {:?}", socket.peer_addr());
-                
-                // CLONE RESOURCES FOR THREADS
-                let socket_reader = socket.try_clone().expect("Clone failed");
-                let mut socket_writer = socket.try_clone().expect("Clone failed");
-                let vpn_writer = vpn_dev.clone();
-                let vpn_reader = vpn_dev.clone();
-                
-                // THREAD 1: UPLINK (Internet -> Client)
-                // Reads from TUN (responses) and writes to TCP
-                thread::spawn(move || {
-                    let mut buf = [0u8; 1500];
-                    loop {
-                        match vpn_reader.read(&mut buf) {
-                            Ok(n) if n > 0 => {
-                                // We caught a packet from Google meant for the Client!
-                                // For MVP: Send Raw. (Production: Encrypt this too)
-                                if let Err(_) = socket_writer.write_all(&buf[..n]) {
-                                    break;
-                                }
-                            },
-                            Ok(_) => {},
-                            Err(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
-                        }
-                    }
-                });
+                // Nothing a client sends (or does not send) holds up the next accept
+                socket.set_nonblocking(true).ok();
+                let _guard = runtime.enter();
+                let Ok(socket) = tokio::net::TcpStream::from_std(socket) else { continue; };
+                let (sessions, cloaks, tls, decoy) = (sessions.clone(), cloaks.clone(), tls.clone(), decoy.clone());
+                runtime.spawn(serve_connection(socket, sessions, cloaks, tls, decoy));
+            },
+            Err(e) => println!("Connection Error: {}", e),
+        }
+    }
+}
 
-                // MAIN LOOP: DOWNLINK (Client -> Internet)
-                // Reads from TCP, Decrypts, Writes to TUN
-                let cipher_clone = XChaCha20Poly1305::new(&key_bytes.into());
-                let mut reader = BufReader::new(socket_reader);
-                let mut line = String::new();
-
-                loop {
-                    line.clear();
-                    match reader.read_line(&mut line) {
-                        Ok(0) => break, 
-                        Ok(_) => {
-                            if let Some(start) = line.find("q=") {
-                                if let Some(end) = line.find("&seq") {
-                                    let b64 = &line[start+2..end];
-                                    if let Ok(wire_bytes) = general_purpose::STANDARD.decode(b64) {
-                                        if wire_bytes.len() < framing::HEADER_SIZE { continue; }
-                                        let (_head, symbol) = wire_bytes.split_at(framing::HEADER_SIZE);
-                                        
-                                        let mut decoder = Decoder::new(config);
-                                        let packet = EncodingPacket::deserialize(&symbol.to_vec());
-                                        if let Some(decoded) = decoder.decode(packet) {
-                                            // 1. DECRYPT
-                                            let len_bytes: [u8;2] = decoded[0..2].try_into().unwrap();
-                                            let real_len = u16::from_be_bytes(len_bytes) as usize;
-                                            if decoded.len() >= 2 + real_len {
-                                                let valid_payload = &decoded[2..2+real_len];
-                                                let (nonce_bytes, ciphertext) = valid_payload.split_at(24);
-                                                let nonce = XNonce::from_slice(nonce_bytes);
-                                                
-                                                if let Ok(ip_packet) = cipher_clone.decrypt(nonce, ciphertext) {
-                                                    // 2. WRITE TO KERNEL (Internet Access!)
-                                                    vpn_writer.write(&ip_packet).ok();
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        },
-                        Err(_) => break,
-                    }
+/// Unwrap TLS if the client opened with it, then serve whatever carrier is
+/// inside: a stream session with proof of our secret, or the decoy
+async fn serve_connection(socket: tokio::net::TcpStream, sessions: resume::Sessions, cloaks: cloak::Registry, tls: Option<tls::Server>, decoy: decoy::Decoy) {
+    let peer = socket.peer_addr();
+    // A TLS client shares the port: its first byte opens a handshake record, never a cloak
+    if let Some(tls) = tls {
+        let mut first = [0u8; 1];
+        if !matches!(tokio::time::timeout(decoy::IDLE, socket.peek(&mut first)).await, Ok(Ok(1))) { return; }
+        if first[0] == tls::HANDSHAKE_RECORD {
+            let socket = match tokio::time::timeout(decoy::IDLE, tls.accept(socket)).await {
+                Ok(Ok(socket)) => socket,
+                Ok(Err(e)) => return println!("[TLS] Handshake with {:?} failed: {}", peer, e),
+                Err(_) => return println!("[TLS] Handshake with {:?} timed out.", peer),
+            };
+            let (socket_reader, socket_writer) = tokio::io::split(socket);
+            if let Err(e) = serve_carrier(socket_reader, socket_writer, Vec::new(), sessions, cloaks, decoy).await {
+                println!("[TLS] {:?}: {}", peer, e);
+            }
+            return;
+        }
+    }
+
+    let (socket_reader, socket_writer) = socket.into_split();
+    if let Err(e) = serve_carrier(socket_reader, socket_writer, Vec::new(), sessions, cloaks, decoy).await {
+        println!("[SESSION] {:?}: {}", peer, e);
+    }
+}
+
+/// Read more of a client's first message into `buffered`. A client that says
+/// nothing for as long as the decoy would wait reads as one that hung up.
+async fn read_more<R: tokio::io::AsyncRead + Unpin>(reader: &mut R, buffered: &mut Vec<u8>) -> std::io::Result<usize> {
+    let mut chunk = [0u8; 4096];
+    let Ok(read) = tokio::time::timeout(decoy::IDLE, tokio::io::AsyncReadExt::read(reader, &mut chunk)).await else { return Ok(0); };
+    let n = read?;
+    buffered.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+/// A stream session on any carrier (bare TCP or inside TLS): sniff the cloak
+/// or WebSocket upgrade from what is `buffered` and whatever follows it, then
+/// run the handshake. A first message that is not ours gets the decoy.
+async fn serve_carrier<R, W>(mut reader: R, writer: W, mut buffered: Vec<u8>, sessions: resume::Sessions, cloaks: cloak::Registry, decoy: decoy::Decoy) -> std::io::Result<()>
+where
+    R: tokio::io::AsyncRead + Send + Unpin + 'static,
+    W: tokio::io::AsyncWrite + Send + Unpin + 'static,
+{
+    let cloak = loop {
+        match http2::sniff(&buffered) {
+            Some(true) => {
+                println!("[HTTP2] Connection preface received.");
+                let (path, secret) = (sessions.path().to_string(), sessions.secret().clone());
+                return serve_framed(http2::accept(reader, writer, buffered, path, secret).await?, sessions).await;
+            }
+            Some(false) if websocket::is_upgrade(&buffered) => {
+                if !websocket::admits(&buffered, sessions.path(), sessions.secret()) {
+                    println!("[DECOY] WebSocket upgrade that is not ours.");
+                    return decoy.serve(reader, writer, buffered).await;
+                }
+                println!("[WEBSOCKET] Upgrade requested.");
+                return serve_framed(websocket::accept(reader, writer, buffered).await?, sessions).await;
+            }
+            Some(false) => match cloaks.sniff(&buffered) {
+                Sniffed::Found(cloak) => break cloak,
+                Sniffed::Unknown => {
+                    println!("[DECOY] Carrier wears no cloak we know.");
+                    return decoy.serve(reader, writer, buffered).await;
                 }
+                Sniffed::Incomplete => {}
             },
-            Err(e) => println!("Connection Error: {}", e),
+            None => {}
+        }
+        if read_more(&mut reader, &mut buffered).await? == 0 { return Ok(()); }
+    };
+    // Stream sessions are shaped: the first frame may come in several pieces
+    let shaped = shape::shaped(cloak.clone());
+    let (len, ticket) = loop {
+        match shaped.unwrap(&buffered) {
+            Unwrapped::Incomplete => {}
+            // A frame is ours only if it opens a stream with proof of our secret
+            unwrapped => {
+                if let Unwrapped::Message { len, frame: Some(first) } = unwrapped
+                    && let Some(ticket) = sessions.admit(&first) {
+                    break (len, ticket);
+                }
+                println!("[DECOY] Carrier wears '{}' but sent no frame of ours.", cloak.name());
+                return decoy.serve(reader, writer, buffered).await;
+            }
         }
+        if read_more(&mut reader, &mut buffered).await? == 0 { return Ok(()); }
+    };
+    println!("[CLOAK] Stream session wears '{}'.", cloak.name());
+
+    // Replay what we already read (minus the handshake)
+    let reader = tokio::io::AsyncReadExt::chain(std::io::Cursor::new(buffered.split_off(len)), reader);
+    // From here on we answer the client, in the reply half of its cloak; an
+    // HTTP/1.1 lookalike only ever answers a request (the handshake's first)
+    if cloak.paired() {
+        let asked = pairing::count(&buffered, cloak.as_ref());
+        let (reader, writer) = pairing::relay(reader, writer, cloak, asked);
+        return sessions.serve(ticket, reader, writer, cloak::replying(shaped)).await;
     }
-}
\ No newline at end of file
+    sessions.serve(ticket, reader, writer, cloak::replying(shaped)).await
+}
+
+/// A session over a carrier that frames for itself (WebSocket, HTTP/2, DNS): the
+/// same handshake, with frames riding its messages. A first frame that is not
+/// ours gets no answer: the carrier just closes.
+async fn serve_framed((mut socket_reader, socket_writer): cloak::Halves, sessions: resume::Sessions) -> std::io::Result<()> {
+    let cloak = shape::shaped(cloak::framed());
+    let mut deframer = cloak::Deframer::new(cloak.clone());
+    let Ok(first) = tokio::time::timeout(decoy::IDLE, deframer.read(&mut socket_reader)).await else { return Ok(()); };
+    let Some(first) = first? else { return Ok(()); };
+    let Some(ticket) = sessions.admit(&first) else {
+        println!("[SESSION] Carrier opened with no frame of ours. Closing it.");
+        return Ok(());
+    };
+
+    // As on a bare carrier: replay everything but the handshake
+    let prefix = deframer.into_buffered();
+    let socket_reader = tokio::io::AsyncReadExt::chain(std::io::Cursor::new(prefix), socket_reader);
+    sessions.serve(ticket, socket_reader, socket_writer, cloak::replying(cloak)).await
+}
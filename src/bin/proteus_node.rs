@@ -1,38 +1,55 @@
-use std::fmt::Write;
-use smoltcp::iface::{Config, Interface, SocketSet};
-use smoltcp::phy::{TunTapInterface, Medium};
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, TunTapInterface, Medium};
 use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
 use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, HardwareAddress};
-use raptorq::Encoder;
+use raptorq::{Encoder, EncodingPacket, ObjectTransmissionInformation};
 use chacha20poly1305::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
-    XChaCha20Poly1305, XNonce
+    XChaCha20Poly1305
 };
 use proteus_core::SYMBOL_SIZE;
 
+// How many clients can be connecting/connected at the same time
+const POOL_SIZE: usize = 16;
+const SOCKET_BUFFER: usize = 16 * 1024;
+// Object names are short; anything longer is not a client of ours
+const MAX_REQUEST: usize = 256;
+// Repair symbols generated per object on top of the source symbols
+const REPAIR_SYMBOLS: u32 = 16;
+
+/// Per-connection progress. Each client asks for its own object and
+/// gets its own encryption nonce and symbol stream.
+#[derive(Default)]
+struct Session {
+    request: Vec<u8>,
+    handshake: Option<Vec<u8>>,
+    packets: Vec<EncodingPacket>,
+    next_packet: usize,
+    packet_counter: u32,
+}
+
 fn main() {
-    println!("--- PROTEUS NODE v2 (DYNAMIC HANDSHAKE) ---");
-    
-    // --- PART 1: PREPARE PAYLOAD ---
-    // You can change this text to ANYTHING now. Long or short.
-    let plaintext = b"PROTEUS UPDATE: We successfully negotiated the packet size. The protocol is now dynamic.";
-    println!("[1] Payload size: {} bytes", plaintext.len());
-    
-    // Encrypt
-    let key_bytes = [0u8; 32]; 
-    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
-    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let encrypted = cipher.encrypt(&nonce, plaintext.as_ref()).unwrap();
-    
-    let mut blob = nonce.to_vec();
-    blob.extend(encrypted);
+    println!("--- PROTEUS NODE v3 (MULTI-CLIENT) ---");
 
-    // CRITICAL: Calculate the exact size to send during handshake
-    let total_blob_size = blob.len() as u64;
-    println!("[INFO] Total Encrypted Size: {} bytes", total_blob_size);
+    // --- PART 1: PREPARE THE CATALOG ---
+    // Built-in objects, plus any files given on the command line (served by file name)
+    let mut catalog: HashMap<String, Vec<u8>> = HashMap::new();
+    catalog.insert("welcome".into(), b"PROTEUS UPDATE: We successfully negotiated the packet size. The protocol is now dynamic.".to_vec());
+    catalog.insert("status".into(), b"PROTEUS STATUS: Node online. Serving many clients at once.".to_vec());
+    for path in std::env::args().skip(1) {
+        let name = std::path::Path::new(&path).file_name().unwrap().to_string_lossy().to_string();
+        let data = std::fs::read(&path).expect("Failed to read object file");
+        catalog.insert(name, data);
+    }
+    for (name, data) in &catalog {
+        println!("[1] Object '{}': {} bytes", name, data.len());
+    }
 
-    let encoder = Encoder::with_defaults(&blob, SYMBOL_SIZE);
+    let key_bytes = [0u8; 32];
+    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
 
     // --- PART 2: SETUP SHADOW STACK ---
     let mut device = TunTapInterface::new("tun0", Medium::Ethernet)
@@ -46,66 +63,102 @@ fn main() {
         ip_addrs.push(IpCidr::new(Ipv4Address::new(10, 0, 0, 2).into(), 24)).unwrap();
     });
 
+    // A pool of listeners on the same port: each one takes a single client
     let mut sockets = SocketSet::new(vec![]);
-    let tcp_rx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
-    let tcp_tx_buffer = tcp::SocketBuffer::new(vec![0; 4096]);
-    let tcp_socket = tcp::Socket::new(tcp_rx_buffer, tcp_tx_buffer);
-    let tcp_handle = sockets.add(tcp_socket);
-
-    println!("Listening on 10.0.0.2:80...");
+    let mut sessions: HashMap<SocketHandle, Session> = HashMap::new();
+    for _ in 0..POOL_SIZE {
+        let tcp_rx_buffer = tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER]);
+        let tcp_tx_buffer = tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER]);
+        let handle = sockets.add(tcp::Socket::new(tcp_rx_buffer, tcp_tx_buffer));
+        sessions.insert(handle, Session::default());
+    }
 
-    // State tracking
-    let mut handshake_sent = false;
-    let mut packet_counter = 0;
+    println!("Listening on 10.0.0.2:80 ({} client slots)...", POOL_SIZE);
 
     // --- PART 3: EVENT LOOP ---
     loop {
         let timestamp = Instant::now();
         iface.poll(timestamp, &mut device, &mut sockets);
-        let socket = sockets.get_mut::<tcp::Socket>(tcp_handle);
-        
-        // A. Reset state on new connection / disconnect
-        if !socket.is_open() {
-            socket.listen(80).ok();
-            handshake_sent = false; // Reset for next client
-            packet_counter = 0;
-        }
 
-        // B. Sending Logic
-        if socket.may_send() {
+        for (handle, session) in sessions.iter_mut() {
+            let socket = sockets.get_mut::<tcp::Socket>(*handle);
+
+            // A. Recycle the slot once its client is gone
+            if !socket.is_open() {
+                *session = Session::default();
+                socket.listen(80).ok();
+                continue;
+            }
+            if !socket.may_recv() && socket.may_send() {
+                socket.close();
+                continue;
+            }
+
+            // B. Wait for the request line: "<object>\n"
+            if session.handshake.is_none() {
+                if socket.can_recv() {
+                    socket.recv(|data| {
+                        session.request.extend_from_slice(data);
+                        (data.len(), ())
+                    }).ok();
+                }
+                let Some(end) = session.request.iter().position(|&b| b == b'\n') else {
+                    if session.request.len() > MAX_REQUEST { socket.abort(); }
+                    continue;
+                };
+                let name = String::from_utf8_lossy(&session.request[..end]).trim().to_string();
+                session.request.clear();
+                let Some(plaintext) = catalog.get(&name) else {
+                    println!("\n[NODE] Unknown object '{}'. Closing.", name);
+                    socket.close();
+                    continue;
+                };
+
+                // Fresh nonce per client, one source block (what client_shadow expects)
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let mut blob = nonce.to_vec();
+                blob.extend(cipher.encrypt(&nonce, plaintext.as_ref()).unwrap());
+                let total_blob_size = blob.len() as u64;
+                let encoder = Encoder::new(&blob, ObjectTransmissionInformation::new(total_blob_size, SYMBOL_SIZE, 1, 1, 1));
+
+                let peer = socket.remote_endpoint().map(|e| e.to_string()).unwrap_or_default();
+                println!("\n[HANDSHAKE] {} wants '{}': {} bytes", peer, name, total_blob_size);
+                session.handshake = Some(total_blob_size.to_be_bytes().to_vec());
+                session.packets = encoder.get_encoded_packets(REPAIR_SYMBOLS);
+            }
+
+            // C. Sending Logic
+            if !socket.can_send() { continue; }
+
             // STEP 1: Send the Handshake (Size)
-            if !handshake_sent {
-                // Convert u64 size to 8 bytes (Big Endian)
-                let size_bytes = total_blob_size.to_be_bytes();
-                
-                match socket.send_slice(&size_bytes) {
-                    Ok(_) => {
-                        println!("\n[HANDSHAKE] Sent file size: {}", total_blob_size);
-                        handshake_sent = true;
-                    },
-                    Err(_) => { /* Wait for buffer space */ }
+            if let Some(handshake) = session.handshake.as_mut()
+                && !handshake.is_empty()
+            {
+                if let Ok(n) = socket.send_slice(handshake) {
+                    handshake.drain(..n);
                 }
-            } 
-            // STEP 2: Stream Symbols (Only after handshake)
-            else {
-                let packets = encoder.get_encoded_packets(1);
-                let symbol = &packets[0];
-                let data = symbol.serialize();
-                
-                if socket.send_slice(b"PROT:").is_ok() {
-                    match socket.send_slice(&data) {
-                        Ok(_) => {
-                            packet_counter += 1;
-                            if packet_counter % 10 == 0 {
-                                print!("."); 
-                                use std::io::Write;
-                                std::io::stdout().flush().unwrap();
-                            }
-                        },
-                        Err(_) => { /* Buffer full */ }
-                    }
+                continue;
+            }
+
+            // STEP 2: Stream Symbols, cycling until the client hangs up
+            while !session.packets.is_empty() {
+                let data = session.packets[session.next_packet].serialize();
+                // Only queue whole frames so the stream never tears mid-symbol
+                if socket.send_capacity() - socket.send_queue() < 5 + data.len() { break; }
+                socket.send_slice(b"PROT:").ok();
+                socket.send_slice(&data).ok();
+                session.next_packet = (session.next_packet + 1) % session.packets.len();
+                session.packet_counter += 1;
+                if session.packet_counter % 10 == 0 {
+                    print!(".");
+                    use std::io::Write;
+                    std::io::stdout().flush().unwrap();
                 }
             }
         }
+
+        // D. Sleep until smoltcp has work or a frame arrives (no busy loop)
+        let delay = iface.poll_delay(Instant::now(), &sockets);
+        phy::wait(device.as_raw_fd(), delay).ok();
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,4 @@
 use tokio::net::UdpSocket;
-use bincode;
 use proteus_core::{ProteusPacket, SERVER_ADDR, SYMBOL_SIZE}; // FIX: Use SYMBOL_SIZE
 use raptorq::{Decoder, ObjectTransmissionInformation, EncodingPacket};
 use chacha20poly1305::{
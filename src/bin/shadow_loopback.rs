@@ -0,0 +1,168 @@
+use std::thread;
+use smoltcp::iface::{Config, Interface, SocketSet};
+use smoltcp::phy::Medium;
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address};
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce
+};
+use proteus_core::SYMBOL_SIZE;
+use proteus_core::channel_device::ChannelDevice;
+
+const MTU: usize = 1500;
+const NODE_ADDR: Ipv4Address = Ipv4Address::new(10, 0, 0, 2);
+const CLIENT_ADDR: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+
+fn main() {
+    println!("--- PROTEUS SHADOW LOOPBACK (NO ROOT) ---");
+    println!("Node and client stacks talk over in-memory channels. No TUN required.");
+
+    let (node_dev, client_dev) = ChannelDevice::pair(Medium::Ip, MTU);
+
+    let node = thread::spawn(move || run_node(node_dev));
+    run_client(client_dev);
+    node.join().unwrap();
+}
+
+fn make_iface(device: &mut ChannelDevice, addr: Ipv4Address) -> Interface {
+    let config = Config::new(HardwareAddress::Ip);
+    let mut iface = Interface::new(config, device, Instant::now());
+    iface.update_ip_addrs(|ip_addrs| {
+        ip_addrs.push(IpCidr::new(addr.into(), 24)).unwrap();
+    });
+    iface
+}
+
+// --- NODE (same wire format as proteus_node) ---
+fn run_node(mut device: ChannelDevice) {
+    let plaintext = b"PROTEUS LOOPBACK: The shadow stack runs entirely in userspace.";
+
+    let key_bytes = [0u8; 32];
+    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut blob = nonce.to_vec();
+    blob.extend(cipher.encrypt(&nonce, plaintext.as_ref()).unwrap());
+    let total_blob_size = blob.len() as u64;
+    let encoder = Encoder::with_defaults(&blob, SYMBOL_SIZE);
+
+    let mut iface = make_iface(&mut device, NODE_ADDR);
+    let mut sockets = SocketSet::new(vec![]);
+    let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 4096]), tcp::SocketBuffer::new(vec![0; 4096]));
+    socket.listen(80).unwrap();
+    let handle = sockets.add(socket);
+    println!("[NODE] Listening on {}:80", NODE_ADDR);
+
+    let mut requested = false;
+    let mut handshake_sent = false;
+    let mut was_open = false;
+
+    loop {
+        iface.poll(Instant::now(), &mut device, &mut sockets);
+        let socket = sockets.get_mut::<tcp::Socket>(handle);
+
+        // One client, then done: stop once it has hung up
+        if socket.is_active() { was_open = true; }
+        if was_open && !socket.is_active() { break; }
+        if !socket.may_recv() && socket.may_send() { socket.close(); }
+
+        // The client names the object it wants; this node only has the one
+        if !requested && socket.can_recv() {
+            requested = socket.recv(|data| (data.len(), data.contains(&b'\n'))).unwrap_or(false);
+        }
+
+        if requested && socket.can_send() {
+            if !handshake_sent {
+                if socket.send_slice(&total_blob_size.to_be_bytes()).is_ok() {
+                    println!("[NODE] Sent handshake: {} bytes", total_blob_size);
+                    handshake_sent = true;
+                }
+            } else {
+                let data = encoder.get_encoded_packets(1)[0].serialize();
+                // Only queue whole frames so the stream never tears mid-symbol
+                if socket.send_capacity() - socket.send_queue() >= 5 + data.len() {
+                    socket.send_slice(b"PROT:").ok();
+                    socket.send_slice(&data).ok();
+                }
+            }
+        }
+
+        if !device.wait(iface.poll_delay(Instant::now(), &sockets).map(Into::into)) { break; }
+    }
+    println!("[NODE] Client gone. Shutting down.");
+}
+
+// --- CLIENT (same wire format as client_shadow) ---
+fn run_client(mut device: ChannelDevice) {
+    let mut iface = make_iface(&mut device, CLIENT_ADDR);
+    let mut sockets = SocketSet::new(vec![]);
+    let socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 8192]), tcp::SocketBuffer::new(vec![0; 1024]));
+    let handle = sockets.add(socket);
+
+    sockets.get_mut::<tcp::Socket>(handle)
+        .connect(iface.context(), (IpAddress::Ipv4(NODE_ADDR), 80), 49152)
+        .unwrap();
+    println!("[CLIENT] Connecting to {}:80...", NODE_ADDR);
+
+    let key_bytes = [0u8; 32];
+    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
+    let frame_len = 5 + 4 + SYMBOL_SIZE as usize;
+    let mut decoder: Option<Decoder> = None;
+    let mut accumulator = Vec::new();
+    let mut request_sent = false;
+    let mut delivered = false;
+
+    loop {
+        iface.poll(Instant::now(), &mut device, &mut sockets);
+        let socket = sockets.get_mut::<tcp::Socket>(handle);
+
+        // STEP 0: ASK FOR AN OBJECT
+        if !request_sent && socket.can_send() {
+            socket.send_slice(b"welcome\n").ok();
+            request_sent = true;
+        }
+
+        if socket.can_recv() {
+            socket.recv(|data| {
+                accumulator.extend_from_slice(data);
+                (data.len(), ())
+            }).ok();
+        }
+
+        // STEP 1: HANDSHAKE
+        if decoder.is_none() && accumulator.len() >= 8 {
+            let size = u64::from_be_bytes(accumulator[..8].try_into().unwrap());
+            println!("[CLIENT] Handshake: payload is {} bytes", size);
+            decoder = Some(Decoder::new(ObjectTransmissionInformation::new(size, SYMBOL_SIZE, 1, 1, 1)));
+            accumulator.drain(..8);
+        }
+
+        // STEP 2: SYMBOLS (symbols still in flight after the decode are ignored)
+        if !delivered && let Some(decoder) = decoder.as_mut() {
+            while accumulator.len() >= frame_len {
+                let packet = EncodingPacket::deserialize(&accumulator[5..frame_len]);
+                accumulator.drain(..frame_len);
+                if let Some(decoded) = decoder.decode(packet) {
+                    let (nonce_bytes, ciphertext) = decoded.split_at(24);
+                    match cipher.decrypt(XNonce::from_slice(nonce_bytes), ciphertext) {
+                        Ok(msg) => println!("[CLIENT] MESSAGE: \"{}\"", String::from_utf8_lossy(&msg)),
+                        Err(_) => println!("[CLIENT] Decryption Error"),
+                    }
+                    socket.close();
+                    delivered = true;
+                    break;
+                }
+            }
+        }
+
+        // TIME-WAIT is not worth waiting out in a demo
+        if !socket.is_active() {
+            println!("[CLIENT] Done.");
+            return;
+        }
+
+        if !device.wait(iface.poll_delay(Instant::now(), &sockets).map(Into::into)) { return; }
+    }
+}
@@ -59,7 +59,7 @@ fn main() {
                  println!("> [SHADOW-TCP] Received Encrypted Frame: {:?}", msg);
                  
                  // Reply
-                 write!(socket, "Proteus Shadow-ACK\n").ok();
+                 writeln!(socket, "Proteus Shadow-ACK").ok();
             }
         }
     }
@@ -1,95 +1,235 @@
-use std::net::UdpSocket;
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use raptorq::{Decoder, ObjectTransmissionInformation, EncodingPacket};
-use base64::{Engine as _, engine::general_purpose};
 use chacha20poly1305::{
     aead::{Aead, KeyInit},
     XChaCha20Poly1305, XNonce
 };
 use proteus_core::{SYMBOL_SIZE, framing};
+use proteus_core::cloak::{self, Cloak, Deframer, Sniffed};
+use proteus_core::migration::PeerPath;
 
 fn main() {
     println!("--- PROTEUS STEALTH RECEIVER (PROTOCOL V1) ---");
-    println!("Listening for 'Google Search' traffic on Port 9000...");
 
-    let socket = UdpSocket::bind("0.0.0.0:9000").expect("Could not bind to port 9000");
-
-    let approx_payload_size = 512; 
-    let config = ObjectTransmissionInformation::new(
-        approx_payload_size, 
-        SYMBOL_SIZE, 
-        1, 1, 1
-    );
-    let mut decoder = Decoder::new(config);
+    // Every port takes both UDP and TCP, so senders can fail over between them
+    let mut ports: Vec<u16> = std::env::args().skip(1)
+        .map(|port| port.parse().expect("Ports must be numbers"))
+        .collect();
+    if ports.is_empty() { ports.push(9000); }
+    println!("Listening for 'Google Search' traffic on Port(s) {:?} (UDP + TCP)...", ports);
 
     let key_bytes = [0u8; 32];
-    let cipher = XChaCha20Poly1305::new(&key_bytes.into());
+    let receiver = Arc::new(Receiver {
+        cipher: XChaCha20Poly1305::new(&key_bytes.into()),
+        // Senders shape every frame; so do we, answering them
+        cloaks: cloak::Registry::builtin().shaped(),
+        sessions: Mutex::new(HashMap::new()),
+    });
 
-    let mut buffer = [0u8; 2048];
+    for port in ports {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).unwrap_or_else(|_| panic!("Could not bind to port {}", port));
+        let listener = TcpListener::bind(("0.0.0.0", port)).unwrap_or_else(|_| panic!("Could not bind to port {}", port));
+        let udp_receiver = receiver.clone();
+        thread::spawn(move || serve_udp(udp_receiver, socket));
+        let tcp_receiver = receiver.clone();
+        thread::spawn(move || serve_tcp(tcp_receiver, listener));
+    }
+    loop { thread::park(); }
+}
 
+fn serve_udp(receiver: Arc<Receiver>, socket: UdpSocket) {
+    // A shaped frame may come in several pieces, all in one datagram
+    let mut buffer = vec![0u8; u16::MAX as usize];
     loop {
         // [FIXED] We name the source address 'src' (no underscore) so we can use it
         match socket.recv_from(&mut buffer) {
             Ok((size, src)) => {
-                let msg_str = String::from_utf8_lossy(&buffer[..size]);
-
-                if let Some(start) = msg_str.find("q=") {
-                    if let Some(end) = msg_str.find("&seq") {
-                        let b64_data = &msg_str[start+2..end];
-                        
-                        if let Ok(binary_data) = general_purpose::STANDARD.decode(b64_data) {
-                            
-                            // [LAYER 1] PARSE PROTEUS HEADER
-                            if binary_data.len() < framing::HEADER_SIZE { continue; }
-                            
-                            let (head_bytes, symbol_bytes) = binary_data.split_at(framing::HEADER_SIZE);
-                            
-                            if let Some(header) = framing::PacketHeader::from_bytes(head_bytes) {
-                                // [FIXED] SEND ACK
-                                // We use 'src' here, which matches the variable above
-                                let ack = framing::AckPacket::new(header.seq_id, header.timestamp);
-                                let ack_bytes = ack.to_bytes();
-                                socket.send_to(&ack_bytes, src).ok();
-                            }
-
-                            // [LAYER 2] RAPTORQ & DECRYPT
-                            let packet = EncodingPacket::deserialize(&symbol_bytes.to_vec());
-                            
-                            if let Some(decoded_data) = decoder.decode(packet) {
-                                println!("\n[!!!] RESURRECTION COMPLETE!");
-
-                                if decoded_data.len() < 2 { continue; } 
-                                
-                                let len_bytes: [u8; 2] = decoded_data[0..2].try_into().unwrap();
-                                let real_len = u16::from_be_bytes(len_bytes) as usize;
-
-                                println!("-> Size Header says: {} bytes (Buffer is {})", real_len, decoded_data.len());
-
-                                if decoded_data.len() < 2 + real_len { continue; }
-                                let valid_data = &decoded_data[2..2+real_len];
-
-                                let (nonce_bytes, ciphertext) = valid_data.split_at(24);
-                                let nonce = XNonce::from_slice(nonce_bytes);
-                                
-                                match cipher.decrypt(nonce, ciphertext) {
-                                    Ok(msg) => {
-                                        println!("------------------------------------------------");
-                                        println!("MESSAGE: \"{}\"", String::from_utf8_lossy(&msg));
-                                        println!("------------------------------------------------");
-                                        return;
-                                    },
-                                    Err(_) => println!("Decryption Error"),
-                                }
-                            } else {
-                                print!("."); 
-                                use std::io::Write;
-                                std::io::stdout().flush().unwrap();
-                            }
-                        }
-                    }
+                // Each datagram is a whole message, in whichever cloak the sender wears
+                if let Some((cloak, frame)) = receiver.cloaks.unwrap_message(&buffer[..size]) {
+                    receiver.handle_frame(&frame, Origin::Udp(&socket, src), cloak.as_ref());
                 }
             },
             Err(e) => println!("Rx Error: {}", e),
         }
     }
-}
\ No newline at end of file
+}
+
+fn serve_tcp(receiver: Arc<Receiver>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue; };
+        let receiver = receiver.clone();
+        thread::spawn(move || {
+            let Ok(mut reader) = stream.try_clone() else { return; };
+            // One cloaked message per frame, as over UDP; the first one tells us the cloak
+            let mut buffered = Vec::new();
+            let cloak = loop {
+                match receiver.cloaks.sniff(&buffered) {
+                    Sniffed::Found(cloak) => break cloak,
+                    Sniffed::Unknown => return,
+                    Sniffed::Incomplete => {}
+                }
+                let mut chunk = [0u8; 4096];
+                match reader.read(&mut chunk) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buffered.extend_from_slice(&chunk[..n]),
+                }
+            };
+            let mut deframer = Deframer::with_buffered(cloak.clone(), buffered);
+            while let Ok(Some(frame)) = deframer.read_blocking(&mut reader) {
+                receiver.handle_frame(&frame, Origin::Tcp(&stream), cloak.as_ref());
+            }
+        });
+    }
+}
+
+struct Receiver {
+    cipher: XChaCha20Poly1305,
+    cloaks: cloak::Registry,
+    // Sessions by connection id, not by source address (which NATs rebind)
+    sessions: Mutex<HashMap<u64, Session>>,
+}
+
+/// Where a frame came from, and so how to answer it
+enum Origin<'a> {
+    Udp(&'a UdpSocket, SocketAddr),
+    Tcp(&'a TcpStream),
+}
+
+impl Receiver {
+    /// One frame from a sender; whatever goes back wears `cloak`, as the frame did
+    fn handle_frame(&self, binary_data: &[u8], origin: Origin, cloak: &dyn Cloak) {
+        let cipher = &self.cipher;
+        // [LAYER 0] FIND THE SESSION
+        let Some(dgram) = framing::DatagramHeader::from_bytes(binary_data) else { return; };
+        let body = &binary_data[framing::DATAGRAM_HEADER_SIZE..];
+        let mut sessions = self.sessions.lock().unwrap();
+
+        if dgram.kind == framing::KIND_PATH_RESPONSE {
+            if let Origin::Udp(_, src) = origin
+                && let Some(session) = sessions.get_mut(&dgram.conn_id)
+                && session.path.validate(src, body, cipher, dgram.conn_id) {
+                println!("\n[PATH] Connection {:016x} validated {}", dgram.conn_id, src);
+            }
+            return;
+        }
+        if dgram.kind != framing::KIND_DATA { return; }
+
+        let session = sessions.entry(dgram.conn_id).or_insert_with(|| {
+            println!("[SESSION] New connection {:016x} from {}", dgram.conn_id, origin);
+            Session::new(&origin)
+        });
+
+        // A known id from a new address: keep replying to the old one until this one proves itself
+        // (a stream carrier needs no such proof: its handshake already showed the peer is there)
+        if let Origin::Udp(socket, src) = origin
+            && let Some(token) = session.path.observe(src) {
+            println!("\n[PATH] Connection {:016x} seen from {}. Challenging.", dgram.conn_id, src);
+            let mut challenge = framing::DatagramHeader::new(dgram.conn_id, framing::KIND_PATH_CHALLENGE).to_bytes().to_vec();
+            challenge.extend(token);
+            socket.send_to(&cloak.wrap_reply(&challenge, 0), src).ok();
+        }
+
+        // [LAYER 1] PARSE PROTEUS HEADER
+        if body.len() < framing::HEADER_SIZE { return; }
+        
+        let (head_bytes, symbol_bytes) = body.split_at(framing::HEADER_SIZE);
+        
+        if let Some(header) = framing::PacketHeader::from_bytes(head_bytes) {
+            // ACK the way it came in, once that way is validated
+            let ack = framing::AckPacket::new(header.seq_id, header.timestamp);
+            let mut ack_bytes = framing::DatagramHeader::new(dgram.conn_id, framing::KIND_ACK).to_bytes().to_vec();
+            ack_bytes.extend(ack.to_bytes());
+            reply(&origin, &session.path, &cloak.wrap_reply(&ack_bytes, header.seq_id));
+        }
+
+        if session.delivered { return; }
+
+        // [LAYER 2] RAPTORQ & DECRYPT
+        // One source block, and a symbol with its payload id: anything else
+        // would trip the decoder (while we hold every session's lock)
+        if symbol_bytes.len() < 4 || symbol_bytes[0] != 0 { return; }
+        let packet = EncodingPacket::deserialize(symbol_bytes);
+        
+        if let Some(decoded_data) = session.decoder.decode(packet) {
+            println!("\n[!!!] RESURRECTION COMPLETE!");
+
+            if decoded_data.len() < 2 { return; } 
+            
+            let real_len = u16::from_be_bytes([decoded_data[0], decoded_data[1]]) as usize;
+
+            println!("-> Size Header says: {} bytes (Buffer is {})", real_len, decoded_data.len());
+
+            let Some(valid_data) = decoded_data.get(2..2 + real_len) else { return; };
+            let Some((nonce_bytes, ciphertext)) = valid_data.split_at_checked(24) else { return; };
+            let nonce = XNonce::from_slice(nonce_bytes);
+            
+            match cipher.decrypt(nonce, ciphertext) {
+                Ok(msg) => {
+                    println!("------------------------------------------------");
+                    println!("MESSAGE: \"{}\"", String::from_utf8_lossy(&msg));
+                    println!("------------------------------------------------");
+                    session.delivered = true;
+                },
+                Err(_) => println!("Decryption Error"),
+            }
+        } else {
+            print!("."); 
+            std::io::stdout().flush().unwrap();
+        }
+    }
+}
+
+impl fmt::Display for Origin<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Udp(_, src) => write!(f, "udp://{}", src),
+            Origin::Tcp(stream) => match stream.peer_addr() {
+                Ok(peer) => write!(f, "tcp://{}", peer),
+                Err(_) => write!(f, "tcp://?"),
+            },
+        }
+    }
+}
+
+/// Send a cloaked reply back: over UDP to a validated address, over TCP as it is
+fn reply(origin: &Origin, path: &PeerPath, message: &[u8]) {
+    match origin {
+        Origin::Udp(socket, src) => {
+            if let Some(to) = path.reply_to(*src) { socket.send_to(message, to).ok(); }
+        }
+        Origin::Tcp(stream) => {
+            let mut stream: &TcpStream = stream;
+            stream.write_all(message).ok();
+        }
+    }
+}
+
+/// One sender, wherever it currently is
+struct Session {
+    path: PeerPath,
+    decoder: Decoder,
+    // The message is out; keep ACKing (and following the peer) but stop decoding
+    delivered: bool,
+}
+
+impl Session {
+    fn new(origin: &Origin) -> Self {
+        let approx_payload_size = 512; 
+        let config = ObjectTransmissionInformation::new(
+            approx_payload_size, 
+            SYMBOL_SIZE, 
+            1, 1, 1
+        );
+        // The address that opens a session over UDP is trusted as its first path
+        let path = match origin {
+            Origin::Udp(_, src) => PeerPath::new(*src),
+            Origin::Tcp(_) => PeerPath::default(),
+        };
+        Self { path, decoder: Decoder::new(config), delivered: false }
+    }
+}
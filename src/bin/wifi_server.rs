@@ -2,12 +2,11 @@ use std::net::UdpSocket;
 use std::thread;
 use std::time::Duration;
 use raptorq::Encoder;
-use base64::{Engine as _, engine::general_purpose};
 use chacha20poly1305::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
     XChaCha20Poly1305
 };
-use proteus_core::SYMBOL_SIZE;
+use proteus_core::{SYMBOL_SIZE, cloak, shape};
 use dotenv::dotenv;
 use std::env;
 
@@ -18,6 +17,11 @@ fn main() {
     dotenv().ok();
     let target_ip = env::var("TARGET_IP")
         .expect("ERROR: TARGET_IP not set in .env file!");
+    // The beacon's own HTTP disguise unless CLOAK picks another
+    let cloak_name = env::var("CLOAK").unwrap_or_else(|_| "beacon".to_string());
+    let cloak = cloak::Registry::builtin().get(&cloak_name)
+        .map(shape::shaped)
+        .unwrap_or_else(|| panic!("ERROR: unknown CLOAK '{}'", cloak_name));
 
     // CHANGED: We now hide the IP in the console output
     println!("[READY] Mimicking Google Traffic to [REDACTED TARGET]");
@@ -46,18 +50,9 @@ fn main() {
         let data = symbol.serialize();
 
         // 3. Cloak as HTTP
-        let b64_data = general_purpose::STANDARD.encode(&data);
+        let http_packet = cloak.wrap(&data, seq);
 
-        let http_packet = format!(
-            "GET /api/v1/sync?seq={} HTTP/1.1\r\n\
-             Host: www.google.com\r\n\
-             User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)\r\n\
-             X-Goog-Payload: {}\r\n\
-             \r\n", 
-            seq, b64_data
-        );
-
-        match socket.send_to(http_packet.as_bytes(), &target_ip) {
+        match socket.send_to(&http_packet, &target_ip) {
             Ok(_) => {
                 seq += 1;
                 if seq % 10 == 0 { print!("."); }
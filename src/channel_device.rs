@@ -0,0 +1,103 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+
+// --- IN-MEMORY DEVICE ---
+
+/// A smoltcp `phy::Device` whose "wire" is a pair of in-process channels.
+///
+/// Frames smoltcp transmits go out on `tx`; frames pushed into `rx` are
+/// what it receives. No kernel TUN, no root: the other end can be the
+/// tunnel, another stack (see `pair`), or a test.
+pub struct ChannelDevice {
+    rx: Receiver<Vec<u8>>,
+    tx: Sender<Vec<u8>>,
+    // A frame pulled off `rx` by `wait()` that smoltcp has not consumed yet
+    pending: Option<Vec<u8>>,
+    medium: Medium,
+    mtu: usize,
+}
+
+pub struct ChannelRxToken(Vec<u8>);
+pub struct ChannelTxToken<'a>(&'a Sender<Vec<u8>>);
+
+impl phy::RxToken for ChannelRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for ChannelTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0u8; len];
+        let result = f(&mut frame);
+        // Nobody listening on the other end is the same as a cut cable
+        self.0.send(frame).ok();
+        result
+    }
+}
+
+impl ChannelDevice {
+    pub fn new(medium: Medium, mtu: usize, rx: Receiver<Vec<u8>>, tx: Sender<Vec<u8>>) -> Self {
+        Self { rx, tx, pending: None, medium, mtu }
+    }
+
+    /// Two devices wired back to back: whatever one transmits, the other receives
+    pub fn pair(medium: Medium, mtu: usize) -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (Self::new(medium, mtu, a_rx, a_tx), Self::new(medium, mtu, b_rx, b_tx))
+    }
+
+    /// Block until a frame arrives or `timeout` passes (`None` = forever).
+    /// Drive it with `Interface::poll_delay` instead of spinning.
+    /// Returns false once the other end has gone away.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> bool {
+        if self.pending.is_some() { return true; }
+        let received = match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout),
+            None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(frame) => {
+                self.pending = Some(frame);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.pending.take() { return Some(frame); }
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Device for ChannelDevice {
+    type RxToken<'a> = ChannelRxToken where Self: 'a;
+    type TxToken<'a> = ChannelTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.next_frame()?;
+        Some((ChannelRxToken(frame), ChannelTxToken(&self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(ChannelTxToken(&self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = self.medium;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
@@ -1,29 +1,57 @@
-use std::net::{UdpSocket, TcpStream};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use raptorq::Encoder;
-use base64::{Engine as _, engine::general_purpose};
+use std::time::{Duration, Instant};
+use raptorq::{Encoder, EncodingPacket};
+use socket2::{Domain, Socket, Type};
 use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng}, XChaCha20Poly1305};
-use crate::{SYMBOL_SIZE, framing, oracle, transport};
+use rand::Rng;
+use crate::cloak::{Cloak, Deframer, Unwrapped};
+use crate::failover::{self, CarrierSpec, Failover, Scheme};
+use crate::{SYMBOL_SIZE, framing, migration, multipath, shape, transport};
 
-pub fn start_sender(target: String, message: String, use_tcp: bool) {
-    println!("[CLIENT] Target: {} | Mode: {}", target, if use_tcp { "SHADOW TCP" } else { "UDP" });
-    
-    let transport = if use_tcp {
-        println!("[SETUP] Connecting TCP...");
-        let stream = TcpStream::connect(&target).expect("TCP Failed");
-        stream.set_nodelay(true).ok();
-        // FIXED: Added Arc::new(...) to match the new TransportType
-        transport::TransportType::Tcp(Arc::new(Mutex::new(stream)))
-    } else {
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("UDP Bind Failed");
-        transport::TransportType::Udp(socket)
-    };
+const DIAL_TIMEOUT: Duration = Duration::from_secs(2);
+// A carrier that cannot take a frame for this long is as good as down
+const WRITE_TIMEOUT: Duration = Duration::from_secs(3);
+// How often an idle UDP reply reader checks whether its carrier was dropped
+const REPLY_POLL: Duration = Duration::from_secs(1);
+
+/// Send `message` to `target`, falling back along `fallback` (best first)
+/// if it stops working. Each entry in `via` (a local IP or an interface
+/// name) is an uplink to bond; none means the default route. Every frame
+/// goes out dressed in `cloak`, at a length drawn from its histogram.
+pub fn start_sender(target: String, message: String, use_tcp: bool, via: Vec<String>, fallback: Vec<CarrierSpec>, cloak: Arc<dyn Cloak>) {
+    // A bare HOST:PORT target is TCP or UDP by the --tcp flag
+    let primary = target.parse::<CarrierSpec>().unwrap_or(CarrierSpec {
+        scheme: if use_tcp { Scheme::Tcp } else { Scheme::Udp },
+        target,
+    });
+    let mut carriers = vec![primary];
+    carriers.extend(fallback);
+    let names: Vec<String> = carriers.iter().map(|carrier| carrier.to_string()).collect();
+    println!("[CLIENT] Carriers: {}", names.join(" -> "));
+    let cloak = shape::shaped(cloak);
 
-    let oracle = Arc::new(Mutex::new(oracle::NetworkOracle::new()));
-    
     let key_bytes = [0u8; 32];
     let cipher = XChaCha20Poly1305::new(&key_bytes.into());
+
+    // Frames carry a connection id so the session outlives our NAT mapping
+    // and its symbols can arrive over any of our paths and carriers
+    let conn_id: u64 = rand::rng().random();
+    let uplinks: Vec<Option<String>> = if via.is_empty() { vec![None] } else { via.into_iter().map(Some).collect() };
+    let paths = uplinks.into_iter().map(|uplink| {
+        let name = uplink.clone().unwrap_or_else(|| "default".to_string());
+        let acks = multipath::AckTracker::default();
+        let dialer: failover::Dialer = {
+            let (acks, cipher, cloak) = (acks.clone(), cipher.clone(), cloak.clone());
+            Arc::new(move |spec: &CarrierSpec, heard| dial(spec, uplink.as_deref(), conn_id, &cipher, &cloak, &acks, heard))
+        };
+        multipath::Path::new(name.clone(), Failover::new(name, carriers.clone(), dialer), &acks)
+    }).collect();
+    let mut scheduler = multipath::Scheduler::new(paths);
+    
     let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
     let encrypted = cipher.encrypt(&nonce, message.as_bytes()).unwrap();
     
@@ -34,31 +62,153 @@ pub fn start_sender(target: String, message: String, use_tcp: bool) {
     final_payload.extend(blob);
 
     let encoder = Encoder::with_defaults(&final_payload, SYMBOL_SIZE);
+    let mut symbols = SymbolFeed::new(&encoder);
 
     println!("[CLIENT] Sending...");
     let mut seq = 0;
     loop {
-        let packets = encoder.get_encoded_packets(1);
-        let symbol_data = packets[0].serialize();
+        let symbol_data = symbols.next().serialize();
+        // Whichever path is due next carries this symbol (stamp it after the wait)
+        let path = scheduler.next_path();
 
         let header = framing::PacketHeader::new(seq);
-        let header_bytes = header.to_bytes();
-        let mut packet_data = header_bytes.to_vec();
+        let mut packet_data = framing::DatagramHeader::new(conn_id, framing::KIND_DATA).to_bytes().to_vec();
+        packet_data.extend(header.to_bytes());
         packet_data.extend(symbol_data);
 
-        let b64_data = general_purpose::STANDARD.encode(&packet_data);
-        // Important: Add newline for compatibility with the new server
-        let http_packet = format!("GET /search?q={}&seq={} HTTP/1.1\n", b64_data, seq);
+        path.send(&cloak.wrap(&packet_data, seq), seq).ok();
+        seq += 1;
+    }
+}
+
+/// Open one carrier, pinned to a local address or interface if given, and
+/// start reading what the receiver sends back on it
+fn dial(spec: &CarrierSpec, uplink: Option<&str>, conn_id: u64, cipher: &XChaCha20Poly1305, cloak: &Arc<dyn Cloak>, acks: &multipath::AckTracker, heard: failover::Heard) -> io::Result<transport::TransportType> {
+    let remote = spec.target.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", spec.target)))?;
+    let kind = match spec.scheme { Scheme::Tcp => Type::STREAM, Scheme::Udp => Type::DGRAM };
+    let socket = Socket::new(Domain::for_address(remote), kind, None)?;
 
-        transport.send(http_packet.as_bytes(), &target).ok();
+    let local = match uplink.map(|uplink| uplink.parse::<IpAddr>()) {
+        Some(Ok(local)) => Some(local),
+        // Not an address: an interface name
+        Some(Err(_)) => { socket.bind_device(uplink.map(str::as_bytes))?; None }
+        None => None,
+    };
+    // UDP binds up front so its reply thread can listen before the first send
+    if local.is_some() || spec.scheme == Scheme::Udp {
+        socket.bind(&SocketAddr::new(local.unwrap_or_else(|| unspecified(remote)), 0).into())?;
+    }
 
-        let pacing;
-        {
-            // Now we can use the updated public methods
-            let brain = oracle.lock().unwrap();
-            pacing = brain.get_pacing_interval();
+    let replies = Replies { conn_id, target: spec.target.clone(), cipher: cipher.clone(), cloak: cloak.clone(), acks: acks.clone(), heard };
+    match spec.scheme {
+        Scheme::Tcp => {
+            socket.connect_timeout(&remote.into(), DIAL_TIMEOUT)?;
+            let stream = TcpStream::from(socket);
+            stream.set_nodelay(true).ok();
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            let reader = stream.try_clone()?;
+            thread::spawn(move || replies.serve_tcp(reader));
+            // FIXED: Added Arc::new(...) to match the new TransportType
+            Ok(transport::TransportType::Tcp(Arc::new(Mutex::new(stream))))
+        }
+        Scheme::Udp => {
+            let socket = UdpSocket::from(socket);
+            let reader = socket.try_clone()?;
+            reader.set_read_timeout(Some(REPLY_POLL))?;
+            thread::spawn(move || replies.serve_udp(reader));
+            Ok(transport::TransportType::Udp(socket))
+        }
+    }
+}
+
+fn unspecified(remote: SocketAddr) -> IpAddr {
+    if remote.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() }
+}
+
+/// Every symbol is a new one (source symbols, then fresh repair symbols), so
+/// whichever path delivers it, it moves the decoder forward
+struct SymbolFeed<'a> {
+    encoder: &'a Encoder,
+    queue: VecDeque<EncodingPacket>,
+    next_repair: u32,
+}
+
+impl<'a> SymbolFeed<'a> {
+    fn new(encoder: &'a Encoder) -> Self {
+        Self { encoder, queue: encoder.get_encoded_packets(0).into(), next_repair: 0 }
+    }
+
+    fn next(&mut self) -> EncodingPacket {
+        if self.queue.is_empty() {
+            for block in self.encoder.get_block_encoders() {
+                self.queue.extend(block.repair_packets(self.next_repair, 1));
+            }
+            self.next_repair += 1;
+        }
+        self.queue.pop_front().unwrap()
+    }
+}
+
+/// One carrier's return traffic. Anything from the receiver shows the
+/// carrier is alive; ACKs also feed the path's oracle. Challenges (UDP only)
+/// prove we own this carrier's address: the receiver will not send to a new
+/// address (a NAT rebinding, another uplink or carrier) until it answers.
+struct Replies {
+    conn_id: u64,
+    target: String,
+    cipher: XChaCha20Poly1305,
+    cloak: Arc<dyn Cloak>,
+    acks: multipath::AckTracker,
+    heard: failover::Heard,
+}
+
+impl Replies {
+    fn serve_udp(self, socket: UdpSocket) {
+        // A shaped reply may come in several pieces, all in one datagram
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(size) => {
+                    // Replies wear our cloak too, one message per datagram
+                    let Unwrapped::Message { frame: Some(reply), .. } = self.cloak.unwrap(&buffer[..size]) else { continue; };
+                    let Some(token) = self.handle(&reply) else { continue; };
+                    let mut frame = framing::DatagramHeader::new(self.conn_id, framing::KIND_PATH_RESPONSE).to_bytes().to_vec();
+                    frame.extend(migration::respond(&self.cipher, self.conn_id, &token));
+                    println!("[PATH] Answering path challenge from {} on {:?}", self.target, socket.local_addr());
+                    socket.send_to(&self.cloak.wrap(&frame, 0), &self.target).ok();
+                }
+                // Idle: stop once the carrier has been dropped
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if Arc::strong_count(&self.heard) == 1 { return; }
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Replies on a stream are cloaked messages, back to back
+    fn serve_tcp(self, mut stream: TcpStream) {
+        let mut deframer = Deframer::new(self.cloak.clone());
+        while let Ok(Some(reply)) = deframer.read_blocking(&mut stream) {
+            self.handle(&reply);
+        }
+    }
+
+    /// Returns the token of a path challenge, which needs an answer
+    fn handle(&self, reply: &[u8]) -> Option<Vec<u8>> {
+        let header = framing::DatagramHeader::from_bytes(reply)?;
+        if header.conn_id != self.conn_id { return None; }
+        *self.heard.lock().unwrap() = Instant::now();
+
+        let body = &reply[framing::DATAGRAM_HEADER_SIZE..];
+        match header.kind {
+            framing::KIND_ACK => {
+                if let Some(ack) = framing::AckPacket::from_bytes(body) { self.acks.on_ack(&ack); }
+                None
+            }
+            framing::KIND_PATH_CHALLENGE => Some(body.to_vec()),
+            _ => None,
         }
-        seq += 1;
-        thread::sleep(pacing);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,694 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{Engine as _, engine::general_purpose};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, DuplexStream, ReadHalf, WriteHalf};
+use crate::shape::{self, Histogram};
+
+// --- CLOAKS ---
+// A cloak dresses each frame up as a message of some innocent protocol and
+// finds frames again in what arrives. The core never sees the disguise: it
+// hands frames to its session's cloak and gets frames back. A client picks
+// one per session; the relay tells them apart by which of its cloaks
+// understands the client's first message.
+
+pub const DEFAULT_CLOAK: &str = "http";
+
+/// Longest message a cloak waits for before calling the stream garbage
+pub const MAX_MESSAGE: usize = 64 * 1024;
+
+pub trait Cloak: Send + Sync {
+    /// What `--cloak` calls it
+    fn name(&self) -> &str;
+
+    /// Disguise one frame as a whole carrier message
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8>;
+
+    /// The same, going back from the relay. Cloaks that look alike both
+    /// ways (one line per frame, say) need not tell the two apart.
+    fn wrap_reply(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        self.wrap(frame, seq)
+    }
+
+    /// Look for a complete message at the front of `buf`
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped;
+
+    /// Most frame bytes one message can carry
+    fn capacity(&self) -> usize {
+        MAX_MESSAGE / 2
+    }
+
+    /// How long messages should come out on the wire (see `shape`); None
+    /// leaves it to the built-in histogram
+    fn lengths(&self) -> Option<&Histogram> {
+        None
+    }
+
+    /// The same, going back from the relay
+    fn reply_lengths(&self) -> Option<&Histogram> {
+        self.lengths()
+    }
+
+    /// Whether every reply must answer a request of its own, as in HTTP/1.1
+    /// (see `pairing`). Such a cloak wraps an empty frame as a message that
+    /// carries none: a poll, or an answer with nothing in it.
+    fn paired(&self) -> bool {
+        false
+    }
+}
+
+pub enum Unwrapped {
+    /// Not a whole message yet
+    Incomplete,
+    /// A message `len` bytes long and the frame it carried, if any
+    /// (a message without one of our frames is skipped, not fatal)
+    Message { len: usize, frame: Option<Vec<u8>> },
+    /// This cannot be the cloak's traffic
+    Invalid,
+}
+
+/// What a peer's first bytes say about the cloak it wears
+pub enum Sniffed {
+    Found(Arc<dyn Cloak>),
+    Incomplete,
+    Unknown,
+}
+
+/// The cloaks a node knows, by name
+#[derive(Clone)]
+pub struct Registry {
+    cloaks: Vec<Arc<dyn Cloak>>,
+}
+
+impl Registry {
+    /// Every cloak compiled in
+    pub fn builtin() -> Self {
+        Self { cloaks: vec![Arc::new(SearchCloak), Arc::new(BeaconCloak), Arc::new(HttpCloak::new(HTTP_HOST))] }
+    }
+
+    /// Learn another cloak (a loaded profile, say). It is tried before the
+    /// built-in ones when sniffing: an operator's cloak is the more specific.
+    pub fn add(&mut self, cloak: Arc<dyn Cloak>) -> Result<(), String> {
+        if self.get(cloak.name()).is_some() {
+            return Err(format!("a cloak named '{}' already exists", cloak.name()));
+        }
+        self.cloaks.insert(0, cloak);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Cloak>> {
+        self.cloaks.iter().find(|cloak| cloak.name() == name).cloned()
+    }
+
+    /// The same cloaks, each with its lengths shaped (see `shape`)
+    pub fn shaped(&self) -> Self {
+        Self { cloaks: self.cloaks.iter().cloned().map(shape::shaped).collect() }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.cloaks.iter().map(|cloak| cloak.name().to_string()).collect()
+    }
+
+    /// Which cloak a peer wears, judging by the start of what it sent
+    pub fn sniff(&self, buf: &[u8]) -> Sniffed {
+        let mut waiting = false;
+        for cloak in &self.cloaks {
+            match cloak.unwrap(buf) {
+                Unwrapped::Message { frame: Some(_), .. } => return Sniffed::Found(cloak.clone()),
+                Unwrapped::Incomplete => waiting = true,
+                Unwrapped::Message { frame: None, .. } | Unwrapped::Invalid => {}
+            }
+        }
+        if waiting { Sniffed::Incomplete } else { Sniffed::Unknown }
+    }
+
+    /// The frame in a self-contained message (a datagram), whatever it
+    /// wears, and the cloak to answer in
+    pub fn unwrap_message(&self, message: &[u8]) -> Option<(Arc<dyn Cloak>, Vec<u8>)> {
+        self.cloaks.iter().find_map(|cloak| match cloak.unwrap(message) {
+            Unwrapped::Message { frame: Some(frame), .. } => Some((cloak.clone(), frame)),
+            _ => None,
+        })
+    }
+}
+
+/// The relay's side of `cloak`: what it sends are replies
+pub fn replying(cloak: Arc<dyn Cloak>) -> Arc<dyn Cloak> {
+    Arc::new(Replying(cloak))
+}
+
+struct Replying(Arc<dyn Cloak>);
+
+impl Cloak for Replying {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        self.0.wrap_reply(frame, seq)
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        self.0.unwrap(buf)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    fn lengths(&self) -> Option<&Histogram> {
+        self.0.reply_lengths()
+    }
+
+    fn reply_lengths(&self) -> Option<&Histogram> {
+        self.0.lengths()
+    }
+
+    fn paired(&self) -> bool {
+        self.0.paired()
+    }
+}
+
+/// Bare frames, [len u32][frame], for carriers that are disguise enough on
+/// their own (WebSocket, DNS): they move each frame as one message of theirs
+/// and hand the stream these through a pipe (see `Halves`).
+pub fn framed() -> Arc<dyn Cloak> {
+    Arc::new(Framed)
+}
+
+/// Our end of such a carrier's pipe
+pub type Halves = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+pub(crate) struct Framed;
+
+impl Cloak for Framed {
+    fn name(&self) -> &str {
+        "framed"
+    }
+
+    fn wrap(&self, frame: &[u8], _seq: u32) -> Vec<u8> {
+        let mut message = (frame.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(frame);
+        message
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let Some(len) = buf.get(..4).map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize) else {
+            return Unwrapped::Incomplete;
+        };
+        if len > MAX_MESSAGE { return Unwrapped::Invalid; }
+        match buf.get(4..4 + len) {
+            Some(frame) => Unwrapped::Message { len: 4 + len, frame: Some(frame.to_vec()) },
+            None => Unwrapped::Incomplete,
+        }
+    }
+}
+
+/// Turns a carrier's bytes back into frames
+pub struct Deframer {
+    cloak: Arc<dyn Cloak>,
+    buf: Vec<u8>,
+}
+
+impl Deframer {
+    pub fn new(cloak: Arc<dyn Cloak>) -> Self {
+        Self::with_buffered(cloak, Vec::new())
+    }
+
+    /// Start from bytes already read off the carrier
+    pub fn with_buffered(cloak: Arc<dyn Cloak>, buf: Vec<u8>) -> Self {
+        Self { cloak, buf }
+    }
+
+    /// The next frame among the bytes received so far, if there is a whole one
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.cloak.unwrap(&self.buf) {
+                Unwrapped::Incomplete => return Ok(None),
+                Unwrapped::Message { len, frame } => {
+                    self.buf.drain(..len);
+                    if frame.is_some() { return Ok(frame); }
+                }
+                Unwrapped::Invalid => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("not {} traffic", self.cloak.name())));
+                }
+            }
+        }
+    }
+
+    /// Next frame off `reader`, or None at end of stream
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(frame) = self.next_frame()? { return Ok(Some(frame)); }
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 { return Ok(None); }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Same as `read`, for blocking sockets
+    pub fn read_blocking<R: Read>(&mut self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(frame) = self.next_frame()? { return Ok(Some(frame)); }
+            let n = reader.read(&mut chunk)?;
+            if n == 0 { return Ok(None); }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Bytes received past the last frame returned
+    pub fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// --- BUILT-IN CLOAKS ---
+
+/// One fake search per frame: `GET /search?q=<base64>&seq=<n> HTTP/1.1`
+pub struct SearchCloak;
+
+const SEARCH_START: &[u8] = b"GET /search?q=";
+
+/// Whether `buf`, however short, could still be the start of a message
+/// opening with `start`: junk is refused without waiting for a line end
+fn opens(buf: &[u8], start: &[u8]) -> bool {
+    buf.starts_with(start) || start.starts_with(buf)
+}
+
+impl Cloak for SearchCloak {
+    fn name(&self) -> &str {
+        "search"
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        let b64_data = general_purpose::STANDARD.encode(frame);
+        // Important: Add newline for compatibility with the new server
+        format!("GET /search?q={}&seq={} HTTP/1.1\n", b64_data, seq).into_bytes()
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+            return if buf.len() > MAX_MESSAGE || !opens(buf, SEARCH_START) { Unwrapped::Invalid } else { Unwrapped::Incomplete };
+        };
+        let line = String::from_utf8_lossy(&buf[..end]);
+        let frame = line.find("q=")
+            .zip(line.find("&seq"))
+            .and_then(|(start, end)| line.get(start + 2..end))
+            .and_then(|b64| general_purpose::STANDARD.decode(b64).ok());
+        Unwrapped::Message { len: end + 1, frame }
+    }
+}
+
+/// A sync call with the payload in a header, as the old HTTP beacon sent it
+pub struct BeaconCloak;
+
+const BEACON_HEADER: &str = "X-Goog-Payload:";
+const BEACON_START: &[u8] = b"GET /api/v1/sync?seq=";
+
+impl Cloak for BeaconCloak {
+    fn name(&self) -> &str {
+        "beacon"
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        let b64_data = general_purpose::STANDARD.encode(frame);
+        format!(
+            "GET /api/v1/sync?seq={} HTTP/1.1\r\n\
+             Host: www.google.com\r\n\
+             User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)\r\n\
+             {} {}\r\n\
+             \r\n",
+            seq, BEACON_HEADER, b64_data
+        ).into_bytes()
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return if buf.len() > MAX_MESSAGE || !opens(buf, BEACON_START) { Unwrapped::Invalid } else { Unwrapped::Incomplete };
+        };
+        let head = String::from_utf8_lossy(&buf[..end]);
+        let frame = head.lines()
+            .find_map(|line| line.strip_prefix(BEACON_HEADER))
+            .and_then(|b64| general_purpose::STANDARD.decode(b64.trim()).ok());
+        Unwrapped::Message { len: end + 4, frame }
+    }
+}
+
+// --- HTTP/1.1 CLOAK ---
+// Every frame is a complete, well-formed HTTP/1.1 exchange on a keep-alive
+// connection: the client POSTs, the relay answers 200 with the frame as the
+// body. Requests spread the frame over the query string, a cookie and the
+// body, so no single field carries a suspicious blob. Both directions go
+// through the same strict parser; anything a real HTTP/1.1 peer would not
+// send (bare LFs, folded or nameless headers, conflicting lengths, chunked
+// bodies) is refused rather than guessed at. The relay answers each request
+// with exactly one response, holding its frames until a request comes to
+// fetch them (see `pairing`).
+
+const HTTP_HOST: &str = "www.google.com";
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+// Frame bytes carried in the query string and the session cookie; the rest goes in the body
+const QUERY_PART: usize = 48;
+const COOKIE_PART: usize = 96;
+// Largest header block we accept; nothing of ours comes close
+pub(crate) const MAX_HEAD: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// POSTs to an analytics-looking endpoint, answered by plain 200s
+pub struct HttpCloak {
+    host: String,
+    // A stable `_ga` cookie, as a returning visitor would have
+    visitor: u32,
+    since: u64,
+}
+
+impl HttpCloak {
+    pub fn new(host: &str) -> Self {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).map(|age| age.as_secs()).unwrap_or(0);
+        Self { host: host.to_string(), visitor: rand::rng().random(), since }
+    }
+}
+
+impl Cloak for HttpCloak {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        let (query, rest) = frame.split_at(frame.len().min(QUERY_PART));
+        let (cookie, body) = rest.split_at(rest.len().min(COOKIE_PART));
+        let mut message = format!(
+            "POST /api/v2/collect?v=2&tid={}&d={} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: {}\r\n\
+             Accept: */*\r\n\
+             Accept-Language: en-US,en;q=0.9\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Length: {}\r\n\
+             Cookie: _ga=GA1.2.{}.{}; sid={}\r\n\
+             Connection: keep-alive\r\n\
+             \r\n",
+            seq, general_purpose::URL_SAFE_NO_PAD.encode(query),
+            self.host, USER_AGENT, body.len(),
+            self.visitor, self.since, general_purpose::URL_SAFE_NO_PAD.encode(cookie)
+        ).into_bytes();
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn wrap_reply(&self, frame: &[u8], _seq: u32) -> Vec<u8> {
+        let mut message = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Date: {}\r\n\
+             Server: nginx\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: no-store\r\n\
+             Connection: keep-alive\r\n\
+             \r\n",
+            http_date(SystemTime::now()), frame.len()
+        ).into_bytes();
+        message.extend_from_slice(frame);
+        message
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let message = match parse_http(buf) {
+            Parsed::Incomplete => return Unwrapped::Incomplete,
+            Parsed::Invalid => return Unwrapped::Invalid,
+            Parsed::Message(message) => message,
+        };
+        let frame = match message.start {
+            StartLine::Response { status: 200 } if !message.body.is_empty() => Some(message.body.to_vec()),
+            StartLine::Response { .. } => None,
+            StartLine::Request { target } => request_frame(target, &message),
+        };
+        Unwrapped::Message { len: message.len, frame }
+    }
+
+    fn paired(&self) -> bool {
+        true
+    }
+}
+
+/// Reassemble a frame from the query, the cookie and the body of a request
+fn request_frame(target: &str, message: &HttpMessage) -> Option<Vec<u8>> {
+    let (_, query) = target.split_once('?')?;
+    let query = query.split('&').find_map(|param| param.strip_prefix("d="))?;
+    let cookie = message.header("cookie")
+        .and_then(|cookies| cookies.split(';').find_map(|cookie| cookie.trim().strip_prefix("sid=")))
+        .unwrap_or("");
+    let mut frame = general_purpose::URL_SAFE_NO_PAD.decode(query).ok()?;
+    frame.extend(general_purpose::URL_SAFE_NO_PAD.decode(cookie).ok()?);
+    frame.extend_from_slice(message.body);
+    // An empty one is a poll
+    Some(frame).filter(|frame| !frame.is_empty())
+}
+
+pub(crate) enum StartLine<'a> {
+    Request { target: &'a str },
+    Response { status: u16 },
+}
+
+pub(crate) struct HttpMessage<'a> {
+    pub len: usize,
+    pub start: StartLine<'a>,
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body: &'a [u8],
+}
+
+impl HttpMessage<'_> {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+    }
+}
+
+pub(crate) enum Parsed<'a> {
+    Incomplete,
+    Message(HttpMessage<'a>),
+    Invalid,
+}
+
+/// One HTTP/1.1 message off the front of `buf`, strictly by RFC 9112
+pub(crate) fn parse_http(buf: &[u8]) -> Parsed<'_> {
+    let Some(head_end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+        // No need to wait for the rest of a head that is already unprintable
+        // (or ends its lines in anything but CRLF)
+        let broken = !buf.iter().copied().all(is_head_byte) || bare_line_end(buf);
+        return if buf.len() > MAX_HEAD || broken { Parsed::Invalid } else { Parsed::Incomplete };
+    };
+    if head_end > MAX_HEAD { return Parsed::Invalid; }
+    // Printable ASCII only; CR and LF appear solely as line ends
+    let Ok(head) = std::str::from_utf8(&buf[..head_end]) else { return Parsed::Invalid; };
+    if !head.bytes().all(is_head_byte) { return Parsed::Invalid; }
+    let mut lines = head.split("\r\n");
+    if lines.clone().any(|line| line.contains(['\r', '\n'])) { return Parsed::Invalid; }
+
+    let Some(start) = lines.next().and_then(parse_start_line) else { return Parsed::Invalid; };
+    let mut headers = Vec::new();
+    let mut length = None;
+    for line in lines {
+        if headers.len() == MAX_HEADERS { return Parsed::Invalid; }
+        // A name of token characters straight up to the colon: this also
+        // refuses obsolete line folding and whitespace before the colon
+        let Some((name, value)) = line.split_once(':') else { return Parsed::Invalid; };
+        if name.is_empty() || !name.bytes().all(is_token) { return Parsed::Invalid; }
+        let value = value.trim_matches([' ', '\t']);
+
+        // No chunked bodies: a length we cannot pin down is a smuggling risk
+        if name.eq_ignore_ascii_case("transfer-encoding") { return Parsed::Invalid; }
+        if name.eq_ignore_ascii_case("content-length") {
+            if value.is_empty() || value.len() > 6 || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Parsed::Invalid;
+            }
+            let value: usize = value.parse().unwrap_or(usize::MAX);
+            if length.is_some_and(|length| length != value) { return Parsed::Invalid; }
+            length = Some(value);
+        }
+        headers.push((name, value));
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE { return Parsed::Invalid; }
+    let body_start = head_end + 4;
+    if buf.len() < body_start + length { return Parsed::Incomplete; }
+    Parsed::Message(HttpMessage { len: body_start + length, start, headers, body: &buf[body_start..body_start + length] })
+}
+
+fn parse_start_line(line: &str) -> Option<StartLine<'_>> {
+    let mut parts = line.splitn(3, ' ');
+    let (first, second, third) = (parts.next()?, parts.next()?, parts.next()?);
+    if first == "HTTP/1.1" {
+        // The reason phrase is free text (already checked printable)
+        if second.len() != 3 || !second.bytes().all(|b| b.is_ascii_digit()) { return None; }
+        return Some(StartLine::Response { status: second.parse().ok()? });
+    }
+    let method_ok = !first.is_empty() && first.bytes().all(|b| b.is_ascii_uppercase());
+    let target_ok = second.starts_with('/') && !second.contains(' ');
+    (method_ok && target_ok && third == "HTTP/1.1").then_some(StartLine::Request { target: second })
+}
+
+/// A CR or LF that is not half of a CRLF (a CR last in `buf` may still be)
+fn bare_line_end(buf: &[u8]) -> bool {
+    buf.iter().enumerate().any(|(i, &b)| match b {
+        b'\r' => buf.get(i + 1).is_some_and(|&next| next != b'\n'),
+        b'\n' => i == 0 || buf[i - 1] != b'\r',
+        _ => false,
+    })
+}
+
+fn is_head_byte(b: u8) -> bool {
+    b == b'\r' || b == b'\n' || b == b'\t' || (b' '..=b'~').contains(&b)
+}
+
+pub(crate) fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// IMF-fixdate, as the Date header wants it
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).map(|age| age.as_secs()).unwrap_or(0);
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        rest / 3600, rest % 3600 / 60, rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"POST /api?d=x HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello";
+
+    fn is_invalid(buf: &[u8]) -> bool {
+        matches!(parse_http(buf), Parsed::Invalid)
+    }
+
+    /// `lines`, joined with CRLF, as a whole message without a body
+    fn request(lines: &[&str]) -> Vec<u8> {
+        format!("{}\r\n\r\n", lines.join("\r\n")).into_bytes()
+    }
+
+    #[test]
+    fn parses_a_whole_request() {
+        let Parsed::Message(message) = parse_http(REQUEST) else { panic!("not parsed") };
+        assert_eq!(message.len, REQUEST.len());
+        assert!(matches!(message.start, StartLine::Request { target: "/api?d=x" }));
+        assert_eq!(message.header("HOST"), Some("example.com"));
+        assert_eq!(message.body, b"hello");
+    }
+
+    #[test]
+    fn waits_for_every_split_of_a_request() {
+        for cut in 0..REQUEST.len() {
+            assert!(matches!(parse_http(&REQUEST[..cut]), Parsed::Incomplete), "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_first_message() {
+        let mut two = REQUEST.to_vec();
+        two.extend_from_slice(REQUEST);
+        let Parsed::Message(message) = parse_http(&two) else { panic!("not parsed") };
+        assert_eq!(message.len, REQUEST.len());
+    }
+
+    #[test]
+    fn refuses_bare_line_ends() {
+        assert!(is_invalid(b"GET / HTTP/1.1\nHost: x\n\n"));
+        assert!(is_invalid(b"GET / HTTP/1.1\nHost: x"));
+        assert!(is_invalid(b"GET / HTTP/1.1\r\nHost: x\nX-A: y\r\n\r\n"));
+        assert!(is_invalid(b"GET / HTTP/1.1\r\nHost: x\rX-A: y\r\n\r\n"));
+        // A CR at the very end may be the start of a CRLF
+        assert!(matches!(parse_http(b"GET / HTTP/1.1\r"), Parsed::Incomplete));
+    }
+
+    #[test]
+    fn refuses_oversized_heads() {
+        assert!(is_invalid(&vec![b'a'; MAX_HEAD + 1]));
+        let long = format!("X-Pad: {}", "a".repeat(MAX_HEAD));
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", &long])));
+        let many: Vec<String> = (0..=MAX_HEADERS).map(|i| format!("X-{}: y", i)).collect();
+        let mut lines = vec!["GET / HTTP/1.1"];
+        lines.extend(many.iter().map(String::as_str));
+        assert!(is_invalid(&request(&lines)));
+    }
+
+    #[test]
+    fn refuses_unprintable_heads_early() {
+        assert!(is_invalid(b"GET / HTTP/1.1\r\nHost: \x01"));
+        assert!(is_invalid(b"GET / HTTP/1.1\r\nHost: \xc3\xa9\r\n\r\n"));
+    }
+
+    #[test]
+    fn refuses_lengths_it_cannot_pin_down() {
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: 5", "Transfer-Encoding: chunked"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Transfer-Encoding: chunked"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: 5", "Content-Length: 6"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: 5, 5"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: +5"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: "])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", "Content-Length: 9999999"])));
+        assert!(is_invalid(&request(&["POST / HTTP/1.1", &format!("Content-Length: {}", MAX_MESSAGE + 1)])));
+        // The same length twice is only redundant
+        let mut twice = request(&["POST / HTTP/1.1", "Content-Length: 2", "content-length: 2"]);
+        twice.extend_from_slice(b"ok");
+        assert!(matches!(parse_http(&twice), Parsed::Message(_)));
+    }
+
+    #[test]
+    fn refuses_bad_tokens() {
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", "Host : x"])));
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", ": x"])));
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", "Ho(st): x"])));
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", "Host: x", " folded"])));
+        assert!(is_invalid(&request(&["GET / HTTP/1.1", "no colon"])));
+        assert!(is_invalid(&request(&["get / HTTP/1.1"])));
+        assert!(is_invalid(&request(&["GET x HTTP/1.1"])));
+        assert!(is_invalid(&request(&["GET / HTTP/1.0"])));
+        assert!(is_invalid(&request(&["GET  / HTTP/1.1"])));
+        assert!(is_invalid(&request(&["HTTP/1.1 20x OK"])));
+        assert!(is_invalid(&request(&["HTTP/1.1 2000 OK"])));
+    }
+
+    #[test]
+    fn http_cloak_round_trips() {
+        let cloak = HttpCloak::new(HTTP_HOST);
+        // Across the query, cookie and body boundaries
+        for size in [1, QUERY_PART - 1, QUERY_PART, QUERY_PART + 1, QUERY_PART + COOKIE_PART, QUERY_PART + COOKIE_PART + 1, 5000] {
+            let frame: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            for wrapped in [cloak.wrap(&frame, 7), cloak.wrap_reply(&frame, 7)] {
+                let Unwrapped::Message { len, frame: Some(unwrapped) } = cloak.unwrap(&wrapped) else { panic!("{} bytes did not unwrap", size) };
+                assert_eq!(len, wrapped.len());
+                assert_eq!(unwrapped, frame);
+            }
+        }
+    }
+
+    #[test]
+    fn http_cloak_empty_frames_carry_none() {
+        let cloak = HttpCloak::new(HTTP_HOST);
+        for wrapped in [cloak.wrap(&[], 0), cloak.wrap_reply(&[], 0)] {
+            assert!(matches!(cloak.unwrap(&wrapped), Unwrapped::Message { frame: None, .. }));
+        }
+    }
+}
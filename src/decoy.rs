@@ -0,0 +1,279 @@
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::cloak;
+
+// --- DECOY ---
+// What the relay shows a connection whose first message is not ours: a
+// scanner sweeping the port, or a prober replaying junk dressed in one of
+// our cloaks. Going quiet or hanging up would set the port apart, so the
+// relay hands the connection over, bytes already read and all, to a decoy:
+// the operator's own web server, or a built-in one that answers the way a
+// freshly installed nginx does. From the prober's first byte on it is
+// talking to an ordinary web server.
+
+// Header block nginx buffers before giving up on a request
+const MAX_HEAD: usize = 8 * 1024;
+// nginx's client_max_body_size
+const MAX_BODY: usize = 1024 * 1024;
+/// How long to wait for the (next) request, as client_header_timeout does;
+/// the relay gives a client no longer to say what it is
+pub const IDLE: Duration = Duration::from_secs(60);
+// When the welcome page was "installed"
+const INSTALLED: u64 = 1_681_213_337;
+
+const WELCOME: &str = "<!DOCTYPE html>
+<html>
+<head>
+<title>Welcome to nginx!</title>
+<style>
+html { color-scheme: light dark; }
+body { width: 35em; margin: 0 auto;
+font-family: Tahoma, Verdana, Arial, sans-serif; }
+</style>
+</head>
+<body>
+<h1>Welcome to nginx!</h1>
+<p>If you see this page, the nginx web server is successfully installed and
+working. Further configuration is required.</p>
+
+<p>For online documentation and support please refer to
+<a href=\"http://nginx.org/\">nginx.org</a>.<br/>
+Commercial support is available at
+<a href=\"http://nginx.com/\">nginx.com</a>.</p>
+
+<p><em>Thank you for using nginx.</em></p>
+</body>
+</html>
+";
+
+/// Who answers connections that are not ours
+#[derive(Clone)]
+pub enum Decoy {
+    /// The built-in site: nginx's welcome page
+    Site,
+    /// A real web server at this HOST:PORT, handed the connection as is
+    Backend(String),
+}
+
+impl Decoy {
+    /// Let the decoy have the connection; `buffered` is what the client
+    /// already sent
+    pub async fn serve<R, W>(&self, reader: R, writer: W, buffered: Vec<u8>) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Decoy::Site => serve_site(reader, writer, buffered).await,
+            Decoy::Backend(address) => forward(address, reader, writer, buffered).await,
+        }
+    }
+}
+
+impl std::fmt::Display for Decoy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Decoy::Site => write!(f, "the built-in site"),
+            Decoy::Backend(address) => write!(f, "the web server at {}", address),
+        }
+    }
+}
+
+async fn forward<R, W>(address: &str, reader: R, writer: W, buffered: Vec<u8>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut backend = TcpStream::connect(address).await?;
+    backend.set_nodelay(true).ok();
+    backend.write_all(&buffered).await?;
+    tokio::io::copy_bidirectional(&mut tokio::io::join(reader, writer), &mut backend).await?;
+    Ok(())
+}
+
+// --- BUILT-IN SITE ---
+
+/// One request's worth of what nginx would look at
+struct Request {
+    len: usize,
+    method: String,
+    path: String,
+    body: usize,
+    keep_alive: bool,
+}
+
+enum Head {
+    Incomplete,
+    Request(Request),
+    /// Not HTTP, or not HTTP nginx would take: 400 and hang up
+    Bad,
+}
+
+async fn serve_site<R, W>(mut reader: R, mut writer: W, mut buffered: Vec<u8>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+    loop {
+        let request = match parse_head(&buffered) {
+            Head::Request(request) => request,
+            Head::Bad => {
+                writer.write_all(&response(400, "Bad Request", error_page(400, "Bad Request"), false, false)).await?;
+                return writer.shutdown().await;
+            }
+            Head::Incomplete => {
+                // A quiet client is let go without a word
+                let Ok(read) = tokio::time::timeout(IDLE, reader.read(&mut chunk)).await else { return Ok(()); };
+                match read? {
+                    0 => return Ok(()),
+                    n => buffered.extend_from_slice(&chunk[..n]),
+                }
+                continue;
+            }
+        };
+
+        if request.body > MAX_BODY {
+            let reason = "Request Entity Too Large";
+            writer.write_all(&response(413, reason, error_page(413, reason), false, request.method == "HEAD")).await?;
+            return writer.shutdown().await;
+        }
+        // Skip the body before answering, so the next request starts clean
+        while buffered.len() < request.len + request.body {
+            let Ok(read) = tokio::time::timeout(IDLE, reader.read(&mut chunk)).await else { return Ok(()); };
+            match read? {
+                0 => return Ok(()),
+                n => buffered.extend_from_slice(&chunk[..n]),
+            }
+        }
+        buffered.drain(..request.len + request.body);
+
+        let (status, reason, page) = page(&request.method, &request.path);
+        let head_only = request.method == "HEAD";
+        writer.write_all(&response(status, reason, page, request.keep_alive, head_only)).await?;
+        if !request.keep_alive {
+            return writer.shutdown().await;
+        }
+    }
+}
+
+/// The request at the front of `buf`. Like nginx, this refuses a bad
+/// request line as soon as it sees one, without waiting for the rest.
+fn parse_head(buf: &[u8]) -> Head {
+    let line_end = buf.iter().position(|&b| b == b'\n');
+    let line = &buf[..line_end.unwrap_or(buf.len())];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let method_len = line.iter().position(|&b| b == b' ').unwrap_or(line.len());
+    if !line[..method_len].iter().all(|&b| b.is_ascii_uppercase() || b == b'_' || b == b'-') {
+        return Head::Bad;
+    }
+    if line_end.is_some() && method_len == 0 { return Head::Bad; }
+
+    let end = buf.windows(4).position(|window| window == b"\r\n\r\n").map(|end| end + 4)
+        .or_else(|| buf.windows(2).position(|window| window == b"\n\n").map(|end| end + 2));
+    let Some(end) = end else {
+        return if buf.len() > MAX_HEAD { Head::Bad } else { Head::Incomplete };
+    };
+    if end > MAX_HEAD { return Head::Bad; }
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else { return Head::Bad; };
+    let mut lines = head.lines().map(|line| line.trim_end_matches('\r'));
+
+    let mut parts = lines.next().unwrap_or("").split(' ').filter(|part| !part.is_empty());
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Head::Bad;
+    };
+    let http11 = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Head::Bad,
+    };
+    // An absolute URI names the path after its authority
+    let path = match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None if target.starts_with('/') => target,
+        None => return Head::Bad,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+
+    let (mut host, mut body, mut connection) = (false, 0, None);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else { return Head::Bad; };
+        if name.is_empty() || !name.bytes().all(cloak::is_token) { return Head::Bad; }
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("host") {
+            host = true;
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let Ok(length) = value.parse() else { return Head::Bad; };
+            body = length;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // We will not walk a chunked body: answer, then hang up
+            connection = Some(false);
+        } else if name.eq_ignore_ascii_case("connection") && connection != Some(false) {
+            if value.eq_ignore_ascii_case("close") { connection = Some(false); }
+            if value.eq_ignore_ascii_case("keep-alive") { connection = Some(true); }
+        }
+    }
+    // HTTP/1.1 must name a host
+    if http11 && !host { return Head::Bad; }
+
+    Head::Request(Request {
+        len: end,
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+        keep_alive: connection.unwrap_or(http11),
+    })
+}
+
+/// The built-in site's answer to `method` on `path`: status, reason and page.
+/// HTTP/2 probes, which cannot be handed over whole, get these too.
+pub(crate) fn page(method: &str, path: &str) -> (u16, &'static str, String) {
+    match (method, path) {
+        ("GET" | "HEAD", "/" | "/index.html") => (200, "OK", WELCOME.to_string()),
+        ("GET" | "HEAD", _) => (404, "Not Found", error_page(404, "Not Found")),
+        _ => (405, "Not Allowed", error_page(405, "Not Allowed")),
+    }
+}
+
+/// The headers nginx sends with a page, in its order, less Connection
+pub(crate) fn page_headers(status: u16, len: usize) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("Server", "nginx".to_string()),
+        ("Date", cloak::http_date(SystemTime::now())),
+        ("Content-Type", "text/html".to_string()),
+        ("Content-Length", len.to_string()),
+    ];
+    // The welcome page is a static file
+    if status == 200 {
+        headers.push(("Last-Modified", cloak::http_date(UNIX_EPOCH + Duration::from_secs(INSTALLED))));
+        headers.push(("ETag", format!("\"{:x}-{:x}\"", INSTALLED, len)));
+        headers.push(("Accept-Ranges", "bytes".to_string()));
+    }
+    headers
+}
+
+/// An error, in nginx's words
+fn error_page(status: u16, reason: &str) -> String {
+    format!(
+        "<html>\r\n<head><title>{status} {reason}</title></head>\r\n<body>\r\n\
+         <center><h1>{status} {reason}</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n"
+    )
+}
+
+fn response(status: u16, reason: &str, page: String, keep_alive: bool, head_only: bool) -> Vec<u8> {
+    let connection = format!("Connection: {}\r\n", if keep_alive { "keep-alive" } else { "close" });
+    let mut message = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    // nginx puts Connection ahead of the ETag, or last
+    let mut connection = Some(connection);
+    for (name, value) in page_headers(status, page.len()) {
+        if name == "ETag" { message.extend(connection.take()); }
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.extend(connection);
+    message.push_str("\r\n");
+    let mut message = message.into_bytes();
+    if !head_only { message.extend_from_slice(page.as_bytes()); }
+    message
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use crate::egress::{self, Address};
+use crate::mux::{Mux, MuxStream};
+
+// --- DNS OVER THE TUNNEL ---
+// Each query rides its own mux stream, DoH-style: the OPEN payload is
+// [CMD_DNS][unspecified address], followed straight away by the query as
+// [len u16][message]. The relay answers [status][len u16][message] after
+// asking its own resolver, so lookups never leave from the client's network.
+
+/// The tunnel's virtual resolver: point the system's DNS here in VPN mode
+pub const RESOLVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 53);
+pub const DNS_PORT: u16 = 53;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+const UPSTREAM_ATTEMPTS: usize = 2;
+// Used by the relay when /etc/resolv.conf has nothing usable
+const FALLBACK_UPSTREAM: &str = "1.1.1.1:53";
+
+const MAX_CACHE_ENTRIES: usize = 4096;
+// Answers without records (NXDOMAIN, NODATA) are remembered briefly
+const NEGATIVE_TTL: u32 = 30;
+const MAX_TTL: u32 = 3600;
+
+pub(crate) const HEADER_SIZE: usize = 12;
+pub(crate) const FLAG_TRUNCATED: u16 = 0x0200;
+pub(crate) const RCODE_MASK: u16 = 0x000F;
+pub(crate) const RCODE_NXDOMAIN: u16 = 3;
+
+/// Client side: answers queries from its cache or over the session
+pub struct DnsResolver {
+    mux: Mux,
+    // Keyed by question (see `question_key`)
+    cache: Mutex<HashMap<Vec<u8>, CachedAnswer>>,
+}
+
+struct CachedAnswer {
+    response: Vec<u8>,
+    expires: Instant,
+}
+
+impl DnsResolver {
+    pub fn new(mux: Mux) -> Self {
+        Self { mux, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Resolve one wire-format query; the answer carries the query's ID
+    pub async fn resolve(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let key = question_key(query)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed DNS query"))?;
+
+        let cached = self.cache.lock().unwrap().get(&key)
+            .filter(|answer| answer.expires > Instant::now())
+            .map(|answer| answer.response.clone());
+        let mut response = match cached {
+            Some(response) => response,
+            None => {
+                let response = tokio::time::timeout(QUERY_TIMEOUT, self.ask_relay(query)).await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                self.remember(key, &response);
+                response
+            }
+        };
+
+        response[..2].copy_from_slice(&query[..2]);
+        Ok(response)
+    }
+
+    async fn ask_relay(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut request = vec![egress::CMD_DNS];
+        request.extend(Address::unspecified().to_bytes());
+        let mut stream = self.mux.open(&request)?;
+
+        // Send the query without waiting for the status: one round trip per lookup
+        write_message(&mut stream, query).await?;
+        match stream.read_u8().await? {
+            egress::REPLY_SUCCEEDED => read_message(&mut stream).await,
+            code => Err(egress::status_error(code)),
+        }
+    }
+
+    fn remember(&self, key: Vec<u8>, response: &[u8]) {
+        let Some(ttl) = cache_ttl(response) else { return; };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            let now = Instant::now();
+            cache.retain(|_, answer| answer.expires > now);
+            // Still full of live entries: start over rather than grow without bound
+            if cache.len() >= MAX_CACHE_ENTRIES { cache.clear(); }
+        }
+        let expires = Instant::now() + Duration::from_secs(ttl.into());
+        cache.insert(key, CachedAnswer { response: response.to_vec(), expires });
+    }
+}
+
+/// A plain UDP resolver on `listen` for apps that cannot use the tunnel's
+/// virtual address (SOCKS mode). Every query goes through `resolver`.
+pub async fn serve_local(listen: &str, resolver: Arc<DnsResolver>) -> io::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(listen).await?);
+    println!("[DNS] Resolving over the tunnel on {}", listen);
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, client) = socket.recv_from(&mut buf).await?;
+        let query = buf[..n].to_vec();
+        let (socket, resolver) = (socket.clone(), resolver.clone());
+        tokio::spawn(async move {
+            match resolver.resolve(&query).await {
+                Ok(response) => { socket.send_to(&response, client).await.ok(); }
+                Err(e) => println!("[DNS] {} -> {}", client, e),
+            }
+        });
+    }
+}
+
+// --- RELAY SIDE ---
+
+/// Answer one CMD_DNS stream with the relay's own resolver
+pub async fn serve_query(mut stream: MuxStream) -> io::Result<()> {
+    let query = read_message(&mut stream).await?;
+    let response = match forward(&query).await {
+        Ok(response) => response,
+        Err(e) => {
+            stream.write_all(&[egress::REPLY_HOST_UNREACHABLE]).await.ok();
+            return Err(e);
+        }
+    };
+    stream.write_all(&[egress::REPLY_SUCCEEDED]).await?;
+    write_message(&mut stream, &response).await?;
+    stream.shutdown().await
+}
+
+async fn forward(query: &[u8]) -> io::Result<Vec<u8>> {
+    let upstream = upstream();
+    let bind: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(upstream).await?;
+
+    let mut buf = vec![0u8; 65535];
+    for _ in 0..UPSTREAM_ATTEMPTS {
+        socket.send(query).await?;
+        let Ok(received) = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await else { continue; };
+        let n = received?;
+        if n < HEADER_SIZE || buf[..2] != query[..2] { continue; }
+        // Too big for UDP: ask again over TCP
+        if flags(&buf[..n]) & FLAG_TRUNCATED != 0 {
+            return forward_tcp(upstream, query).await;
+        }
+        return Ok(buf[..n].to_vec());
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", upstream)))
+}
+
+async fn forward_tcp(upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = tokio::time::timeout(UPSTREAM_TIMEOUT, TcpStream::connect(upstream)).await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    write_message(&mut stream, query).await?;
+    tokio::time::timeout(UPSTREAM_TIMEOUT, read_message(&mut stream)).await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// First nameserver in /etc/resolv.conf
+fn upstream() -> SocketAddr {
+    std::fs::read_to_string("/etc/resolv.conf").ok()
+        .and_then(|conf| conf.lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|server| server.trim().parse().ok()))
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .unwrap_or_else(|| FALLBACK_UPSTREAM.parse().unwrap())
+}
+
+// --- WIRE FORMAT (RFC 1035) ---
+
+/// DNS over a stream: [len u16][message]
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS message too long"))?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    writer.write_all(&framed).await
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    if len < HEADER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "short DNS message"));
+    }
+    let mut message = vec![0u8; len];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+pub(crate) fn flags(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[2], message[3]])
+}
+
+pub(crate) fn count(message: &[u8], index: usize) -> u16 {
+    let at = 4 + index * 2;
+    u16::from_be_bytes([message[at], message[at + 1]])
+}
+
+/// Cache key: the single question, with the name lowercased
+fn question_key(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < HEADER_SIZE || count(message, 0) != 1 { return None; }
+    let end = skip_name(message, HEADER_SIZE)? + 4;
+    if end > message.len() { return None; }
+    Some(message[HEADER_SIZE..end].to_ascii_lowercase())
+}
+
+/// How long an answer may be reused, or None if it must not be cached
+fn cache_ttl(message: &[u8]) -> Option<u32> {
+    if message.len() < HEADER_SIZE || flags(message) & FLAG_TRUNCATED != 0 { return None; }
+    let rcode = flags(message) & RCODE_MASK;
+    if rcode != 0 && rcode != RCODE_NXDOMAIN { return None; }
+
+    let mut pos = skip_name(message, HEADER_SIZE)? + 4;
+    let mut ttl: Option<u32> = None;
+    for _ in 0..count(message, 1) {
+        pos = skip_name(message, pos)?;
+        let record = message.get(pos..pos + 10)?;
+        let record_ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let rdlen = u16::from_be_bytes([record[8], record[9]]) as usize;
+        // A record cut short is a broken answer, not one to keep
+        message.get(pos + 10..pos + 10 + rdlen)?;
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        pos += 10 + rdlen;
+    }
+    Some(ttl.unwrap_or(NEGATIVE_TTL).min(MAX_TTL))
+}
+
+/// Step over a (possibly compressed) name, returning the offset after it
+pub(crate) fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer ends the name in two bytes
+            l if l & 0xC0 == 0xC0 => return message.get(pos + 1).map(|_| pos + 2),
+            // 0x40 and 0x80 label types are reserved
+            l if l & 0xC0 != 0 => return None,
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // example.com, A, IN
+    const QUESTION: &[u8] = b"\x07example\x03com\x00\x00\x01\x00\x01";
+
+    /// A response with `flags` and one A record per TTL, each named by a
+    /// pointer back to the question
+    fn response(flags: u16, ttls: &[u32]) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34];
+        message.extend(flags.to_be_bytes());
+        message.extend([0, 1, 0, ttls.len() as u8, 0, 0, 0, 0]);
+        message.extend_from_slice(QUESTION);
+        for ttl in ttls {
+            message.extend([0xC0, HEADER_SIZE as u8, 0, 1, 0, 1]);
+            message.extend(ttl.to_be_bytes());
+            message.extend([0, 4, 192, 0, 2, 1]);
+        }
+        message
+    }
+
+    #[test]
+    fn skip_name_steps_over_labels_and_pointers() {
+        assert_eq!(skip_name(QUESTION, 0), Some(13));
+        assert_eq!(skip_name(b"\xc0\x0c", 0), Some(2));
+        assert_eq!(skip_name(b"\x03www\xc0\x0c", 0), Some(6));
+        assert_eq!(skip_name(b"\x00", 0), Some(1));
+    }
+
+    #[test]
+    fn skip_name_refuses_names_cut_short_or_malformed() {
+        assert_eq!(skip_name(b"", 0), None);
+        assert_eq!(skip_name(b"\x07exam", 0), None);
+        assert_eq!(skip_name(b"\x07example", 0), None);
+        assert_eq!(skip_name(b"\x03www\xc0", 0), None);
+        // Reserved label types
+        assert_eq!(skip_name(b"\x40abc\x00", 0), None);
+        assert_eq!(skip_name(b"\x80abc\x00", 0), None);
+        assert_eq!(skip_name(QUESTION, QUESTION.len() + 10), None);
+    }
+
+    #[test]
+    fn cache_ttl_takes_the_shortest_record() {
+        assert_eq!(cache_ttl(&response(0x8180, &[300, 60, 900])), Some(60));
+        assert_eq!(cache_ttl(&response(0x8180, &[u32::MAX])), Some(MAX_TTL));
+        // No records (NODATA, NXDOMAIN): remembered briefly
+        assert_eq!(cache_ttl(&response(0x8180, &[])), Some(NEGATIVE_TTL));
+        assert_eq!(cache_ttl(&response(0x8180 | RCODE_NXDOMAIN, &[])), Some(NEGATIVE_TTL));
+    }
+
+    #[test]
+    fn cache_ttl_refuses_answers_not_worth_keeping() {
+        // Truncated, or a failure (SERVFAIL, REFUSED)
+        assert_eq!(cache_ttl(&response(0x8180 | FLAG_TRUNCATED, &[300])), None);
+        assert_eq!(cache_ttl(&response(0x8182, &[300])), None);
+        assert_eq!(cache_ttl(&response(0x8185, &[300])), None);
+        // Cut anywhere short of its last record's end
+        let whole = response(0x8180, &[300, 60]);
+        for cut in 0..whole.len() {
+            assert_eq!(cache_ttl(&whole[..cut]), None, "cut at {}", cut);
+        }
+        // More records claimed than are there
+        let mut overclaimed = response(0x8180, &[300]);
+        overclaimed[7] = 200;
+        assert_eq!(cache_ttl(&overclaimed), None);
+    }
+
+    #[test]
+    fn question_key_ignores_case_and_wants_one_question() {
+        let mut query = response(0x0100, &[]);
+        let lower = question_key(&query).unwrap();
+        query[HEADER_SIZE + 1] = b'E';
+        assert_eq!(question_key(&query).unwrap(), lower);
+        query[5] = 2;
+        assert_eq!(question_key(&query), None);
+        assert_eq!(question_key(&response(0x0100, &[])[..HEADER_SIZE + 5]), None);
+    }
+}
@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose};
+use data_encoding::BASE32_DNSSEC;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use crate::cloak::Halves;
+use crate::{auth, dns};
+
+// --- DNS TUNNEL CARRIER ---
+// For networks where only DNS gets out. The client asks the relay's
+// authoritative server (directly, or through any recursive resolver) about
+// names under the relay's domain: each query name carries upstream bytes in
+// base32 labels, each NULL (raw) or TXT (base64) answer carries downstream
+// bytes. The payload of a name is [session u32][seq u16][kind u8][data];
+// an answer is [status u8][data]. One query is in flight at a time: the
+// relay remembers its last answer, so a retried query (lost, or asked
+// again by a resolver) gets the same answer and nothing is delivered twice.
+// The client polls when it has nothing to send, backing off while the
+// relay has nothing either. Like a WebSocket, the tunnel carries bare
+// frames (see `cloak::framed`) and is disguise enough on its own.
+//
+// PROBE and OPEN carry proof of the relay's secret for their session (the
+// same proof passes twice: resolvers retry), and a probe's answer is never
+// more than a few times the size of its query, so the relay is no use as
+// an amplifier to anyone without the secret, and little use to anyone with it.
+
+const KIND_PROBE: u8 = 0;
+const KIND_OPEN: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_CLOSE: u8 = 3;
+const NAME_HEADER: usize = 7;
+
+// Answer status bits
+const MORE: u8 = 0x01;
+const CLOSED: u8 = 0x02;
+
+const TYPE_NULL: u16 = 10;
+const TYPE_TXT: u16 = 16;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const RCODE_REFUSED: u16 = 5;
+const EDNS_PAYLOAD: u16 = 4096;
+const MAX_NAME: usize = 253;
+const MAX_LABEL: usize = 63;
+
+/// One whole frame on the pipe: answers are sized in frames, so a frame of
+/// FEC symbols never waits on a second round trip if it can help it
+const FRAME: usize = 4 + 64 + crate::SYMBOL_SIZE as usize;
+/// Downstream sizes to try, largest first; the first that survives the path wins
+const DOWNSTREAM_SIZES: [usize; 4] = [2 * FRAME, FRAME, FRAME / 2, FRAME / 4];
+const MAX_PROBE: usize = DOWNSTREAM_SIZES[0];
+// Most a probe's answer may outweigh its query
+const PROBE_GAIN: usize = 5;
+// Sessions one source (a client, or a resolver on behalf of many) may hold, and all of them together
+const MAX_SESSIONS_PER_SOURCE: usize = 16;
+const MAX_SESSIONS: usize = 256;
+
+const RETRY_AFTER: Duration = Duration::from_millis(800);
+const PROBE_ATTEMPTS: usize = 2;
+const GIVE_UP: Duration = Duration::from_secs(10);
+const MIN_POLL: Duration = Duration::from_millis(20);
+const MAX_POLL: Duration = Duration::from_secs(1);
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+const PIPE_BUFFER: usize = 256 * 1024;
+
+/// What the client and the relay agreed on
+#[derive(Clone)]
+struct Tunnel {
+    domain: String,
+    session: u32,
+    qtype: u16,
+    downstream: usize,
+}
+
+impl Tunnel {
+    /// Most payload bytes a query name under `domain` can hold
+    fn upstream(&self) -> usize {
+        let room = MAX_NAME.saturating_sub(self.domain.len() + 1);
+        // Labels of up to 63 characters, a dot between each
+        let chars = room - room / (MAX_LABEL + 1);
+        (chars * 5 / 8).saturating_sub(NAME_HEADER)
+    }
+
+    fn name(&self, seq: u16, kind: u8, data: &[u8]) -> String {
+        let mut payload = self.session.to_be_bytes().to_vec();
+        payload.extend(seq.to_be_bytes());
+        payload.push(kind);
+        payload.extend_from_slice(data);
+        self.encode(&payload)
+    }
+
+    /// A PROBE or OPEN name: the size it asks for and proof of `secret`,
+    /// padded out to a whole query (a probe's answer is sized by its query)
+    fn proven(&self, seq: u16, kind: u8, size: usize, secret: &auth::Secret) -> String {
+        let mut data = (size as u16).to_be_bytes().to_vec();
+        data.extend(secret.prove(auth::DNS, &proof_subject(self.session, kind)));
+        let fill = self.upstream().saturating_sub(data.len());
+        data.extend((0..fill).map(|_| rand::rng().random::<u8>()));
+        self.name(seq, kind, &data)
+    }
+
+    fn encode(&self, payload: &[u8]) -> String {
+        let encoded = BASE32_DNSSEC.encode(payload);
+        let labels: Vec<&str> = encoded.as_bytes().chunks(MAX_LABEL).map(|label| std::str::from_utf8(label).unwrap()).collect();
+        format!("{}.{}", labels.join("."), self.domain)
+    }
+}
+
+/// What a PROBE's or OPEN's proof is for
+fn proof_subject(session: u32, kind: u8) -> [u8; 5] {
+    let mut subject = [kind; 5];
+    subject[..4].copy_from_slice(&session.to_be_bytes());
+    subject
+}
+
+// --- CLIENT SIDE ---
+
+/// Tunnel through `resolver` (HOST:PORT) to the relay serving `domain`:
+/// find the largest answers that get through, open a session and start
+/// the poll pump. Probing and opening prove we know the relay's `secret`.
+pub async fn connect(resolver: &str, domain: &str, secret: &auth::Secret) -> io::Result<Halves> {
+    let resolver: SocketAddr = tokio::net::lookup_host(resolver).await?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cannot resolve the resolver"))?;
+    let bind = if resolver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(resolver).await?;
+
+    let mut tunnel = Tunnel { domain: domain.trim_end_matches('.').to_string(), session: rand::rng().random(), qtype: TYPE_NULL, downstream: 0 };
+    'found: for qtype in [TYPE_NULL, TYPE_TXT] {
+        tunnel.qtype = qtype;
+        for size in DOWNSTREAM_SIZES {
+            let probe = tunnel.proven(rand::rng().random(), KIND_PROBE, size, secret);
+            for _ in 0..PROBE_ATTEMPTS {
+                if let Ok(Ok((_, data))) = tokio::time::timeout(RETRY_AFTER, ask(&socket, &probe, qtype)).await
+                    && data.len() == size {
+                    tunnel.downstream = size;
+                    break 'found;
+                }
+            }
+        }
+    }
+    if tunnel.downstream == 0 {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no tunnel answers for {} via {}", domain, resolver)));
+    }
+
+    let open = tunnel.proven(0, KIND_OPEN, tunnel.downstream, secret);
+    exchange_name(&socket, &tunnel, &open).await?;
+    println!("[DNS TUNNEL] Via {}: {} answers of {} bytes, {} bytes per query.",
+        resolver, if tunnel.qtype == TYPE_NULL { "NULL" } else { "TXT" }, tunnel.downstream, tunnel.upstream());
+
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    tokio::spawn(poll_pump(socket, tunnel, ours));
+    Ok(tokio::io::split(theirs))
+}
+
+/// Ship what the stream writes, a query at a time, and feed it the answers
+async fn poll_pump(socket: UdpSocket, tunnel: Tunnel, pipe: DuplexStream) {
+    let (mut pipe_reader, mut pipe_writer) = tokio::io::split(pipe);
+    let mut chunk = vec![0u8; tunnel.upstream()];
+    let mut seq: u16 = 1;
+    let mut delay = Duration::ZERO;
+    loop {
+        let n = match tokio::time::timeout(delay, pipe_reader.read(&mut chunk)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => {
+                // The stream let go of its carrier: tell the relay, once
+                exchange(&socket, &tunnel, seq, KIND_CLOSE, &[]).await.ok();
+                break;
+            }
+            Ok(Ok(n)) => n,
+            // Nothing to send: poll
+            Err(_) => 0,
+        };
+        let (status, data) = match exchange(&socket, &tunnel, seq, KIND_DATA, &chunk[..n]).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                println!("[DNS TUNNEL] {}", e);
+                break;
+            }
+        };
+        seq = seq.wrapping_add(1);
+        if !data.is_empty() && pipe_writer.write_all(&data).await.is_err() { break; }
+        if status & CLOSED != 0 { break; }
+
+        let busy = n > 0 || !data.is_empty() || status & MORE != 0;
+        delay = if busy { Duration::ZERO } else { (delay * 2).clamp(MIN_POLL, MAX_POLL) };
+    }
+    pipe_writer.shutdown().await.ok();
+}
+
+/// One query, retried until it is answered or the relay is given up on
+async fn exchange(socket: &UdpSocket, tunnel: &Tunnel, seq: u16, kind: u8, data: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    exchange_name(socket, tunnel, &tunnel.name(seq, kind, data)).await
+}
+
+async fn exchange_name(socket: &UdpSocket, tunnel: &Tunnel, name: &str) -> io::Result<(u8, Vec<u8>)> {
+    let deadline = Instant::now() + GIVE_UP;
+    loop {
+        match tokio::time::timeout(RETRY_AFTER, ask(socket, name, tunnel.qtype)).await {
+            Ok(Ok(answer)) => return Ok(answer),
+            // The relay forgot the session: asking again cannot help
+            Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+            Ok(Err(e)) if Instant::now() >= deadline => return Err(e),
+            Err(_) if Instant::now() >= deadline => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "relay stopped answering"));
+            }
+            // Refused on the way (ICMP) or failed by a resolver: pause, then ask again
+            Ok(Err(_)) => tokio::time::sleep(MIN_POLL).await,
+            // Lost: ask again
+            Err(_) => {}
+        }
+    }
+}
+
+/// Send one query for `name` and wait for its answer, skipping stale ones
+async fn ask(socket: &UdpSocket, name: &str, qtype: u16) -> io::Result<(u8, Vec<u8>)> {
+    let id: u16 = rand::rng().random();
+    socket.send(&query(id, name, qtype)).await?;
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        if let Some(answer) = answer(&buf[..n], id, name, qtype)? { return Ok(answer); }
+    }
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut message = id.to_be_bytes().to_vec();
+    // Recursion desired; one question, one OPT record for big answers
+    message.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    message.extend(encode_name(name));
+    message.extend(qtype.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+    message.extend(opt_record());
+    message
+}
+
+/// The payload of the answer to query `id`, None if this is some other message
+fn answer(message: &[u8], id: u16, name: &str, qtype: u16) -> io::Result<Option<(u8, Vec<u8>)>> {
+    if message.len() < dns::HEADER_SIZE || message[..2] != id.to_be_bytes() || dns::flags(message) & 0x8000 == 0 {
+        return Ok(None);
+    }
+    let Some((question, mut pos)) = read_name(message, dns::HEADER_SIZE) else { return Ok(None); };
+    if !question.eq_ignore_ascii_case(name) { return Ok(None); }
+
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "mangled tunnel answer");
+    match dns::flags(message) & dns::RCODE_MASK {
+        0 => {}
+        dns::RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "relay has no such tunnel session")),
+        rcode => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("DNS error {}", rcode))),
+    }
+    if dns::flags(message) & dns::FLAG_TRUNCATED != 0 || dns::count(message, 1) == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "tunnel answer did not fit"));
+    }
+
+    pos += 4;
+    pos = dns::skip_name(message, pos).ok_or_else(bad)?;
+    let record = message.get(pos..pos + 10).ok_or_else(bad)?;
+    let rtype = u16::from_be_bytes([record[0], record[1]]);
+    let rdlen = u16::from_be_bytes([record[8], record[9]]) as usize;
+    let rdata = message.get(pos + 10..pos + 10 + rdlen).ok_or_else(bad)?;
+    if rtype != qtype { return Err(bad()); }
+
+    let payload = match qtype {
+        TYPE_TXT => {
+            let mut text = Vec::new();
+            let mut rest = rdata;
+            while let Some((&len, tail)) = rest.split_first() {
+                let part = tail.get(..len as usize).ok_or_else(bad)?;
+                text.extend_from_slice(part);
+                rest = &tail[len as usize..];
+            }
+            general_purpose::STANDARD.decode(text).map_err(|_| bad())?
+        }
+        _ => rdata.to_vec(),
+    };
+    let (&status, data) = payload.split_first().ok_or_else(bad)?;
+    Ok(Some((status, data.to_vec())))
+}
+
+// --- RELAY SIDE ---
+
+/// A tunnel session: the relay's end of its pipe, and the answer to repeat
+/// if the last query comes again
+struct Session {
+    next: u16,
+    last: Vec<u8>,
+    downstream: usize,
+    uplink: mpsc::Sender<Vec<u8>>,
+    downlink: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    seen: Instant,
+    // Who opened it: the client, or the resolver asking for it
+    source: IpAddr,
+}
+
+/// Answer for `domain` on `listen` (UDP). Every tunnel session that opens
+/// (with proof of `secret`) comes out of the returned channel as a carrier.
+pub async fn serve(listen: &str, domain: &str, secret: auth::Secret) -> io::Result<mpsc::Receiver<Halves>> {
+    let socket = UdpSocket::bind(listen).await?;
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    println!("[DNS TUNNEL] Authoritative for {} on {}", domain, listen);
+    let (carriers_tx, carriers) = mpsc::channel(16);
+    tokio::spawn(respond(socket, domain, secret, carriers_tx));
+    Ok(carriers)
+}
+
+async fn respond(socket: UdpSocket, domain: String, secret: auth::Secret, carriers: mpsc::Sender<Halves>) {
+    let mut sessions: HashMap<u32, Session> = HashMap::new();
+    let mut sweep = tokio::time::interval(SESSION_IDLE / 4);
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, peer) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(_) => continue,
+            },
+            _ = sweep.tick() => {
+                // Dropping a session closes its pipe: the stream sees its carrier go
+                sessions.retain(|_, session| session.seen.elapsed() < SESSION_IDLE);
+                continue;
+            }
+        };
+        if let Some(response) = handle(&buf[..n], peer.ip(), &domain, &secret, &mut sessions, &carriers) {
+            socket.send_to(&response, peer).await.ok();
+        }
+    }
+}
+
+/// The response to one query from `source`, or None to stay silent (the client retries)
+fn handle(message: &[u8], source: IpAddr, domain: &str, secret: &auth::Secret, sessions: &mut HashMap<u32, Session>, carriers: &mpsc::Sender<Halves>) -> Option<Vec<u8>> {
+    if message.len() < dns::HEADER_SIZE || dns::flags(message) & 0x8000 != 0 || dns::count(message, 0) != 1 { return None; }
+    let (name, pos) = read_name(message, dns::HEADER_SIZE)?;
+    let question = message.get(dns::HEADER_SIZE..pos + 4)?;
+    let qtype = u16::from_be_bytes([message[pos], message[pos + 1]]);
+    let reply = |rcode: u16, rdata: Option<Vec<u8>>| response(message, question, qtype, rcode, rdata);
+
+    let lowered = name.to_ascii_lowercase();
+    let Some(labels) = lowered.strip_suffix(domain).filter(|rest| rest.is_empty() || rest.ends_with('.')) else {
+        return Some(reply(RCODE_REFUSED, None));
+    };
+    // Anything but our own names gets what any zone's server would say
+    let payload = BASE32_DNSSEC.decode(labels.replace('.', "").as_bytes()).ok().filter(|payload| payload.len() >= NAME_HEADER);
+    let (Some(payload), TYPE_NULL | TYPE_TXT) = (payload, qtype) else {
+        return Some(reply(dns::RCODE_NXDOMAIN, None));
+    };
+    let session_id = u32::from_be_bytes(payload[..4].try_into().unwrap());
+    let seq = u16::from_be_bytes([payload[4], payload[5]]);
+    let (kind, data) = (payload[6], &payload[NAME_HEADER..]);
+
+    // Probing and opening take proof of the secret: without it, these are names we do not have
+    if matches!(kind, KIND_PROBE | KIND_OPEN) {
+        let proof = data.get(2..2 + auth::PROOF_SIZE).unwrap_or_default();
+        if !secret.verify(proof, auth::DNS, &proof_subject(session_id, kind)) {
+            return Some(reply(dns::RCODE_NXDOMAIN, None));
+        }
+    }
+
+    let answer = match kind {
+        KIND_PROBE => {
+            let size = data.get(..2).map(|size| u16::from_be_bytes([size[0], size[1]]) as usize)?
+                .min(MAX_PROBE)
+                .min(message.len() * PROBE_GAIN);
+            let mut answer = vec![0u8];
+            answer.extend((0..size).map(|_| rand::rng().random::<u8>()));
+            answer
+        }
+        KIND_OPEN => {
+            // The OPEN again (retried, or replayed): the same answer while it is
+            // the latest query, silence after; never a second session on one id
+            if let Some(session) = sessions.get(&session_id) {
+                if seq != session.next.wrapping_sub(1) { return None; }
+                return Some(reply(0, Some(encode_answer(&session.last, qtype))));
+            }
+            let from_source = sessions.values().filter(|session| session.source == source).count();
+            if sessions.len() >= MAX_SESSIONS || from_source >= MAX_SESSIONS_PER_SOURCE {
+                println!("[DNS TUNNEL] Too many sessions ({} from {}). Refusing another.", sessions.len(), source);
+                return Some(reply(RCODE_REFUSED, None));
+            }
+            let downstream = data.get(..2).map(|size| u16::from_be_bytes([size[0], size[1]]) as usize)?.min(MAX_PROBE);
+            let (mut session, halves) = open(downstream, source);
+            if carriers.try_send(halves).is_err() { return Some(reply(RCODE_REFUSED, None)); }
+            session.next = seq.wrapping_add(1);
+            session.last = vec![0u8];
+            sessions.insert(session_id, session);
+            vec![0u8]
+        }
+        KIND_DATA | KIND_CLOSE => {
+            let Some(session) = sessions.get_mut(&session_id) else {
+                return Some(reply(dns::RCODE_NXDOMAIN, None));
+            };
+            session.seen = Instant::now();
+            if seq == session.next.wrapping_sub(1) {
+                return Some(reply(0, Some(encode_answer(&session.last, qtype))));
+            }
+            // A query from before the last one: its client has moved on
+            if seq != session.next { return None; }
+            if kind == KIND_CLOSE {
+                sessions.remove(&session_id);
+                vec![CLOSED]
+            } else {
+                // Full uplink: leave the query unanswered and let the retry try again
+                if !data.is_empty() && session.uplink.try_send(data.to_vec()).is_err() { return None; }
+                session.next = seq.wrapping_add(1);
+                session.last = session.fill();
+                session.last.clone()
+            }
+        }
+        _ => return Some(reply(dns::RCODE_NXDOMAIN, None)),
+    };
+    Some(reply(0, Some(encode_answer(&answer, qtype))))
+}
+
+/// A new session for `source` and the carrier it feeds
+fn open(downstream: usize, source: IpAddr) -> (Session, Halves) {
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (uplink, uplink_rx) = mpsc::channel(64);
+    let (downlink_tx, downlink) = mpsc::channel(64);
+    tokio::spawn(uplink_pump(uplink_rx, pipe_writer));
+    tokio::spawn(downlink_pump(pipe_reader, downlink_tx));
+    let session = Session { next: 0, last: Vec::new(), downstream, uplink, downlink, pending: Vec::new(), seen: Instant::now(), source };
+    (session, tokio::io::split(theirs))
+}
+
+impl Session {
+    /// The next answer: as much of what the stream wrote as fits
+    fn fill(&mut self) -> Vec<u8> {
+        let mut closed = false;
+        while self.pending.len() < self.downstream {
+            match self.downlink.try_recv() {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        let take = self.pending.len().min(self.downstream);
+        let data: Vec<u8> = self.pending.drain(..take).collect();
+        let mut status = if self.pending.is_empty() { 0 } else { MORE };
+        if closed && self.pending.is_empty() { status |= CLOSED; }
+        let mut answer = vec![status];
+        answer.extend(data);
+        answer
+    }
+}
+
+async fn uplink_pump(mut uplink: mpsc::Receiver<Vec<u8>>, mut pipe: WriteHalf<DuplexStream>) {
+    while let Some(data) = uplink.recv().await {
+        if pipe.write_all(&data).await.is_err() { break; }
+    }
+    pipe.shutdown().await.ok();
+}
+
+async fn downlink_pump(mut pipe: ReadHalf<DuplexStream>, downlink: mpsc::Sender<Vec<u8>>) {
+    let mut chunk = vec![0u8; 4 * FRAME];
+    while let Ok(n) = pipe.read(&mut chunk).await {
+        if n == 0 || downlink.send(chunk[..n].to_vec()).await.is_err() { break; }
+    }
+}
+
+// --- WIRE FORMAT ---
+
+fn response(query: &[u8], question: &[u8], qtype: u16, rcode: u16, rdata: Option<Vec<u8>>) -> Vec<u8> {
+    let edns = dns::count(query, 2) > 0;
+    let mut message = query[..2].to_vec();
+    // Response, authoritative for our zone, recursion desired copied from the query
+    let authoritative = if rcode == RCODE_REFUSED { 0 } else { 0x0400 };
+    let flags = 0x8000 | authoritative | (dns::flags(query) & 0x0100) | rcode;
+    message.extend(flags.to_be_bytes());
+    message.extend([0, 1, 0, rdata.is_some() as u8, 0, 0, 0, edns as u8]);
+    message.extend_from_slice(question);
+    if let Some(rdata) = rdata {
+        // The name points back at the question; never cached
+        message.extend([0xC0, dns::HEADER_SIZE as u8]);
+        message.extend(qtype.to_be_bytes());
+        message.extend(CLASS_IN.to_be_bytes());
+        message.extend(0u32.to_be_bytes());
+        message.extend((rdata.len() as u16).to_be_bytes());
+        message.extend(rdata);
+    }
+    if edns { message.extend(opt_record()); }
+    message
+}
+
+fn encode_answer(payload: &[u8], qtype: u16) -> Vec<u8> {
+    if qtype != TYPE_TXT { return payload.to_vec(); }
+    let text = general_purpose::STANDARD.encode(payload);
+    let mut rdata = Vec::new();
+    for part in text.as_bytes().chunks(255) {
+        rdata.push(part.len() as u8);
+        rdata.extend_from_slice(part);
+    }
+    rdata
+}
+
+fn opt_record() -> Vec<u8> {
+    let mut record = vec![0];
+    record.extend(TYPE_OPT.to_be_bytes());
+    record.extend(EDNS_PAYLOAD.to_be_bytes());
+    record.extend([0, 0, 0, 0, 0, 0]);
+    record
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// An uncompressed name as text, and the offset after it
+fn read_name(message: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let len = *message.get(pos)? as usize;
+        pos += 1;
+        if len == 0 { break; }
+        if len > MAX_LABEL { return None; }
+        labels.push(std::str::from_utf8(message.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+    Some((labels.join("."), pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN: &str = "t.example.com";
+
+    fn secret() -> auth::Secret {
+        auth::Secret::new("correct-horse-battery-staple").unwrap()
+    }
+
+    fn tunnel() -> Tunnel {
+        Tunnel { domain: DOMAIN.to_string(), session: 7, qtype: TYPE_NULL, downstream: FRAME }
+    }
+
+    /// Run one query through the relay's handler with fresh state
+    fn ask_relay(message: &[u8]) -> Option<Vec<u8>> {
+        let (carriers, _) = mpsc::channel(1);
+        handle(message, IpAddr::from([192, 0, 2, 1]), DOMAIN, &secret(), &mut HashMap::new(), &carriers)
+    }
+
+    fn rcode(response: &[u8]) -> u16 {
+        dns::flags(response) & dns::RCODE_MASK
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let name = tunnel().name(3, KIND_DATA, &[0xAB; 40]);
+        assert!(name.len() <= MAX_NAME);
+        let encoded = encode_name(&name);
+        assert_eq!(read_name(&encoded, 0), Some((name, encoded.len())));
+    }
+
+    #[test]
+    fn read_name_refuses_what_it_cannot_follow() {
+        // Cut short, in a label and before the root
+        assert_eq!(read_name(b"\x03ab", 0), None);
+        assert_eq!(read_name(b"\x03abc", 0), None);
+        // Too long for a label, and compression pointers (which the tunnel never sends)
+        let mut long = vec![64];
+        long.extend([b'a'; 64]);
+        long.push(0);
+        assert_eq!(read_name(&long, 0), None);
+        assert_eq!(read_name(b"\xc0\x0c", 0), None);
+        assert_eq!(read_name(b"\x02\xff\xfe\x00", 0), None);
+    }
+
+    #[test]
+    fn upstream_fills_a_name_without_overflowing_it() {
+        let tunnel = tunnel();
+        let name = tunnel.name(0, KIND_DATA, &vec![0xFF; tunnel.upstream()]);
+        assert!(name.len() <= MAX_NAME, "{} characters", name.len());
+        assert!(name.split('.').all(|label| label.len() <= MAX_LABEL));
+    }
+
+    #[test]
+    fn answers_round_trip() {
+        for qtype in [TYPE_NULL, TYPE_TXT] {
+            let name = tunnel().name(1, KIND_DATA, b"up");
+            let query = query(42, &name, qtype);
+            let pos = read_name(&query, dns::HEADER_SIZE).unwrap().1;
+            let payload: Vec<u8> = (0..600).map(|i| i as u8).collect();
+            let reply = response(&query, &query[dns::HEADER_SIZE..pos + 4], qtype, 0, Some(encode_answer(&payload, qtype)));
+            let (status, data) = answer(&reply, 42, &name, qtype).unwrap().unwrap();
+            assert_eq!(status, payload[0]);
+            assert_eq!(data, payload[1..]);
+        }
+    }
+
+    #[test]
+    fn answer_skips_other_messages_and_refuses_mangled_ones() {
+        let name = tunnel().name(1, KIND_DATA, b"up");
+        let query = query(42, &name, TYPE_NULL);
+        let pos = read_name(&query, dns::HEADER_SIZE).unwrap().1;
+        let reply = response(&query, &query[dns::HEADER_SIZE..pos + 4], TYPE_NULL, 0, Some(vec![0, 1, 2]));
+
+        // Another id, a query rather than a response, or too short to be either
+        assert!(answer(&reply, 43, &name, TYPE_NULL).unwrap().is_none());
+        assert!(answer(&query, 42, &name, TYPE_NULL).unwrap().is_none());
+        assert!(answer(&reply[..5], 42, &name, TYPE_NULL).unwrap().is_none());
+        // Every cut through the answer record is an error, never a panic
+        let record = pos + 4;
+        for cut in record..reply.len() - opt_record().len() {
+            assert!(answer(&reply[..cut], 42, &name, TYPE_NULL).is_err(), "cut at {}", cut);
+        }
+        // TXT strings that claim more than is there
+        let txt = response(&query, &query[dns::HEADER_SIZE..pos + 4], TYPE_TXT, 0, Some(vec![10, b'A']));
+        assert!(answer(&txt, 42, &name, TYPE_TXT).is_err());
+        // The relay forgot the session
+        let gone = response(&query, &query[dns::HEADER_SIZE..pos + 4], TYPE_NULL, dns::RCODE_NXDOMAIN, None);
+        assert_eq!(answer(&gone, 42, &name, TYPE_NULL).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn relay_ignores_what_is_not_a_question() {
+        let query = query(1, &tunnel().name(0, KIND_DATA, b""), TYPE_NULL);
+        assert!(ask_relay(&[]).is_none());
+        assert!(ask_relay(&query[..dns::HEADER_SIZE]).is_none());
+        // Cut inside the question: the name or its type and class missing
+        let pos = read_name(&query, dns::HEADER_SIZE).unwrap().1;
+        for cut in dns::HEADER_SIZE..pos + 4 {
+            assert!(ask_relay(&query[..cut]).is_none(), "cut at {}", cut);
+        }
+        // A response, or more than one question
+        let mut reply = query.clone();
+        reply[2] |= 0x80;
+        assert!(ask_relay(&reply).is_none());
+        let mut two = query.clone();
+        two[5] = 2;
+        assert!(ask_relay(&two).is_none());
+    }
+
+    #[test]
+    fn relay_answers_strangers_like_any_zone() {
+        // Not our zone
+        let foreign = query(1, "www.example.org", TYPE_NULL);
+        assert_eq!(rcode(&ask_relay(&foreign).unwrap()), RCODE_REFUSED);
+        // Our zone, but not a name of ours or not a type we answer
+        let plain = query(1, &format!("www.{}", DOMAIN), TYPE_NULL);
+        assert_eq!(rcode(&ask_relay(&plain).unwrap()), dns::RCODE_NXDOMAIN);
+        let other_type = query(1, &tunnel().name(0, KIND_DATA, b""), 1);
+        assert_eq!(rcode(&ask_relay(&other_type).unwrap()), dns::RCODE_NXDOMAIN);
+        // A session it never opened
+        let data = query(1, &tunnel().name(0, KIND_DATA, b"x"), TYPE_NULL);
+        assert_eq!(rcode(&ask_relay(&data).unwrap()), dns::RCODE_NXDOMAIN);
+    }
+
+    #[test]
+    fn relay_probes_only_with_proof_and_within_bounds() {
+        let tunnel = tunnel();
+        // No proof, or proof under another secret
+        let bare = query(1, &tunnel.name(0, KIND_PROBE, &(MAX_PROBE as u16).to_be_bytes()), TYPE_NULL);
+        assert_eq!(rcode(&ask_relay(&bare).unwrap()), dns::RCODE_NXDOMAIN);
+        let stranger = auth::Secret::new("another-secret-altogether").unwrap();
+        let forged = query(1, &tunnel.proven(0, KIND_PROBE, MAX_PROBE, &stranger), TYPE_NULL);
+        assert_eq!(rcode(&ask_relay(&forged).unwrap()), dns::RCODE_NXDOMAIN);
+        // Proof for an OPEN does not pass for a PROBE
+        let open = tunnel.proven(0, KIND_OPEN, MAX_PROBE, &secret());
+        let mut payload = BASE32_DNSSEC.decode(open.trim_end_matches(DOMAIN).replace('.', "").as_bytes()).unwrap();
+        payload[6] = KIND_PROBE;
+        let misused = tunnel.encode(&payload);
+        assert_eq!(rcode(&ask_relay(&query(1, &misused, TYPE_NULL)).unwrap()), dns::RCODE_NXDOMAIN);
+
+        // With proof: answered, never far past the query's own size
+        let proven = query(1, &tunnel.proven(0, KIND_PROBE, u16::MAX as usize, &secret()), TYPE_NULL);
+        let reply = ask_relay(&proven).unwrap();
+        assert_eq!(rcode(&reply), 0);
+        assert!(reply.len() <= proven.len() * (PROBE_GAIN + 1), "{} for {}", reply.len(), proven.len());
+    }
+
+    #[tokio::test]
+    async fn relay_opens_once_per_session() {
+        let (carriers, mut opened) = mpsc::channel(4);
+        let mut sessions = HashMap::new();
+        let source = IpAddr::from([192, 0, 2, 1]);
+        let open = query(1, &tunnel().proven(5, KIND_OPEN, FRAME, &secret()), TYPE_NULL);
+        let first = handle(&open, source, DOMAIN, &secret(), &mut sessions, &carriers).unwrap();
+        assert_eq!(rcode(&first), 0);
+        // Retried (or replayed): the same answer, and no second carrier
+        let again = handle(&open, source, DOMAIN, &secret(), &mut sessions, &carriers).unwrap();
+        assert_eq!(again[2..], first[2..]);
+        assert!(opened.try_recv().is_ok());
+        assert!(opened.try_recv().is_err());
+        // Once the session has moved on, the OPEN gets nothing
+        let data = query(2, &tunnel().name(6, KIND_DATA, b""), TYPE_NULL);
+        assert!(handle(&data, source, DOMAIN, &secret(), &mut sessions, &carriers).is_some());
+        assert!(handle(&open, source, DOMAIN, &secret(), &mut sessions, &carriers).is_none());
+    }
+}
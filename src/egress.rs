@@ -0,0 +1,477 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, lookup_host};
+use crate::mux::{Incoming, Mux, MuxStream, Side};
+use crate::{auth, resume};
+use crate::stream::ProteusStream;
+
+// --- EGRESS PROTOCOL ---
+// The client opens one mux stream per flow. The OPEN payload is
+// [command][address] (SOCKS5 encoding) and the relay answers with a single
+// status byte before any data: 0x00 means the outbound side is ready.
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02; // listen on the relay; inbound connections come back as CONNECT streams
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+// Not a SOCKS command: one DNS query answered by the relay's resolver (see `dns`)
+pub const CMD_DNS: u8 = 0x80;
+
+// Status bytes are SOCKS5 reply codes so the proxy can pass them straight through
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// --- DESTINATION POLICY ---
+// The relay dials whatever its clients name. Left open, that reaches into the
+// relay's own host and network: services on loopback, the cloud metadata
+// endpoint on 169.254.169.254, the LAN behind it. Those are refused unless
+// the operator lets clients in. Checked on resolved addresses, so a domain
+// that resolves inside gets no further than the address itself would.
+
+/// Where the relay will send traffic on a client's behalf, and where it
+/// will listen for one (`CMD_BIND`)
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Let clients reach loopback, link-local and private addresses too
+    pub allow_private: bool,
+    /// Addresses clients may have the relay listen on; none means no reverse forwards
+    pub binds: Vec<BindRule>,
+}
+
+impl Policy {
+    /// No limits: for a client dialing its own side of a reverse forward
+    pub fn open() -> Self {
+        Policy { allow_private: true, binds: Vec::new() }
+    }
+
+    pub fn check(&self, addr: SocketAddr) -> io::Result<()> {
+        if self.allow_private || is_public(addr.ip()) { return Ok(()); }
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not a public address", addr.ip())))
+    }
+
+    pub fn check_bind(&self, addr: SocketAddr) -> io::Result<()> {
+        if self.binds.iter().any(|rule| rule.allows(addr)) { return Ok(()); }
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("listening on {} is not allowed", addr)))
+    }
+}
+
+/// One address (and range of ports) the relay may listen on:
+/// `ADDR:PORT` or `ADDR:FIRST-LAST`, with IPv6 addresses in brackets
+#[derive(Debug, Clone)]
+pub struct BindRule {
+    ip: IpAddr,
+    ports: RangeInclusive<u16>,
+}
+
+impl BindRule {
+    fn allows(&self, addr: SocketAddr) -> bool {
+        addr.ip() == self.ip && self.ports.contains(&addr.port())
+    }
+}
+
+impl FromStr for BindRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("expected ADDR:PORT or ADDR:FIRST-LAST, got '{}'", spec);
+        let (host, ports) = spec.rsplit_once(':').ok_or_else(bad)?;
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        let ip = host.parse().map_err(|_| bad())?;
+        let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+        let (first, last): (u16, u16) = (first.parse().map_err(|_| bad())?, last.parse().map_err(|_| bad())?);
+        if first == 0 || first > last { return Err(bad()); }
+        Ok(BindRule { ip, ports: first..=last })
+    }
+}
+
+impl fmt::Display for BindRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = SocketAddr::new(self.ip, *self.ports.start());
+        if self.ports.start() == self.ports.end() { return write!(f, "{}", address); }
+        write!(f, "{}-{}", address, self.ports.end())
+    }
+}
+
+/// Not loopback, link-local, private, shared (CGNAT), reserved, multicast or
+/// otherwise local to the relay's network, nor an IPv6 address that reaches
+/// such a v4 one through NAT64 or 6to4
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let first = segments[0];
+            let embedded = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+            // 64:ff9b::/96 is NAT64, 2002::/16 6to4: both carry a v4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] { return is_public_v4(embedded(segments[6], segments[7])); }
+            if first == 0x2002 { return is_public_v4(embedded(segments[1], segments[2])); }
+            // fc00::/7 is unique local, fe80::/10 link-local
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    // 100.64.0.0/10 is shared (CGNAT), 192.0.0.0/24 protocol assignments,
+    // 198.18.0.0/15 benchmarking, 240.0.0.0/4 (and broadcast) reserved
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        || ip.is_multicast() || a == 0 || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0) || (a == 198 && (b & 0xfe) == 18) || a >= 240)
+}
+
+/// A destination as the client names it. Domains are resolved on the relay,
+/// so lookups never leave the tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Socket(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    /// Placeholder used where SOCKS wants an address but none applies
+    pub fn unspecified() -> Self {
+        Address::Socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    }
+
+    /// SOCKS5 wire form: [atyp][addr][port]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Address::Socket(SocketAddr::V4(addr)) => {
+                bytes.push(ATYP_IPV4);
+                bytes.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Socket(SocketAddr::V6(addr)) => {
+                bytes.push(ATYP_IPV6);
+                bytes.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Domain(host, _) => {
+                bytes.push(ATYP_DOMAIN);
+                bytes.push(host.len() as u8);
+                bytes.extend_from_slice(host.as_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.port().to_be_bytes());
+        bytes
+    }
+
+    /// Parse the SOCKS5 wire form. Returns the address and how many bytes it used.
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (&atyp, rest) = bytes.split_first()?;
+        let (addr, used) = match atyp {
+            ATYP_IPV4 => {
+                let octets: [u8; 4] = rest.get(0..4)?.try_into().ok()?;
+                let port = u16::from_be_bytes(rest.get(4..6)?.try_into().ok()?);
+                (Address::Socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port)), 6)
+            }
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = rest.get(0..16)?.try_into().ok()?;
+                let port = u16::from_be_bytes(rest.get(16..18)?.try_into().ok()?);
+                (Address::Socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)), 18)
+            }
+            ATYP_DOMAIN => {
+                let len = *rest.first()? as usize;
+                let host = std::str::from_utf8(rest.get(1..1 + len)?).ok()?.to_string();
+                let port = u16::from_be_bytes(rest.get(1 + len..3 + len)?.try_into().ok()?);
+                (Address::Domain(host, port), 3 + len)
+            }
+            _ => return None,
+        };
+        Some((addr, 1 + used))
+    }
+
+    /// Read the SOCKS5 wire form from a stream
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let atyp = reader.read_u8().await?;
+        let mut bytes = vec![atyp];
+        let body_len = match atyp {
+            ATYP_IPV4 => 4 + 2,
+            ATYP_IPV6 => 16 + 2,
+            ATYP_DOMAIN => {
+                let len = reader.read_u8().await?;
+                bytes.push(len);
+                len as usize + 2
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address type")),
+        };
+        let start = bytes.len();
+        bytes.resize(start + body_len, 0);
+        reader.read_exact(&mut bytes[start..]).await?;
+        Address::from_bytes(&bytes)
+            .map(|(addr, _)| addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad address"))
+    }
+
+    /// Parse an authority such as `example.com:443`, `1.2.3.4:80` or `[::1]:22`
+    pub fn parse_authority(authority: &str) -> Option<Self> {
+        if let Ok(addr) = authority.parse::<SocketAddr>() {
+            return Some(Address::Socket(addr));
+        }
+        let (host, port) = authority.rsplit_once(':')?;
+        let port = port.parse().ok()?;
+        // The wire form stores the length in one byte
+        if host.is_empty() || host.len() > 255 || host.contains(['[', ']', ':']) {
+            return None;
+        }
+        Some(Address::Domain(host.to_string(), port))
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Socket(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    /// Resolve to a concrete socket address (relay side)
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            Address::Socket(addr) => Ok(*addr),
+            Address::Domain(host, port) => lookup_host((host.as_str(), *port)).await?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::HostUnreachable, "no addresses for host")),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Socket(addr) => write!(f, "{}", addr),
+            Address::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Map an outbound failure onto the status byte sent back to the client
+pub fn reply_code(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
+        io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        io::ErrorKind::Unsupported => REPLY_COMMAND_NOT_SUPPORTED,
+        io::ErrorKind::InvalidData => REPLY_ADDRESS_NOT_SUPPORTED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+pub(crate) fn status_error(code: u8) -> io::Error {
+    let kind = match code {
+        REPLY_CONNECTION_REFUSED => io::ErrorKind::ConnectionRefused,
+        REPLY_NOT_ALLOWED => io::ErrorKind::PermissionDenied,
+        REPLY_HOST_UNREACHABLE => io::ErrorKind::HostUnreachable,
+        REPLY_NETWORK_UNREACHABLE => io::ErrorKind::NetworkUnreachable,
+        REPLY_COMMAND_NOT_SUPPORTED => io::ErrorKind::Unsupported,
+        REPLY_ADDRESS_NOT_SUPPORTED => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("relay refused flow (status {:#04x})", code))
+}
+
+// --- DATAGRAMS OVER A STREAM ---
+// UDP associations carry [len u16][address][payload] records on their mux stream.
+
+pub async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, addr: &Address, payload: &[u8]) -> io::Result<()> {
+    let mut record = addr.to_bytes();
+    record.extend_from_slice(payload);
+    let len = u16::try_from(record.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(&record).await
+}
+
+pub async fn read_datagram<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(Address, Vec<u8>)> {
+    let len = reader.read_u16().await? as usize;
+    let mut record = vec![0u8; len];
+    reader.read_exact(&mut record).await?;
+    let (addr, used) = Address::from_bytes(&record)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad datagram address"))?;
+    record.drain(..used);
+    Ok((addr, record))
+}
+
+// --- CLIENT SIDE ---
+
+/// Dial the relay and start a multiplexed Proteus session.
+/// `Incoming` only sees streams if the client asked for reverse forwards (`CMD_BIND`).
+/// A dropped carrier is redialed in the background and the session resumed;
+/// `Incoming` closes only once that has failed.
+pub async fn open_session(relay: resume::Relay) -> io::Result<(Mux, Incoming)> {
+    let (carrier, ticket) = resume::dial(&relay, None).await?;
+    let (stream, handle) = ProteusStream::resumable(carrier, relay.secret.derive(auth::STREAM_KEY), ticket, Side::Client);
+    tokio::spawn(resume::supervise(relay, ticket, handle));
+    Ok(Mux::new(stream, Side::Client))
+}
+
+/// Ask the relay to open `command` towards `addr`, waiting for its verdict
+pub async fn connect(mux: &Mux, command: u8, addr: &Address) -> io::Result<MuxStream> {
+    let mut request = vec![command];
+    request.extend(addr.to_bytes());
+    let mut stream = mux.open(&request)?;
+    match stream.read_u8().await? {
+        REPLY_SUCCEEDED => Ok(stream),
+        code => Err(status_error(code)),
+    }
+}
+
+// --- RELAY SIDE ---
+
+/// Serve one client session over an established stream: every stream the
+/// client opens becomes an outbound flow
+pub async fn serve_stream(stream: ProteusStream, policy: Arc<Policy>) {
+    let (mux, mut incoming) = Mux::new(stream, Side::Server);
+
+    while let Some(stream) = incoming.accept().await {
+        tokio::spawn(serve_flow(stream, mux.clone(), policy.clone()));
+    }
+    println!("[EGRESS] Session closed.");
+}
+
+async fn serve_flow(mut stream: MuxStream, mux: Mux, policy: Arc<Policy>) {
+    let request = stream.target().to_vec();
+    let parsed = request.split_first()
+        .and_then(|(&command, rest)| Address::from_bytes(rest).map(|(addr, _)| (command, addr)));
+
+    let Some((command, addr)) = parsed else {
+        stream.write_all(&[REPLY_ADDRESS_NOT_SUPPORTED]).await.ok();
+        return;
+    };
+
+    let result = match command {
+        CMD_CONNECT => serve_connect(&mut stream, &addr, &policy).await,
+        CMD_BIND => relay_bind(stream, &addr, mux, &policy).await,
+        CMD_UDP_ASSOCIATE => relay_udp(stream, policy).await,
+        CMD_DNS => crate::dns::serve_query(stream).await,
+        _ => {
+            stream.write_all(&[REPLY_COMMAND_NOT_SUPPORTED]).await.ok();
+            return;
+        }
+    };
+    if let Err(e) = result {
+        println!("[EGRESS] {} -> {}", addr, e);
+    }
+}
+
+/// Open the outbound connection on behalf of the client, if `policy` lets it through
+pub async fn dial(addr: &Address, policy: &Policy) -> io::Result<TcpStream> {
+    let resolved = addr.resolve().await?;
+    policy.check(resolved)?;
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(resolved)).await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Answer a CONNECT stream: dial `addr`, report the verdict, then splice.
+/// Used by the relay for egress and by clients for reverse forwards.
+pub async fn serve_connect(stream: &mut MuxStream, addr: &Address, policy: &Policy) -> io::Result<()> {
+    let mut outbound = match dial(addr, policy).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            stream.write_all(&[reply_code(&e)]).await.ok();
+            return Err(e);
+        }
+    };
+    outbound.set_nodelay(true).ok();
+    stream.write_all(&[REPLY_SUCCEEDED]).await?;
+    tokio::io::copy_bidirectional(stream, &mut outbound).await?;
+    Ok(())
+}
+
+/// Listen on the relay for as long as the BIND stream stays open, handing
+/// every inbound connection back to the client
+async fn relay_bind(mut stream: MuxStream, addr: &Address, mux: Mux, policy: &Policy) -> io::Result<()> {
+    let listener = match addr.resolve().await {
+        Ok(resolved) => match policy.check_bind(resolved) {
+            Ok(()) => TcpListener::bind(resolved).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            stream.write_all(&[reply_code(&e)]).await.ok();
+            return Err(e);
+        }
+    };
+    stream.write_all(&[REPLY_SUCCEEDED]).await?;
+    println!("[EGRESS] Reverse forward listening on {}", listener.local_addr()?);
+
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut inbound, peer) = accepted?;
+                let mux = mux.clone();
+                let bind = addr.clone();
+                tokio::spawn(async move {
+                    // The client recognises the forward by the address it asked us to bind
+                    match connect(&mux, CMD_CONNECT, &bind).await {
+                        Ok(mut back) => { tokio::io::copy_bidirectional(&mut inbound, &mut back).await.ok(); }
+                        Err(e) => println!("[EGRESS] Reverse {} from {} -> {}", bind, peer, e),
+                    }
+                });
+            }
+            closed = stream.read(&mut control) => {
+                if matches!(closed, Ok(0) | Err(_)) { break; }
+            }
+        }
+    }
+    println!("[EGRESS] Reverse forward on {} closed.", addr);
+    Ok(())
+}
+
+async fn relay_udp(mut stream: MuxStream, policy: Arc<Policy>) -> io::Result<()> {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            stream.write_all(&[reply_code(&e)]).await.ok();
+            return Err(e);
+        }
+    };
+    stream.write_all(&[REPLY_SUCCEEDED]).await?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Client -> Internet
+    let uplink_socket = socket.clone();
+    let mut uplink = tokio::spawn(async move {
+        while let Ok((addr, payload)) = read_datagram(&mut reader).await {
+            if let Ok(dest) = addr.resolve().await && policy.check(dest).is_ok() {
+                uplink_socket.send_to(&payload, dest).await.ok();
+            }
+        }
+    });
+
+    // Internet -> Client, until the client side of the stream goes away
+    let mut buf = vec![0u8; 65535];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (n, src) = received?;
+                write_datagram(&mut writer, &Address::Socket(src), &buf[..n]).await?;
+            }
+            _ = &mut uplink => break,
+        }
+    }
+    writer.shutdown().await.ok();
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::transport::TransportType;
+
+// --- CARRIER FAILOVER ---
+// A session is not tied to one carrier. The client keeps a prioritized list
+// (say TCP on 443, then UDP, then UDP on an alternate port) and sends over the
+// best one that works. A write error, or silence from the far end for
+// DEAD_AFTER, moves it down the list. While on a fallback, the primary is
+// probed every PROBE_EVERY with copies of real frames (any symbol helps the
+// decoder); once the primary is answered again the session moves back.
+// Frames carry the connection id, so the receiver does not care which
+// carrier brings them.
+
+const DEAD_AFTER: Duration = Duration::from_secs(3);
+const PROBE_EVERY: Duration = Duration::from_secs(5);
+// With every carrier down, try the list again this often
+const REDIAL_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Tcp,
+    Udp,
+}
+
+/// One entry in the carrier list: `tcp://HOST:PORT` or `udp://HOST:PORT`
+#[derive(Debug, Clone)]
+pub struct CarrierSpec {
+    pub scheme: Scheme,
+    pub target: String,
+}
+
+impl FromStr for CarrierSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (scheme, target) = if let Some(target) = spec.strip_prefix("tcp://") {
+            (Scheme::Tcp, target)
+        } else if let Some(target) = spec.strip_prefix("udp://") {
+            (Scheme::Udp, target)
+        } else {
+            return Err(format!("expected tcp://HOST:PORT or udp://HOST:PORT, got '{}'", spec));
+        };
+        Ok(Self { scheme, target: target.to_string() })
+    }
+}
+
+impl fmt::Display for CarrierSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.scheme { Scheme::Tcp => "tcp", Scheme::Udp => "udp" };
+        write!(f, "{}://{}", scheme, self.target)
+    }
+}
+
+/// When a link last heard from the far end (updated by its reply reader)
+pub type Heard = Arc<Mutex<Instant>>;
+
+/// Opens a carrier and starts its reply reader, which must update `Heard`
+pub type Dialer = Arc<dyn Fn(&CarrierSpec, Heard) -> io::Result<TransportType> + Send + Sync>;
+
+struct Link {
+    index: usize,
+    transport: TransportType,
+    heard: Heard,
+    opened: Instant,
+}
+
+// Dropping a TCP link closes the connection for its reply reader too
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let TransportType::Tcp(stream) = &self.transport {
+            stream.lock().unwrap().shutdown(std::net::Shutdown::Both).ok();
+        }
+    }
+}
+
+impl Link {
+    fn silent_for(&self) -> Duration {
+        self.heard.lock().unwrap().elapsed()
+    }
+
+    fn answered(&self) -> bool {
+        *self.heard.lock().unwrap() > self.opened
+    }
+}
+
+/// The carriers of one path, best first, and the one in use
+pub struct Failover {
+    name: String,
+    specs: Vec<CarrierSpec>,
+    dial: Dialer,
+    active: Option<Link>,
+    redial_at: Instant,
+    // A primary being probed (dialed in the background, then tried)
+    probe: Option<Link>,
+    dialing: Option<mpsc::Receiver<io::Result<Link>>>,
+    next_probe: Instant,
+}
+
+impl Failover {
+    pub fn new(name: String, specs: Vec<CarrierSpec>, dial: Dialer) -> Self {
+        assert!(!specs.is_empty(), "a path needs at least one carrier");
+        let now = Instant::now();
+        Self { name, specs, dial, active: None, redial_at: now, probe: None, dialing: None, next_probe: now + PROBE_EVERY }
+    }
+
+    /// Send one frame over the best working carrier
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.check();
+        if let Some(probe) = &self.probe {
+            probe.transport.send(data, &self.specs[0].target).ok();
+        }
+
+        let Some(link) = &self.active else {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "no carrier is up"));
+        };
+        let result = link.transport.send(data, &self.specs[link.index].target);
+        if let Err(e) = &result {
+            println!("[FAILOVER] {}: {} failed ({}).", self.name, self.specs[link.index], e);
+            let next = link.index + 1;
+            self.active = None;
+            self.connect(next);
+        }
+        result
+    }
+
+    fn check(&mut self) {
+        let Some(link) = &self.active else {
+            if Instant::now() >= self.redial_at { self.connect(0); }
+            return;
+        };
+
+        // Only a list can fail over; a lone carrier is kept whatever happens
+        if self.specs.len() > 1 && link.silent_for() >= DEAD_AFTER {
+            println!("[FAILOVER] {}: {} silent for {:?}.", self.name, self.specs[link.index], DEAD_AFTER);
+            let next = link.index + 1;
+            self.active = None;
+            self.connect(next);
+            return;
+        }
+
+        if link.index > 0 { self.probe_primary(); }
+    }
+
+    /// Take the first carrier that dials, starting at `first` and wrapping
+    fn connect(&mut self, first: usize) {
+        for offset in 0..self.specs.len() {
+            let index = (first + offset) % self.specs.len();
+            match open(&self.dial, &self.specs[index], index) {
+                Ok(link) => {
+                    println!("[FAILOVER] {}: using {}.", self.name, self.specs[index]);
+                    self.active = Some(link);
+                    self.probe = None;
+                    self.next_probe = Instant::now() + PROBE_EVERY;
+                    return;
+                }
+                Err(e) => println!("[FAILOVER] {}: {} unavailable ({}).", self.name, self.specs[index], e),
+            }
+        }
+        self.redial_at = Instant::now() + REDIAL_EVERY;
+    }
+
+    fn probe_primary(&mut self) {
+        // A probe that has been answered takes over
+        if let Some(probe) = self.probe.take() {
+            if probe.answered() {
+                println!("[FAILOVER] {}: {} is back. Moving back.", self.name, self.specs[0]);
+                self.active = Some(probe);
+                return;
+            }
+            if probe.opened.elapsed() < DEAD_AFTER {
+                self.probe = Some(probe);
+                return;
+            }
+            self.next_probe = Instant::now() + PROBE_EVERY;
+        }
+
+        if let Some(dialing) = &self.dialing {
+            match dialing.try_recv() {
+                Ok(Ok(link)) => { self.probe = Some(link); self.dialing = None; }
+                Ok(Err(_)) | Err(mpsc::TryRecvError::Disconnected) => {
+                    self.dialing = None;
+                    self.next_probe = Instant::now() + PROBE_EVERY;
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            return;
+        }
+
+        // Dial off the send path: a blocked primary may take a while to fail
+        if Instant::now() >= self.next_probe {
+            let (tx, rx) = mpsc::channel();
+            let (dial, spec) = (self.dial.clone(), self.specs[0].clone());
+            thread::spawn(move || tx.send(open(&dial, &spec, 0)).ok());
+            self.dialing = Some(rx);
+        }
+    }
+}
+
+fn open(dial: &Dialer, spec: &CarrierSpec, index: usize) -> io::Result<Link> {
+    let heard = Arc::new(Mutex::new(Instant::now()));
+    let transport = dial(spec, heard.clone())?;
+    // Timed from when it is usable: a slow connect must not eat its grace period
+    let opened = Instant::now();
+    *heard.lock().unwrap() = opened;
+    Ok(Link { index, transport, heard, opened })
+}
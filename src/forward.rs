@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::io;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use crate::egress::{self, Address};
+use crate::mux::{Incoming, Mux, MuxStream};
+
+// --- STATIC PORT FORWARDING ---
+
+// Forwards listen on loopback unless a bind address is given (same as ssh)
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+/// One `[bind:]port:host:hostport` rule, as given to `-L` or `-R`
+#[derive(Debug, Clone)]
+pub struct ForwardSpec {
+    pub bind: Address,
+    pub target: Address,
+}
+
+impl ForwardSpec {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let fields = split_fields(spec);
+        let (bind_host, fields) = match fields.len() {
+            3 => (DEFAULT_BIND, &fields[..]),
+            4 => (fields[0], &fields[1..]),
+            _ => return None,
+        };
+        let bind = Address::parse_authority(&format!("{}:{}", bind_host, fields[0]))?;
+        let target = Address::parse_authority(&format!("{}:{}", fields[1], fields[2]))?;
+        Some(Self { bind, target })
+    }
+}
+
+/// Split on ':' but keep bracketed IPv6 literals (`[::1]`) in one piece
+fn split_fields(spec: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ':' if depth == 0 => {
+                fields.push(&spec[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&spec[start..]);
+    fields
+}
+
+/// `-L`: listen locally, and have the relay connect each client to `spec.target`
+pub async fn run_local(spec: ForwardSpec, mux: Mux) -> io::Result<()> {
+    let listener = TcpListener::bind(spec.bind.resolve().await?).await?;
+    println!("[FORWARD] {} -> (relay) -> {}", listener.local_addr()?, spec.target);
+
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        let mux = mux.clone();
+        let target = spec.target.clone();
+        tokio::spawn(async move {
+            socket.set_nodelay(true).ok();
+            match egress::connect(&mux, egress::CMD_CONNECT, &target).await {
+                Ok(mut stream) => { tokio::io::copy_bidirectional(&mut socket, &mut stream).await.ok(); }
+                Err(e) => println!("[FORWARD] {} -> {}: {}", peer, target, e),
+            }
+        });
+    }
+}
+
+/// `-R`: ask the relay to listen on `spec.bind`. The returned control stream
+/// keeps the listener alive; drop it to tear the forward down.
+pub async fn request_remote(spec: &ForwardSpec, mux: &Mux) -> io::Result<MuxStream> {
+    let control = egress::connect(mux, egress::CMD_BIND, &spec.bind).await?;
+    println!("[FORWARD] (relay) {} -> {}", spec.bind, spec.target);
+    Ok(control)
+}
+
+/// Answer the relay's reverse connections. Only targets named in `remotes`
+/// are ever dialed; the relay cannot make us connect anywhere else.
+pub async fn serve_remote(mut incoming: Incoming, remotes: Vec<ForwardSpec>) {
+    let routes: HashMap<Address, Address> = remotes.into_iter()
+        .map(|spec| (spec.bind, spec.target))
+        .collect();
+
+    while let Some(mut stream) = incoming.accept().await {
+        let request = stream.target().to_vec();
+        let route = request.split_first()
+            .filter(|(command, _)| **command == egress::CMD_CONNECT)
+            .and_then(|(_, rest)| Address::from_bytes(rest))
+            .and_then(|(bind, _)| routes.get(&bind).cloned());
+
+        tokio::spawn(async move {
+            let Some(target) = route else {
+                stream.write_all(&[egress::REPLY_CONNECTION_REFUSED]).await.ok();
+                return;
+            };
+            if let Err(e) = egress::serve_connect(&mut stream, &target, &egress::Policy::open()).await {
+                println!("[FORWARD] (relay) -> {}: {}", target, e);
+            }
+        });
+    }
+}
@@ -3,6 +3,14 @@ use std::convert::TryInto;
 
 pub const HEADER_SIZE: usize = 12; // 4 bytes (Seq) + 8 bytes (Time)
 
+/// Microseconds since UNIX EPOCH (the clock used by every header timestamp)
+pub fn now_micros() -> u64 {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    since_the_epoch.as_micros() as u64
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PacketHeader {
     pub seq_id: u32,
@@ -12,14 +20,9 @@ pub struct PacketHeader {
 impl PacketHeader {
     /// Create a new header for the current moment
     pub fn new(seq_id: u32) -> Self {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        
         Self {
             seq_id,
-            timestamp: since_the_epoch.as_micros() as u64,
+            timestamp: now_micros(),
         }
     }
 
@@ -77,4 +80,41 @@ impl AckPacket {
         let timestamp = u64::from_be_bytes(bytes[4..12].try_into().ok()?);
         Some(Self { seq_id, timestamp })
     }
-}
\ No newline at end of file
+}
+
+// --- DATAGRAM SESSIONS (UDP) ---
+// A UDP peer's address is not its identity: NAT mappings get rebound and
+// phones hop networks. Every UDP frame opens with the sender's connection id
+// and a kind, so the receiver finds the session whatever address it came from.
+
+pub const DATAGRAM_HEADER_SIZE: usize = 9; // 8 bytes (Conn ID) + 1 byte (Kind)
+
+pub const KIND_DATA: u8 = 0;           // [PacketHeader][symbol]
+pub const KIND_ACK: u8 = 1;            // [AckPacket]
+pub const KIND_PATH_CHALLENGE: u8 = 2; // [token]
+pub const KIND_PATH_RESPONSE: u8 = 3;  // [nonce][sealed token]
+
+#[derive(Debug, Clone, Copy)]
+pub struct DatagramHeader {
+    pub conn_id: u64,
+    pub kind: u8,
+}
+
+impl DatagramHeader {
+    pub fn new(conn_id: u64, kind: u8) -> Self {
+        Self { conn_id, kind }
+    }
+
+    pub fn to_bytes(&self) -> [u8; DATAGRAM_HEADER_SIZE] {
+        let mut bytes = [0u8; DATAGRAM_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.conn_id.to_be_bytes());
+        bytes[8] = self.kind;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < DATAGRAM_HEADER_SIZE { return None; }
+        let conn_id = u64::from_be_bytes(bytes[0..8].try_into().ok()?);
+        Some(Self { conn_id, kind: bytes[8] })
+    }
+}
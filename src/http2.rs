@@ -0,0 +1,298 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use bytes::Bytes;
+use h2::{RecvStream, SendStream};
+use h2::client::{ResponseFuture, SendRequest};
+use h2::server::SendResponse;
+use http::{Method, Request, Response, StatusCode, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::cloak::{self, Cloak, Deframer, Framed, Halves};
+use crate::{auth, decoy};
+
+// --- HTTP/2 CARRIER ---
+// One long-lived HTTP/2 connection (h2c, or h2 inside TLS), used the way a
+// web app uses one: uplink frames go out in the body of a streaming POST,
+// downlink frames come back in the DATA frames of its response. Every so
+// often the client moves on to a fresh request on the same connection and
+// the relay answers on the new one, so no single stream carries the whole
+// session. Only POSTs to the relay's path with proof of its secret in the
+// cookie are ours; every other request gets the decoy site's page. Each side finishes a stream at a frame boundary before starting
+// the next, and both take streams in the order they were opened, so the
+// carrier stays one ordered stream of bare frames (see `cloak::framed`).
+
+/// What every HTTP/2 client sends first, TLS or not
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+const ROTATE_BYTES: usize = 4 * 1024 * 1024;
+const ROTATE_AFTER: Duration = Duration::from_secs(30);
+const STREAM_WINDOW: u32 = 1024 * 1024;
+const CONNECTION_WINDOW: u32 = 8 * 1024 * 1024;
+const PIPE_BUFFER: usize = 256 * 1024;
+
+/// Some(true) once `buf` opens with the preface, None while it still might
+pub fn sniff(buf: &[u8]) -> Option<bool> {
+    if buf.starts_with(PREFACE) {
+        Some(true)
+    } else if PREFACE.starts_with(buf) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+fn h2_error(e: h2::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// --- CLIENT SIDE ---
+
+/// Speak HTTP/2 over `socket`, already connected to the relay `url` names
+/// (`h2c://HOST:PORT/PATH`, or `h2://` once TLS is up underneath), proving
+/// we know its `secret` on every request
+pub async fn connect<S>(socket: S, url: &str, secret: &auth::Secret) -> io::Result<Halves>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (scheme, url) = url.split_once("://").unwrap_or(("h2c", url));
+    let (authority, path) = match url.find('/') {
+        Some(slash) => (&url[..slash], &url[slash..]),
+        None => (url, "/"),
+    };
+    let scheme = if scheme == "h2" { "https" } else { "http" };
+    let uri: Uri = format!("{}://{}{}", scheme, authority, path).parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("bad URL: {}", e)))?;
+
+    let (requests, connection) = h2::client::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake::<_, Bytes>(socket).await
+        .map_err(h2_error)?;
+    let (closed_tx, closed) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        connection.await.ok();
+        drop(closed_tx);
+    });
+
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (responses_tx, responses) = mpsc::unbounded_channel();
+    // Bytes moved either way on the current request
+    let carried = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(client_uplink(requests, uri, secret.clone(), pipe_reader, responses_tx, carried.clone()));
+    tokio::spawn(client_downlink(responses, pipe_writer, closed, carried));
+    Ok(tokio::io::split(theirs))
+}
+
+/// A streaming POST, as a page's fetch() would send it
+async fn open(requests: &SendRequest<Bytes>, uri: &Uri, secret: &auth::Secret) -> io::Result<(ResponseFuture, SendStream<Bytes>)> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(uri.clone())
+        .header("user-agent", USER_AGENT)
+        .header("accept", "*/*")
+        .header("accept-language", "en-US,en;q=0.9")
+        .header("content-type", "application/octet-stream")
+        .header("cookie", secret.cookie(uri.path()))
+        .body(())
+        .unwrap();
+    let mut requests = requests.clone().ready().await.map_err(h2_error)?;
+    requests.send_request(request, false).map_err(h2_error)
+}
+
+/// Frames out of the pipe into request bodies, moving to a new request
+/// now and then
+async fn client_uplink(requests: SendRequest<Bytes>, uri: Uri, secret: auth::Secret, mut pipe: ReadHalf<DuplexStream>, responses: mpsc::UnboundedSender<ResponseFuture>, carried: Arc<AtomicUsize>) {
+    let Ok((response, mut body)) = open(&requests, &uri, &secret).await else { return; };
+    if responses.send(response).is_err() { return; }
+
+    let mut deframer = Deframer::new(cloak::framed());
+    let mut since = Instant::now();
+    loop {
+        let frame = tokio::select! {
+            frame = deframer.read(&mut pipe) => match frame {
+                Ok(Some(frame)) => Some(frame),
+                _ => break,
+            },
+            _ = tokio::time::sleep_until(since + ROTATE_AFTER) => None,
+        };
+
+        if carried.load(Ordering::Relaxed) >= ROTATE_BYTES || since.elapsed() >= ROTATE_AFTER {
+            body.send_data(Bytes::new(), true).ok();
+            let Ok((response, next)) = open(&requests, &uri, &secret).await else { return; };
+            if responses.send(response).is_err() { return; }
+            body = next;
+            carried.store(0, Ordering::Relaxed);
+            since = Instant::now();
+        }
+        if let Some(frame) = frame {
+            let message = Framed.wrap(&frame, 0);
+            carried.fetch_add(message.len(), Ordering::Relaxed);
+            if send_all(&mut body, message.into()).await.is_err() { break; }
+        }
+    }
+    body.send_data(Bytes::new(), true).ok();
+}
+
+/// Response bodies into the pipe, one request after another
+async fn client_downlink(mut responses: mpsc::UnboundedReceiver<ResponseFuture>, mut pipe: WriteHalf<DuplexStream>, mut closed: oneshot::Receiver<()>, carried: Arc<AtomicUsize>) {
+    loop {
+        let response = tokio::select! {
+            response = responses.recv() => response,
+            // The relay hung up (GOAWAY) between two responses
+            _ = &mut closed => None,
+        };
+        let Some(response) = response else { break; };
+        let Ok(response) = response.await else { break; };
+        if response.status() != StatusCode::OK { break; }
+        if copy_body(response.into_body(), &mut pipe, Some(&carried)).await.is_err() { break; }
+    }
+    pipe.shutdown().await.ok();
+}
+
+// --- RELAY SIDE ---
+
+/// Serve the HTTP/2 connection a client opened with the preface in `buffered`
+/// (see `sniff`), taking POSTs to `path` that prove they know `secret`
+pub async fn accept<R, W>(reader: R, writer: W, buffered: Vec<u8>, path: String, secret: auth::Secret) -> io::Result<Halves>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let socket = tokio::io::join(io::Cursor::new(buffered).chain(reader), writer);
+    let connection = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake::<_, Bytes>(socket).await
+        .map_err(h2_error)?;
+
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (bodies_tx, bodies) = mpsc::unbounded_channel();
+    let (responders_tx, responders) = mpsc::unbounded_channel();
+    let (done_tx, done) = oneshot::channel();
+    tokio::spawn(serve_requests(connection, path, secret, bodies_tx, responders_tx, done));
+    tokio::spawn(server_uplink(bodies, pipe_writer));
+    tokio::spawn(server_downlink(responders, pipe_reader, done_tx));
+    Ok(tokio::io::split(theirs))
+}
+
+/// Drive the connection: our requests go to the pumps, anything else gets
+/// the decoy site's pages
+async fn serve_requests<T>(
+    mut connection: h2::server::Connection<T, Bytes>,
+    path: String,
+    secret: auth::Secret,
+    bodies: mpsc::UnboundedSender<RecvStream>,
+    responders: mpsc::UnboundedSender<SendResponse<Bytes>>,
+    mut done: oneshot::Receiver<()>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            request = connection.accept() => {
+                let Some(Ok((request, respond))) = request else { break; };
+                let ours = request.method() == Method::POST
+                    && request.uri().path() == path
+                    && request.headers().get_all("cookie").iter()
+                        .any(|cookie| cookie.to_str().is_ok_and(|cookie| secret.admits(&path, cookie)));
+                if !ours {
+                    site_page(request.method(), request.uri().path(), respond);
+                    continue;
+                }
+                if bodies.send(request.into_body()).is_err() || responders.send(respond).is_err() { break; }
+            }
+            // The stream let go of its carrier: say GOAWAY and let the connection wind down
+            _ = &mut done, if !closing => {
+                closing = true;
+                connection.graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// Request bodies into the pipe, in the order the requests came
+async fn server_uplink(mut bodies: mpsc::UnboundedReceiver<RecvStream>, mut pipe: WriteHalf<DuplexStream>) {
+    while let Some(body) = bodies.recv().await {
+        if copy_body(body, &mut pipe, None).await.is_err() { break; }
+    }
+    pipe.shutdown().await.ok();
+}
+
+/// Frames out of the pipe into the response to the newest request
+async fn server_downlink(mut responders: mpsc::UnboundedReceiver<SendResponse<Bytes>>, mut pipe: ReadHalf<DuplexStream>, _done: oneshot::Sender<()>) {
+    let Some(mut respond) = responders.recv().await else { return; };
+    let Ok(mut body) = respond.send_response(ok(), false) else { return; };
+    let mut deframer = Deframer::new(cloak::framed());
+    loop {
+        tokio::select! {
+            // The client moved on: finish this response and answer on the new request
+            next = responders.recv() => {
+                let Some(mut respond) = next else { break; };
+                body.send_data(Bytes::new(), true).ok();
+                let Ok(next) = respond.send_response(ok(), false) else { return; };
+                body = next;
+            }
+            frame = deframer.read(&mut pipe) => {
+                let Ok(Some(frame)) = frame else { break; };
+                if send_all(&mut body, Framed.wrap(&frame, 0).into()).await.is_err() { break; }
+            }
+        }
+    }
+    body.send_data(Bytes::new(), true).ok();
+}
+
+/// What the decoy site says to the request, as a plain HTTP/1.1 client would hear it
+fn site_page(method: &Method, path: &str, mut respond: SendResponse<Bytes>) {
+    let (status, _, page) = decoy::page(method.as_str(), path);
+    let mut response = Response::builder().status(status);
+    for (name, value) in decoy::page_headers(status, page.len()) {
+        response = response.header(name.to_ascii_lowercase(), value);
+    }
+    let head_only = method == Method::HEAD;
+    let Ok(mut body) = respond.send_response(response.body(()).unwrap(), head_only) else { return; };
+    if !head_only { body.send_data(page.into(), true).ok(); }
+}
+
+fn ok() -> Response<()> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("server", "nginx")
+        .header("content-type", "application/octet-stream")
+        .header("cache-control", "no-store")
+        .body(())
+        .unwrap()
+}
+
+// --- STREAMS ---
+
+/// Send all of `data`, as fast as the peer's flow control allows
+async fn send_all(body: &mut SendStream<Bytes>, mut data: Bytes) -> io::Result<()> {
+    while !data.is_empty() {
+        body.reserve_capacity(data.len());
+        let granted = std::future::poll_fn(|cx| body.poll_capacity(cx)).await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?
+            .map_err(h2_error)?;
+        if granted == 0 { continue; }
+        body.send_data(data.split_to(granted.min(data.len())), false).map_err(h2_error)?;
+    }
+    Ok(())
+}
+
+/// One body into the pipe, handing the window back (and counting it in
+/// `carried`) as we go
+async fn copy_body(mut body: RecvStream, pipe: &mut WriteHalf<DuplexStream>, carried: Option<&AtomicUsize>) -> io::Result<()> {
+    while let Some(data) = body.data().await {
+        let data = data.map_err(h2_error)?;
+        body.flow_control().release_capacity(data.len()).ok();
+        if let Some(carried) = carried { carried.fetch_add(data.len(), Ordering::Relaxed); }
+        pipe.write_all(&data).await?;
+    }
+    Ok(())
+}
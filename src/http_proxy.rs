@@ -0,0 +1,105 @@
+use std::io;
+use base64::{Engine as _, engine::general_purpose};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::egress::{self, Address};
+use crate::mux::Mux;
+
+// --- HTTP/1.1 CONNECT PROXY ---
+
+// Request heads bigger than this are refused (no legitimate CONNECT comes close)
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Accept HTTP CONNECT clients on `listen` and carry every tunnel over `mux`.
+/// With `auth` set (as `user:password`), clients must send matching Basic credentials.
+pub async fn run_http_proxy(listen: &str, mux: Mux, auth: Option<String>) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    println!("[HTTP] Listening on {}", listen);
+
+    // Pre-compute what a valid Proxy-Authorization header carries
+    let expected = auth.map(|creds| general_purpose::STANDARD.encode(creds));
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let mux = mux.clone();
+        let expected = expected.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(socket, mux, expected).await {
+                println!("[HTTP] {} -> {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(mut socket: TcpStream, mux: Mux, expected: Option<String>) -> io::Result<()> {
+    socket.set_nodelay(true).ok();
+
+    // 1. READ THE REQUEST HEAD
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            respond(&mut socket, "431 Request Header Fields Too Large", "").await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 { return Ok(()); }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, authority) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+    // 2. VALIDATE
+    if method != "CONNECT" {
+        respond(&mut socket, "405 Method Not Allowed", "Allow: CONNECT\r\n").await?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("method {}", method)));
+    }
+    let Some(addr) = Address::parse_authority(authority) else {
+        respond(&mut socket, "400 Bad Request", "").await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad authority {:?}", authority)));
+    };
+    if let Some(expected) = expected {
+        let authorized = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Proxy-Authorization"))
+            .any(|(_, value)| {
+                let mut value = value.split_whitespace();
+                matches!(value.next(), Some(scheme) if scheme.eq_ignore_ascii_case("Basic"))
+                    && value.next() == Some(expected.as_str())
+            });
+        if !authorized {
+            respond(&mut socket, "407 Proxy Authentication Required", "Proxy-Authenticate: Basic realm=\"proteus\"\r\n").await?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad proxy credentials"));
+        }
+    }
+
+    // 3. OPEN THE FLOW ON THE RELAY
+    let mut stream = match egress::connect(&mux, egress::CMD_CONNECT, &addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            respond(&mut socket, "502 Bad Gateway", "").await?;
+            return Err(e);
+        }
+    };
+    respond(&mut socket, "200 Connection Established", "").await?;
+
+    // Clients may start talking (e.g. a TLS ClientHello) before our 200 arrives
+    if head_end < buf.len() {
+        stream.write_all(&buf[head_end..]).await?;
+    }
+    tokio::io::copy_bidirectional(&mut socket, &mut stream).await?;
+    Ok(())
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, headers: &str) -> io::Result<()> {
+    let body_headers = if status.starts_with("200") { "" } else { "Content-Length: 0\r\nConnection: close\r\n" };
+    let response = format!("HTTP/1.1 {}\r\n{}{}\r\n", status, headers, body_headers);
+    socket.write_all(response.as_bytes()).await
+}
@@ -0,0 +1,347 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::split::{self, Cidr, Policy, SplitRules};
+
+// --- KILL SWITCH (NFTABLES) ---
+//
+// While the VPN runs, an `inet proteus` table in the output hook decides who
+// may leave through a real interface: loopback, the TUN, the relay carrier and
+// Direct split rules. The carrier is let out by its own address (v4 or v6),
+// port and protocol: TCP, or UDP for a DNS tunnel. Whatever the rules say belongs in the tunnel is dropped,
+// so a dead tunnel fails closed instead of leaking onto the normal network.
+// The ruleset is programmed over netfilter netlink; no `nft` binary needed.
+
+pub const TABLE_NAME: &str = "proteus";
+const CHAIN_NAME: &str = "egress";
+
+// Netlink plumbing (linux/netlink.h, linux/netfilter/nfnetlink.h)
+const NETLINK_NETFILTER: i32 = 12;
+const AF_NETLINK: i32 = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x0001;
+const NLM_F_ACK: u16 = 0x0004;
+const NLM_F_CREATE: u16 = 0x0400;
+const NLM_F_APPEND: u16 = 0x0800;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NLA_F_NESTED: u16 = 0x8000;
+
+// nf_tables messages and attributes (linux/netfilter/nf_tables.h)
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IFNAMSIZ: usize = 16;
+
+/// What the carrier to the relay rides on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    /// A DNS tunnel, to the relay or the resolver in front of it
+    Udp,
+}
+
+/// The installed ruleset. `remove()` takes it back out; if the process dies
+/// instead, the table stays (fail closed) and the next run replaces it.
+pub struct KillSwitch {
+    socket: Socket,
+}
+
+impl KillSwitch {
+    pub fn install(rules: &SplitRules, tun_name: &str, relay: SocketAddr, transport: Transport) -> io::Result<Self> {
+        let socket = Socket::new(Domain::from(AF_NETLINK), Type::RAW, Some(Protocol::from(NETLINK_NETFILTER)))?;
+        let kill_switch = Self { socket };
+
+        // Left over from a run that never got to clean up
+        if kill_switch.delete_table().is_ok() {
+            println!("[KILLSWITCH] Replaced a stale '{}' table.", TABLE_NAME);
+        }
+
+        let mut batch = Batch::new();
+        batch.add(NFT_MSG_NEWTABLE, NLM_F_CREATE, &attr_str(NFTA_TABLE_NAME, TABLE_NAME));
+
+        // Unmatched traffic gets the split default: a full tunnel drops it
+        let policy = if rules.default_policy() == Policy::Direct { NF_ACCEPT } else { NF_DROP };
+        let mut chain = attr_str(NFTA_CHAIN_TABLE, TABLE_NAME);
+        chain.extend(attr_str(NFTA_CHAIN_NAME, CHAIN_NAME));
+        chain.extend(nested(NFTA_CHAIN_HOOK, &[attr_u32(NFTA_HOOK_HOOKNUM, NF_INET_LOCAL_OUT), attr_u32(NFTA_HOOK_PRIORITY, 0)].concat()));
+        chain.extend(attr_u32(NFTA_CHAIN_POLICY, policy));
+        chain.extend(attr_str(NFTA_CHAIN_TYPE, "filter"));
+        batch.add(NFT_MSG_NEWCHAIN, NLM_F_CREATE, &chain);
+
+        // 1. ALWAYS ALLOWED: loopback, the tunnel itself, the carrier to the relay
+        for interface in ["lo", tun_name] {
+            batch.rule(&[oif_is(interface), verdict(NF_ACCEPT)].concat());
+        }
+        let relay_host = match relay.ip() {
+            IpAddr::V4(relay_ip) => ipv4_daddr_in(Cidr::new(relay_ip, 32)),
+            IpAddr::V6(relay_ip) => ipv6_daddr_is(relay_ip.octets()),
+        };
+        let protocol = match transport { Transport::Tcp => IPPROTO_TCP, Transport::Udp => IPPROTO_UDP };
+        batch.rule(&[
+            relay_host,
+            meta_cmp(NFT_META_L4PROTO, &[protocol]),
+            payload_cmp(NFT_PAYLOAD_TRANSPORT_HEADER, 2, &relay.port().to_be_bytes()),
+            verdict(NF_ACCEPT),
+        ].concat());
+
+        // 2. SPLIT RULES: first match wins, so walk them in `decide()` order
+        let mut ordered = rules.rules().to_vec();
+        ordered.sort_by_key(|rule| std::cmp::Reverse((rule.cidr.prefix, split::precedence(rule.policy))));
+        for rule in ordered {
+            let action = if rule.policy == Policy::Direct { NF_ACCEPT } else { NF_DROP };
+            batch.rule(&[ipv4_daddr_in(rule.cidr), verdict(action)].concat());
+        }
+
+        kill_switch.commit(batch)?;
+        println!("[KILLSWITCH] Armed: tunnel traffic can only leave via {}.", tun_name);
+        Ok(kill_switch)
+    }
+
+    pub fn remove(self) {
+        match self.delete_table() {
+            Ok(()) => println!("[KILLSWITCH] Disarmed."),
+            Err(e) => println!("[KILLSWITCH] Failed to remove table '{}': {}", TABLE_NAME, e),
+        }
+    }
+
+    fn delete_table(&self) -> io::Result<()> {
+        let mut batch = Batch::new();
+        batch.add(NFT_MSG_DELTABLE, 0, &attr_str(NFTA_TABLE_NAME, TABLE_NAME));
+        self.commit(batch)
+    }
+
+    /// Send one transaction and wait for an ACK per message: all or nothing
+    fn commit(&self, batch: Batch) -> io::Result<()> {
+        let expected = batch.messages;
+        (&self.socket).write_all(&batch.finish())?;
+
+        let mut buf = vec![0u8; 8192];
+        let mut acked = 0;
+        while acked < expected {
+            let n = (&self.socket).read(&mut buf)?;
+            let mut pos = 0;
+            while pos + 16 <= n {
+                let len = u32::from_ne_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(buf[pos + 4..pos + 6].try_into().unwrap());
+                if len < 16 { break; }
+                if kind == NLMSG_ERROR && pos + 20 <= n {
+                    let errno = i32::from_ne_bytes(buf[pos + 16..pos + 20].try_into().unwrap());
+                    if errno != 0 { return Err(io::Error::from_raw_os_error(-errno)); }
+                    acked += 1;
+                }
+                pos += align(len);
+            }
+        }
+        Ok(())
+    }
+}
+
+// --- RULE EXPRESSIONS ---
+
+fn oif_is(name: &str) -> Vec<u8> {
+    // The kernel compares the whole NUL-padded name
+    let mut padded = [0u8; IFNAMSIZ];
+    let len = name.len().min(IFNAMSIZ - 1);
+    padded[..len].copy_from_slice(&name.as_bytes()[..len]);
+    meta_cmp(NFT_META_OIFNAME, &padded)
+}
+
+/// IPv4 only (the table is `inet`), destination inside `cidr`
+fn ipv4_daddr_in(cidr: Cidr) -> Vec<u8> {
+    let mask = if cidr.prefix == 0 { 0 } else { u32::MAX << (32 - cidr.prefix) };
+    let mut bitwise = attr_u32(NFTA_BITWISE_SREG, NFT_REG_1);
+    bitwise.extend(attr_u32(NFTA_BITWISE_DREG, NFT_REG_1));
+    bitwise.extend(attr_u32(NFTA_BITWISE_LEN, 4));
+    bitwise.extend(data(NFTA_BITWISE_MASK, &mask.to_be_bytes()));
+    bitwise.extend(data(NFTA_BITWISE_XOR, &[0; 4]));
+    [
+        meta_cmp(NFT_META_NFPROTO, &[NFPROTO_IPV4]),
+        payload(NFT_PAYLOAD_NETWORK_HEADER, 16, 4),
+        expr("bitwise", &bitwise),
+        cmp(&cidr.addr.octets()),
+    ].concat()
+}
+
+/// IPv6 only, destination exactly `addr`
+fn ipv6_daddr_is(addr: [u8; 16]) -> Vec<u8> {
+    [
+        meta_cmp(NFT_META_NFPROTO, &[NFPROTO_IPV6]),
+        payload_cmp(NFT_PAYLOAD_NETWORK_HEADER, 24, &addr),
+    ].concat()
+}
+
+fn meta_cmp(key: u32, value: &[u8]) -> Vec<u8> {
+    let mut meta = attr_u32(NFTA_META_DREG, NFT_REG_1);
+    meta.extend(attr_u32(NFTA_META_KEY, key));
+    [expr("meta", &meta), cmp(value)].concat()
+}
+
+fn payload_cmp(base: u32, offset: u32, value: &[u8]) -> Vec<u8> {
+    [payload(base, offset, value.len() as u32), cmp(value)].concat()
+}
+
+fn payload(base: u32, offset: u32, len: u32) -> Vec<u8> {
+    let mut payload = attr_u32(NFTA_PAYLOAD_DREG, NFT_REG_1);
+    payload.extend(attr_u32(NFTA_PAYLOAD_BASE, base));
+    payload.extend(attr_u32(NFTA_PAYLOAD_OFFSET, offset));
+    payload.extend(attr_u32(NFTA_PAYLOAD_LEN, len));
+    expr("payload", &payload)
+}
+
+/// Register 1 equals `value`
+fn cmp(value: &[u8]) -> Vec<u8> {
+    let mut cmp = attr_u32(NFTA_CMP_SREG, NFT_REG_1);
+    cmp.extend(attr_u32(NFTA_CMP_OP, NFT_CMP_EQ));
+    cmp.extend(data(NFTA_CMP_DATA, value));
+    expr("cmp", &cmp)
+}
+
+fn verdict(code: u32) -> Vec<u8> {
+    let verdict = nested(NFTA_DATA_VERDICT, &attr_u32(NFTA_VERDICT_CODE, code));
+    let mut immediate = attr_u32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT);
+    immediate.extend(nested(NFTA_IMMEDIATE_DATA, &verdict));
+    expr("immediate", &immediate)
+}
+
+fn expr(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = attr_str(NFTA_EXPR_NAME, name);
+    body.extend(nested(NFTA_EXPR_DATA, data));
+    nested(NFTA_LIST_ELEM, &body)
+}
+
+fn data(kind: u16, value: &[u8]) -> Vec<u8> {
+    nested(kind, &attr(NFTA_DATA_VALUE, value))
+}
+
+// --- NETLINK ENCODING ---
+
+/// An nf_tables transaction: BATCH_BEGIN, messages, BATCH_END
+struct Batch {
+    bytes: Vec<u8>,
+    messages: usize,
+    seq: u32,
+}
+
+impl Batch {
+    fn new() -> Self {
+        let mut batch = Self { bytes: Vec::new(), messages: 0, seq: 1 };
+        batch.push(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, 0, &[]);
+        batch
+    }
+
+    fn add(&mut self, message: u16, flags: u16, attrs: &[u8]) {
+        self.push((NFNL_SUBSYS_NFTABLES << 8) | message, NLM_F_REQUEST | NLM_F_ACK | flags, NFPROTO_INET, attrs);
+        self.messages += 1;
+    }
+
+    fn rule(&mut self, expressions: &[u8]) {
+        let mut attrs = attr_str(NFTA_RULE_TABLE, TABLE_NAME);
+        attrs.extend(attr_str(NFTA_RULE_CHAIN, CHAIN_NAME));
+        attrs.extend(nested(NFTA_RULE_EXPRESSIONS, expressions));
+        // Without APPEND each rule would go in front of the previous one
+        self.add(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, &attrs);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.push(NFNL_MSG_BATCH_END, NLM_F_REQUEST, 0, &[]);
+        self.bytes
+    }
+
+    fn push(&mut self, kind: u16, flags: u16, family: u8, attrs: &[u8]) {
+        // nlmsghdr (16) + nfgenmsg (4)
+        let len = 16 + 4 + attrs.len();
+        self.bytes.extend((len as u32).to_ne_bytes());
+        self.bytes.extend(kind.to_ne_bytes());
+        self.bytes.extend(flags.to_ne_bytes());
+        self.bytes.extend(self.seq.to_ne_bytes());
+        self.bytes.extend(0u32.to_ne_bytes());
+        self.bytes.push(family);
+        self.bytes.push(0);
+        // Batch markers name the subsystem here; messages leave it zero
+        let res_id = if family == 0 { NFNL_SUBSYS_NFTABLES } else { 0 };
+        self.bytes.extend(res_id.to_be_bytes());
+        self.bytes.extend(attrs);
+        self.seq += 1;
+    }
+}
+
+fn attr(kind: u16, value: &[u8]) -> Vec<u8> {
+    let len = 4 + value.len();
+    let mut bytes = (len as u16).to_ne_bytes().to_vec();
+    bytes.extend(kind.to_ne_bytes());
+    bytes.extend(value);
+    bytes.resize(align(len), 0);
+    bytes
+}
+
+fn nested(kind: u16, value: &[u8]) -> Vec<u8> {
+    attr(kind | NLA_F_NESTED, value)
+}
+
+fn attr_str(kind: u16, value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    attr(kind, &bytes)
+}
+
+/// nf_tables integers travel in network byte order
+fn attr_u32(kind: u16, value: u32) -> Vec<u8> {
+    attr(kind, &value.to_be_bytes())
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
@@ -3,6 +3,16 @@ pub mod framing;
 pub mod client;    
 pub mod transport; 
 pub mod vpn;
+pub mod stream;
+pub mod mux;
+pub mod egress;
+pub mod socks;
+pub mod http_proxy;
+pub mod forward;
+pub mod tun2socks;
+pub mod nat;
+pub mod channel_device;
+pub mod split;
 
 use serde::{Serialize, Deserialize};
 
@@ -28,3 +38,19 @@ pub enum ProteusPacket {
         is_complete: bool,
     }
 }
+pub mod dns;
+pub mod killswitch;
+pub mod resume;
+pub mod migration;
+pub mod multipath;
+pub mod failover;
+pub mod cloak;
+pub mod profile;
+pub mod websocket;
+pub mod tls;
+pub mod dns_tunnel;
+pub mod http2;
+pub mod decoy;
+pub mod shape;
+pub mod auth;
+pub mod pairing;
@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, OsRng, Payload},
+    XChaCha20Poly1305, XNonce
+};
+use rand::Rng;
+
+// --- CONNECTION MIGRATION (UDP) ---
+// A frame carrying a known connection id from a new address is accepted, but
+// replies keep going to the validated address until the new one proves it is
+// really the peer: it must echo a random challenge token, sealed under the
+// session key. A spoofer can copy the connection id but cannot see a token
+// sent to someone else's address, nor seal one without the key, so it cannot
+// redirect a session's return traffic.
+//
+// A bonded sender (see `multipath`) shows up from several addresses at once.
+// Each is validated the same way and stays valid, so ACKs can go back along
+// the path they measure instead of flapping between them.
+
+pub const TOKEN_SIZE: usize = 8;
+const NONCE_SIZE: usize = 24;
+
+// Do not challenge the same address more often than this
+const CHALLENGE_RETRY: Duration = Duration::from_secs(1);
+// Unanswered challenges expire; a newer address then takes the oldest slot
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10);
+const MAX_PENDING: usize = 4;
+// Validated addresses kept per session; the oldest is forgotten first
+const MAX_PATHS: usize = 4;
+
+pub type Token = [u8; TOKEN_SIZE];
+
+/// Where a session's UDP replies may go, and the addresses asking to join.
+/// Empty (the default) for a session that arrived over a stream carrier.
+#[derive(Default)]
+pub struct PeerPath {
+    // Most recently validated last
+    validated: Vec<SocketAddr>,
+    pending: Vec<Challenge>,
+}
+
+struct Challenge {
+    addr: SocketAddr,
+    token: Token,
+    issued: Instant,
+    last_sent: Instant,
+}
+
+impl PeerPath {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { validated: vec![addr], pending: Vec::new() }
+    }
+
+    /// The most recently validated address
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.validated.last().copied()
+    }
+
+    /// Where to answer a frame from `from`: back along its own path once
+    /// that is validated, otherwise to the current one
+    pub fn reply_to(&self, from: SocketAddr) -> Option<SocketAddr> {
+        if self.validated.contains(&from) { Some(from) } else { self.current() }
+    }
+
+    /// A frame arrived from `from`. Returns a token to challenge it with if it
+    /// is an unvalidated address and one is due.
+    pub fn observe(&mut self, from: SocketAddr) -> Option<Token> {
+        if self.validated.contains(&from) { return None; }
+
+        let now = Instant::now();
+        self.pending.retain(|challenge| now.duration_since(challenge.issued) < CHALLENGE_LIFETIME);
+
+        if let Some(challenge) = self.pending.iter_mut().find(|challenge| challenge.addr == from) {
+            if now.duration_since(challenge.last_sent) < CHALLENGE_RETRY { return None; }
+            challenge.last_sent = now;
+            return Some(challenge.token);
+        }
+
+        if self.pending.len() >= MAX_PENDING { self.pending.remove(0); }
+        let token: Token = rand::rng().random();
+        self.pending.push(Challenge { addr: from, token, issued: now, last_sent: now });
+        Some(token)
+    }
+
+    /// Check a PATH_RESPONSE from `from`. If it answers an outstanding
+    /// challenge, `from` becomes the current path.
+    pub fn validate(&mut self, from: SocketAddr, response: &[u8], cipher: &XChaCha20Poly1305, conn_id: u64) -> bool {
+        let Some(token) = open(cipher, conn_id, response) else { return false; };
+        let Some(index) = self.pending.iter().position(|challenge| challenge.addr == from && challenge.token == token) else { return false; };
+        self.pending.remove(index);
+
+        if self.validated.len() >= MAX_PATHS { self.validated.remove(0); }
+        self.validated.push(from);
+        true
+    }
+}
+
+/// Answer a challenge: [nonce][token sealed with the connection id as AAD]
+pub fn respond(cipher: &XChaCha20Poly1305, conn_id: u64, token: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = conn_id.to_be_bytes();
+    let sealed = cipher.encrypt(&nonce, Payload { msg: token, aad: &aad }).unwrap();
+    let mut response = nonce.to_vec();
+    response.extend(sealed);
+    response
+}
+
+fn open(cipher: &XChaCha20Poly1305, conn_id: u64, response: &[u8]) -> Option<Token> {
+    if response.len() < NONCE_SIZE { return None; }
+    let (nonce, sealed) = response.split_at(NONCE_SIZE);
+    let aad = conn_id.to_be_bytes();
+    let token = cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad: &aad }).ok()?;
+    token.try_into().ok()
+}
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{failover::Failover, framing, oracle::NetworkOracle};
+
+// --- MULTIPATH BONDING ---
+// One session, several uplinks (wired + LTE). Rateless symbols make this easy:
+// the receiver needs enough of them, in any order, from any path. So each path
+// keeps its own oracle, and the scheduler hands out symbols in proportion to
+// what each path can carry: the shorter its pacing interval and the lower its
+// loss, the more symbols it gets.
+
+// Floor on a path's interval, however small its RTT looks
+const MIN_INTERVAL: Duration = Duration::from_millis(2);
+// A path that looks dead still gets a trickle, so we notice when it recovers
+const MIN_DELIVERY: f64 = 0.05;
+const MAX_IN_FLIGHT: usize = 1024;
+const REPORT_EVERY: Duration = Duration::from_secs(5);
+
+// Symbols awaiting an ACK, oldest first: (seq, sent at)
+type InFlight = Arc<Mutex<VecDeque<(u32, Instant)>>>;
+
+/// One uplink and what we know about it
+pub struct Path {
+    pub name: String,
+    pub carriers: Failover,
+    pub oracle: Arc<Mutex<NetworkOracle>>,
+    in_flight: InFlight,
+    due: Instant,
+    sent: u64,
+}
+
+/// Feeds a path's ACKs back into its oracle (held by its carriers' reply
+/// readers, whichever carrier brings them)
+#[derive(Clone, Default)]
+pub struct AckTracker {
+    oracle: Arc<Mutex<NetworkOracle>>,
+    in_flight: InFlight,
+}
+
+impl Path {
+    /// A path whose ACKs arrive through `acks`
+    pub fn new(name: String, carriers: Failover, acks: &AckTracker) -> Self {
+        Self {
+            name,
+            carriers,
+            oracle: acks.oracle.clone(),
+            in_flight: acks.in_flight.clone(),
+            due: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8], seq: u32) -> std::io::Result<()> {
+        // Record it first: on a short path the ACK can beat us back
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.len() >= MAX_IN_FLIGHT { in_flight.pop_front(); }
+            in_flight.push_back((seq, Instant::now()));
+        }
+        self.sent += 1;
+        self.carriers.send(data)
+    }
+
+    /// Time between symbols on this path: its pacing, stretched by its loss
+    fn interval(&self) -> Duration {
+        let brain = self.oracle.lock().unwrap();
+        let delivery = (1.0 - brain.loss_rate).max(MIN_DELIVERY);
+        brain.get_pacing_interval().max(MIN_INTERVAL).div_f64(delivery)
+    }
+
+    /// Symbols unACKed for longer than the RTO count as lost
+    fn expire(&self) {
+        let mut brain = self.oracle.lock().unwrap();
+        let deadline = brain.get_retransmit_timeout();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while in_flight.front().is_some_and(|(_, sent)| sent.elapsed() > deadline) {
+            in_flight.pop_front();
+            brain.record_loss();
+        }
+    }
+}
+
+impl AckTracker {
+    pub fn on_ack(&self, ack: &framing::AckPacket) {
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            // An ACK for a symbol another path carried, or one already
+            // written off, says nothing about this path's RTT
+            let Some(index) = in_flight.iter().position(|(seq, _)| *seq == ack.seq_id) else { return; };
+            in_flight.remove(index);
+        }
+        let rtt = Duration::from_micros(framing::now_micros().saturating_sub(ack.timestamp));
+        self.oracle.lock().unwrap().update_rtt(rtt);
+    }
+}
+
+/// Earliest-deadline scheduler over the paths of one session
+pub struct Scheduler {
+    paths: Vec<Path>,
+    last_report: Instant,
+}
+
+impl Scheduler {
+    pub fn new(paths: Vec<Path>) -> Self {
+        assert!(!paths.is_empty(), "a session needs at least one path");
+        Self { paths, last_report: Instant::now() }
+    }
+
+    /// Wait until some path is due for a symbol and return it
+    pub fn next_path(&mut self) -> &mut Path {
+        if self.paths.len() > 1 && self.last_report.elapsed() >= REPORT_EVERY {
+            self.report();
+            self.last_report = Instant::now();
+        }
+
+        for path in &self.paths { path.expire(); }
+        let index = (0..self.paths.len()).min_by_key(|&i| self.paths[i].due).unwrap();
+        let path = &mut self.paths[index];
+
+        let now = Instant::now();
+        if path.due > now { thread::sleep(path.due - now); }
+        path.due = Instant::now() + path.interval();
+        path
+    }
+
+    fn report(&self) {
+        let summary: Vec<String> = self.paths.iter().map(|path| {
+            let brain = path.oracle.lock().unwrap();
+            format!("{}: {} sent, rtt {:?}, loss {:.2}", path.name, path.sent, brain.smoothed_rtt, brain.loss_rate)
+        }).collect();
+        println!("[MULTIPATH] {}", summary.join(" | "));
+    }
+}
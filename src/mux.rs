@@ -0,0 +1,377 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+// --- MUX CONFIGURATION ---
+
+/// Bytes a stream may have in flight before the receiver grants more credit.
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Largest payload carried by a single DATA frame.
+pub const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+/// Streams a carrier holds open at once. OPENs past this are reset, so a
+/// peer cannot pile up stream state (and its windows) without bound.
+pub const MAX_STREAMS: usize = 1024;
+
+// Kind + stream id + payload length.
+const MUX_HEADER_SIZE: usize = 9;
+
+// --- FRAME KINDS ---
+pub const MUX_OPEN: u8 = 1;   // payload: opaque target description
+pub const MUX_DATA: u8 = 2;   // payload: stream bytes
+pub const MUX_CLOSE: u8 = 3;  // sender will write no more (half-close)
+pub const MUX_RESET: u8 = 4;  // abort the stream in both directions
+pub const MUX_WINDOW: u8 = 5; // payload: u32 credit increment
+
+/// Which end of the carrier we are. Decides stream id parity so both
+/// sides can open streams without colliding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client, // odd ids
+    Server, // even ids
+}
+
+#[derive(Debug)]
+struct MuxFrame {
+    kind: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl MuxFrame {
+    fn new(kind: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self { kind, stream_id, payload }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MUX_HEADER_SIZE + self.payload.len());
+        bytes.push(self.kind);
+        bytes.extend_from_slice(&self.stream_id.to_be_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Per-stream state shared between the `MuxStream` handle and the reader task
+struct StreamState {
+    // RECEIVE SIDE
+    recv: VecDeque<u8>,
+    consumed: u32,
+    remote_eof: bool,
+    read_waker: Option<Waker>,
+
+    // SEND SIDE
+    credit: u32,
+    local_closed: bool,
+    write_waker: Option<Waker>,
+
+    reset: bool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            recv: VecDeque::new(),
+            consumed: 0,
+            remote_eof: false,
+            read_waker: None,
+            credit: STREAM_WINDOW,
+            local_closed: false,
+            write_waker: None,
+            reset: false,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(w) = self.read_waker.take() { w.wake(); }
+        if let Some(w) = self.write_waker.take() { w.wake(); }
+    }
+}
+
+struct Shared {
+    side: Side,
+    out: mpsc::UnboundedSender<MuxFrame>,
+    streams: Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>,
+    next_id: Mutex<u32>,
+    // Set (under the `streams` lock) once the carrier is gone
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn send(&self, kind: u8, stream_id: u32, payload: Vec<u8>) -> io::Result<()> {
+        self.out.send(MuxFrame::new(kind, stream_id, payload))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux carrier closed"))
+    }
+
+    fn is_local(&self, stream_id: u32) -> bool {
+        (stream_id % 2 == 1) == (self.side == Side::Client)
+    }
+}
+
+/// Handle used to open new streams on a multiplexed carrier. Cheap to clone.
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+/// Streams opened by the peer, in arrival order
+pub struct Incoming {
+    rx: mpsc::UnboundedReceiver<MuxStream>,
+}
+
+impl Incoming {
+    /// Wait for the next peer-opened stream. `None` once the carrier is gone.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.rx.recv().await
+    }
+}
+
+impl Mux {
+    /// Start multiplexing over a reliable carrier (typically a `ProteusStream`).
+    /// Must be called inside a Tokio runtime.
+    pub fn new<T>(carrier: T, side: Side) -> (Mux, Incoming)
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(carrier);
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+        let shared = Arc::new(Shared {
+            side,
+            out: out_tx,
+            streams: Mutex::new(HashMap::new()),
+            next_id: Mutex::new(if side == Side::Client { 1 } else { 2 }),
+            closed: AtomicBool::new(false),
+        });
+
+        tokio::spawn(write_frames(writer, out_rx));
+        tokio::spawn(read_frames(reader, shared.clone(), incoming_tx));
+
+        (Mux { shared }, Incoming { rx: incoming_rx })
+    }
+
+    /// Open a stream. `target` is handed verbatim to the peer's `MuxStream::target`.
+    pub fn open(&self, target: &[u8]) -> io::Result<MuxStream> {
+        let stream_id = {
+            let mut next = self.shared.next_id.lock().unwrap();
+            let id = *next;
+            *next = next.checked_add(2)
+                .ok_or_else(|| io::Error::other("mux stream ids exhausted"))?;
+            id
+        };
+
+        let state = Arc::new(Mutex::new(StreamState::new()));
+        {
+            let mut streams = self.shared.streams.lock().unwrap();
+            // Nobody would ever answer: fail now instead of waiting forever
+            if self.shared.closed.load(Ordering::Acquire) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux carrier closed"));
+            }
+            streams.insert(stream_id, state.clone());
+        }
+        self.shared.send(MUX_OPEN, stream_id, target.to_vec())?;
+
+        Ok(MuxStream { stream_id, target: target.to_vec(), state, shared: self.shared.clone() })
+    }
+}
+
+/// Drain queued frames onto the carrier, flushing whenever the queue runs dry
+async fn write_frames<W: AsyncWrite + Unpin>(mut writer: W, mut out: mpsc::UnboundedReceiver<MuxFrame>) {
+    while let Some(frame) = out.recv().await {
+        if writer.write_all(&frame.to_bytes()).await.is_err() { return; }
+        if out.is_empty() && writer.flush().await.is_err() { return; }
+    }
+    writer.shutdown().await.ok();
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(mut reader: R, shared: Arc<Shared>, incoming: mpsc::UnboundedSender<MuxStream>) {
+    let mut header = [0u8; MUX_HEADER_SIZE];
+
+    loop {
+        if reader.read_exact(&mut header).await.is_err() { break; }
+        let kind = header[0];
+        let stream_id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+
+        // Nothing legitimate is bigger than a window; anything else is a broken peer
+        if len > STREAM_WINDOW as usize {
+            println!("[MUX] Oversized frame ({} bytes). Dropping carrier.", len);
+            break;
+        }
+        let mut payload = vec![0u8; len];
+        if reader.read_exact(&mut payload).await.is_err() { break; }
+
+        let state = shared.streams.lock().unwrap().get(&stream_id).cloned();
+
+        match (kind, state) {
+            (MUX_OPEN, None) if !shared.is_local(stream_id) => {
+                let state = Arc::new(Mutex::new(StreamState::new()));
+                {
+                    let mut streams = shared.streams.lock().unwrap();
+                    if streams.len() >= MAX_STREAMS {
+                        drop(streams);
+                        println!("[MUX] {} streams open. Refusing another.", MAX_STREAMS);
+                        shared.send(MUX_RESET, stream_id, Vec::new()).ok();
+                        continue;
+                    }
+                    streams.insert(stream_id, state.clone());
+                }
+                let stream = MuxStream { stream_id, target: payload, state, shared: shared.clone() };
+                // Nobody accepting: the stream drops here and resets itself
+                incoming.send(stream).ok();
+            }
+            (MUX_DATA, Some(state)) => {
+                let mut st = state.lock().unwrap();
+                if st.recv.len() + payload.len() > STREAM_WINDOW as usize {
+                    // Peer ignored our window
+                    st.reset = true;
+                    st.wake();
+                    drop(st);
+                    shared.streams.lock().unwrap().remove(&stream_id);
+                    shared.send(MUX_RESET, stream_id, Vec::new()).ok();
+                    continue;
+                }
+                st.recv.extend(payload);
+                if let Some(w) = st.read_waker.take() { w.wake(); }
+            }
+            (MUX_CLOSE, Some(state)) => {
+                let mut st = state.lock().unwrap();
+                st.remote_eof = true;
+                if let Some(w) = st.read_waker.take() { w.wake(); }
+            }
+            (MUX_RESET, Some(state)) => {
+                shared.streams.lock().unwrap().remove(&stream_id);
+                let mut st = state.lock().unwrap();
+                st.reset = true;
+                st.wake();
+            }
+            (MUX_WINDOW, Some(state)) => {
+                let Ok(bytes) = <[u8; 4]>::try_from(payload.as_slice()) else { continue; };
+                let mut st = state.lock().unwrap();
+                st.credit = st.credit.saturating_add(u32::from_be_bytes(bytes));
+                if let Some(w) = st.write_waker.take() { w.wake(); }
+            }
+            // Late credit or half-close for a stream we already finished and dropped
+            (MUX_RESET | MUX_WINDOW | MUX_CLOSE, None) => {}
+            (_, _) => {
+                // Data for an unknown stream, duplicate OPEN or unknown kind
+                shared.send(MUX_RESET, stream_id, Vec::new()).ok();
+            }
+        }
+    }
+
+    // Carrier gone: every live stream is dead
+    println!("[MUX] Carrier closed.");
+    let streams: Vec<_> = {
+        let mut streams = shared.streams.lock().unwrap();
+        shared.closed.store(true, Ordering::Release);
+        streams.drain().map(|(_, s)| s).collect()
+    };
+    for state in streams {
+        let mut st = state.lock().unwrap();
+        st.reset = true;
+        st.wake();
+    }
+}
+
+/// One logical flow inside a multiplexed carrier.
+///
+/// Dropping the handle before both directions have finished resets the stream.
+pub struct MuxStream {
+    stream_id: u32,
+    target: Vec<u8>,
+    state: Arc<Mutex<StreamState>>,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// What the opener asked for (e.g. a destination address)
+    pub fn target(&self) -> &[u8] {
+        &self.target
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut st = self.state.lock().unwrap();
+
+        if st.recv.is_empty() {
+            // A stream the peer finished cleanly reads to its end, whatever came after
+            if st.remote_eof { return Poll::Ready(Ok(())); }
+            if st.reset { return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())); }
+            st.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.remaining().min(st.recv.len());
+        let (front, back) = st.recv.as_slices();
+        let from_front = n.min(front.len());
+        buf.put_slice(&front[..from_front]);
+        buf.put_slice(&back[..n - from_front]);
+        st.recv.drain(..n);
+
+        // Hand credit back once half the window has been consumed
+        st.consumed += n as u32;
+        if st.consumed >= STREAM_WINDOW / 2 && !st.reset {
+            let grant = std::mem::take(&mut st.consumed);
+            self.shared.send(MUX_WINDOW, self.stream_id, grant.to_be_bytes().to_vec()).ok();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut st = self.state.lock().unwrap();
+
+        if st.reset { return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())); }
+        if st.local_closed { return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())); }
+        if buf.is_empty() { return Poll::Ready(Ok(0)); }
+        if st.credit == 0 {
+            st.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(st.credit as usize).min(MAX_FRAME_PAYLOAD);
+        self.shared.send(MUX_DATA, self.stream_id, buf[..n].to_vec())?;
+        st.credit -= n as u32;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are queued in order; the writer task flushes the carrier
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut st = self.state.lock().unwrap();
+        if !st.local_closed && !st.reset {
+            st.local_closed = true;
+            self.shared.send(MUX_CLOSE, self.stream_id, Vec::new())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().remove(&self.stream_id);
+        let st = self.state.lock().unwrap();
+        let finished = st.reset || (st.local_closed && st.remote_eof);
+        if !finished {
+            self.shared.send(MUX_RESET, self.stream_id, Vec::new()).ok();
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, mpsc};
+use crate::egress::Policy;
+
+// --- USERSPACE NAT (GATEWAY EGRESS) ---
+//
+// The relay terminates each client flow in its own stack (see `tun2socks`)
+// and re-originates it from an ordinary socket. The kernel then picks the
+// public source port and routes the reply back to that socket, so no
+// iptables MASQUERADE rule is needed. Flow tables and idle timeouts live in
+// `NetStack`; this module holds the pieces that talk to the outside.
+
+/// Echo requests are forgotten after this long without traffic
+pub const ICMP_IDLE: Duration = Duration::from_secs(30);
+
+// ICMP has no ports: the echo identifier is what we allocate instead
+static NEXT_ECHO_ID: AtomicU16 = AtomicU16::new(1);
+
+/// UDP: one socket per client source. Every remote it talks to shares the
+/// same public port, which keeps endpoint-independent mapping (games, STUN).
+pub async fn relay_udp(local: SocketAddr, mut uplink: mpsc::Receiver<(SocketAddr, Vec<u8>)>, downlink: mpsc::Sender<(SocketAddr, SocketAddr, Vec<u8>)>, notify: Arc<Notify>, policy: Arc<Policy>) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("[NAT] UDP from {} -> {}", local, e);
+            return;
+        }
+    };
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        tokio::select! {
+            datagram = uplink.recv() => match datagram {
+                Some((remote, payload)) => {
+                    if policy.check(remote).is_ok() { socket.send_to(&payload, remote).await.ok(); }
+                }
+                // The stack expired the mapping
+                None => break,
+            },
+            received = socket.recv_from(&mut buf) => {
+                let (n, remote) = match received {
+                    Ok(received) => received,
+                    Err(e) if transient(&e) => continue,
+                    Err(e) => {
+                        println!("[NAT] UDP from {} -> {}", local, e);
+                        break;
+                    }
+                };
+                if downlink.send((local, remote, buf[..n].to_vec())).await.is_err() { break; }
+                notify.notify_one();
+            }
+        }
+    }
+}
+
+/// ICMP echo: rewrite the identifier on the way out, restore it on the way
+/// back, and hand the stack a ready-made reply packet for the client.
+pub async fn relay_icmp(client: Ipv4Addr, remote: Ipv4Addr, ident: u16, mut uplink: mpsc::Receiver<(u16, Vec<u8>)>, downlink: mpsc::Sender<Vec<u8>>, notify: Arc<Notify>) {
+    let socket = match echo_socket(remote) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("[NAT] ICMP to {} -> {} (raw sockets need root)", remote, e);
+            return;
+        }
+    };
+    let public_ident = NEXT_ECHO_ID.fetch_add(1, Ordering::Relaxed);
+    let checksums = ChecksumCapabilities::default();
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        tokio::select! {
+            request = uplink.recv() => match request {
+                Some((seq_no, data)) => {
+                    let repr = Icmpv4Repr::EchoRequest { ident: public_ident, seq_no, data: &data };
+                    let mut bytes = vec![0u8; repr.buffer_len()];
+                    repr.emit(&mut Icmpv4Packet::new_unchecked(&mut bytes[..]), &checksums);
+                    socket.send(&bytes).await.ok();
+                }
+                None => break,
+            },
+            received = socket.recv(&mut buf) => {
+                // Raw sockets see every ICMP packet from `remote`: keep only our replies
+                let n = match received {
+                    Ok(n) => n,
+                    Err(e) if transient(&e) => continue,
+                    Err(e) => {
+                        println!("[NAT] ICMP to {} -> {}", remote, e);
+                        break;
+                    }
+                };
+                let Ok(ip) = Ipv4Packet::new_checked(&buf[..n]) else { continue; };
+                let Ok(icmp) = Icmpv4Packet::new_checked(ip.payload()) else { continue; };
+                let Ok(Icmpv4Repr::EchoReply { ident: reply_ident, seq_no, data }) = Icmpv4Repr::parse(&icmp, &checksums) else { continue; };
+                if reply_ident != public_ident { continue; }
+
+                let reply = Icmpv4Repr::EchoReply { ident, seq_no, data };
+                let header = Ipv4Repr {
+                    src_addr: remote,
+                    dst_addr: client,
+                    next_header: IpProtocol::Icmp,
+                    payload_len: reply.buffer_len(),
+                    hop_limit: 64,
+                };
+                let mut packet = vec![0u8; header.buffer_len() + reply.buffer_len()];
+                let mut ip = Ipv4Packet::new_unchecked(&mut packet[..]);
+                header.emit(&mut ip, &checksums);
+                reply.emit(&mut Icmpv4Packet::new_unchecked(ip.payload_mut()), &checksums);
+
+                if downlink.send(packet).await.is_err() { break; }
+                notify.notify_one();
+            }
+        }
+    }
+}
+
+/// Errors a datagram socket reports and then carries on from: an ICMP error
+/// for something sent earlier, or an interrupted call. Anything else would
+/// come back on every `recv`, so the relay loop gives up instead.
+fn transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// A raw ICMP socket connected to `remote`, so the kernel only hands us its packets
+fn echo_socket(remote: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+    socket.set_nonblocking(true)?;
+    socket.connect(&SocketAddr::V4(SocketAddrV4::new(remote, 0)).into())?;
+    // tokio has no raw socket type; send/recv on a datagram fd is all we need
+    UdpSocket::from_std(std::net::UdpSocket::from(socket))
+}
@@ -1,12 +1,20 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 const ALPHA: f64 = 0.125; // EWMA Smoothing Factor
+const BETA: f64 = 0.25;   // RTT Variance Smoothing Factor
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
 
 pub struct NetworkOracle {
     pub smoothed_rtt: Duration, // FIXED: Made Public
     pub rtt_var: Duration,      // FIXED: Made Public
     pub loss_rate: f64,         // FIXED: Made Public
-    last_sent: Instant,
+}
+
+impl Default for NetworkOracle {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkOracle {
@@ -15,7 +23,6 @@ impl NetworkOracle {
             smoothed_rtt: Duration::from_millis(100), // Default start
             rtt_var: Duration::from_millis(0),
             loss_rate: 0.0,
-            last_sent: Instant::now(),
         }
     }
 
@@ -24,6 +31,10 @@ impl NetworkOracle {
         // Standard TCP RTT Estimator (Jacobson's Algorithm)
         let rtt_float = rtt.as_secs_f64();
         let srtt_float = self.smoothed_rtt.as_secs_f64();
+
+        // Variance first, so it measures deviation from the previous estimate
+        let var_float = (1.0 - BETA) * self.rtt_var.as_secs_f64() + BETA * (srtt_float - rtt_float).abs();
+        self.rtt_var = Duration::from_secs_f64(var_float);
         
         // EWMA Calculation
         let new_srtt = (1.0 - ALPHA) * srtt_float + ALPHA * rtt_float;
@@ -43,4 +54,14 @@ impl NetworkOracle {
         // This prevents flooding a slow network.
         self.smoothed_rtt.div_f64(10.0) // Send at 10% of RTT interval
     }
+
+    /// A repair timer fired without an ACK: treat it as evidence of loss
+    pub fn record_loss(&mut self) {
+        self.loss_rate = (self.loss_rate + 0.1).min(1.0);
+    }
+
+    /// How long to wait for an ACK before sending repair symbols (RFC 6298 style)
+    pub fn get_retransmit_timeout(&self) -> Duration {
+        (self.smoothed_rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO)
+    }
 }
\ No newline at end of file
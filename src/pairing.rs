@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use crate::cloak::{Cloak, Halves, Unwrapped};
+
+// --- REQUEST/RESPONSE PAIRING ---
+// An HTTP/1.1 server answers every request with exactly one response and
+// never speaks unasked. Carriers whose cloak looks like that (see
+// `Cloak::paired`) run through a pair of pumps on each side that keep it so.
+// The relay sends a message only in answer to a request still waiting for
+// one, holding its frames until the next request comes, and answers a
+// request it has had nothing for in `HOLD` with an empty response. The
+// client keeps requests waiting at the relay, polls that carry no frame
+// when it has none to send: more of them while answers come back full, just
+// one once they come back empty. The stream sees the same messages either
+// way; the empty ones carry no frame and are skipped.
+
+// How long the relay keeps a request waiting for something to answer it with
+const HOLD: Duration = Duration::from_secs(20);
+// Requests the relay keeps waiting at most; past that the oldest gets an empty answer
+const MAX_HELD: usize = 32;
+// Polls the client keeps waiting at most
+const MAX_POLLS: usize = 32;
+const PIPE_BUFFER: usize = 256 * 1024;
+
+// --- CLIENT SIDE ---
+
+/// Run the client's end of a carrier in `cloak` (already connected, nothing
+/// sent yet) through the pumps
+pub fn client<R, W>(socket_reader: R, socket_writer: W, cloak: Arc<dyn Cloak>) -> Halves
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (answers_tx, answers) = mpsc::unbounded_channel();
+    let (requests_tx, requests) = mpsc::channel(64);
+    tokio::spawn(incoming(socket_reader, pipe_writer, cloak.clone(), answers_tx));
+    tokio::spawn(read_messages(pipe_reader, cloak.clone(), requests_tx));
+    tokio::spawn(client_outgoing(socket_writer, requests, answers, cloak));
+    tokio::io::split(theirs)
+}
+
+/// The stream's requests onto the socket, topped up with polls whenever an
+/// answer leaves fewer waiting than the downlink seems to need
+async fn client_outgoing<W: AsyncWrite + Unpin>(mut socket: W, mut requests: mpsc::Receiver<Vec<u8>>, mut answers: mpsc::UnboundedReceiver<bool>, cloak: Arc<dyn Cloak>) {
+    let mut waiting = 0usize;
+    let mut wanted = 1usize;
+    loop {
+        let result = tokio::select! {
+            request = requests.recv() => match request {
+                Some(request) => {
+                    waiting += 1;
+                    socket.write_all(&request).await
+                }
+                None => break,
+            },
+            answer = answers.recv() => match answer {
+                Some(full) => {
+                    waiting = waiting.saturating_sub(1);
+                    wanted = if full { (wanted + 1).min(MAX_POLLS) } else { 1 };
+                    let polls = wanted.saturating_sub(waiting);
+                    waiting += polls;
+                    socket.write_all(&cloak.wrap(&[], 0).repeat(polls)).await
+                }
+                // The relay hung up
+                None => break,
+            },
+        };
+        if result.is_err() { break; }
+    }
+    socket.shutdown().await.ok();
+}
+
+// --- RELAY SIDE ---
+
+/// Run the relay's end of a carrier in `cloak` through the pumps. `asked`
+/// requests came in before the pumps did (the handshake) and are owed answers.
+pub fn relay<R, W>(socket_reader: R, socket_writer: W, cloak: Arc<dyn Cloak>, asked: usize) -> Halves
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (asks_tx, asks) = mpsc::unbounded_channel();
+    let (replies_tx, replies) = mpsc::channel(64);
+    tokio::spawn(incoming(socket_reader, pipe_writer, cloak.clone(), asks_tx));
+    tokio::spawn(read_messages(pipe_reader, cloak.clone(), replies_tx));
+    tokio::spawn(relay_outgoing(socket_writer, replies, asks, cloak, asked));
+    tokio::io::split(theirs)
+}
+
+/// The stream's replies onto the socket, one per request waiting; a request
+/// left waiting too long (or too many of them) gets an empty one
+async fn relay_outgoing<W: AsyncWrite + Unpin>(mut socket: W, mut replies: mpsc::Receiver<Vec<u8>>, mut asks: mpsc::UnboundedReceiver<bool>, cloak: Arc<dyn Cloak>, asked: usize) {
+    let mut waiting: VecDeque<Instant> = std::iter::repeat_n(Instant::now(), asked).collect();
+    loop {
+        while waiting.len() > MAX_HELD {
+            waiting.pop_front();
+            if socket.write_all(&cloak.wrap_reply(&[], 0)).await.is_err() { return; }
+        }
+        let oldest = waiting.front().copied();
+        let result = tokio::select! {
+            ask = asks.recv() => match ask {
+                Some(_) => {
+                    waiting.push_back(Instant::now());
+                    Ok(())
+                }
+                // The client hung up: nobody is left to answer
+                None => break,
+            },
+            reply = replies.recv(), if oldest.is_some() => match reply {
+                Some(reply) => {
+                    waiting.pop_front();
+                    socket.write_all(&reply).await
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(oldest.unwrap_or_else(Instant::now) + HOLD), if oldest.is_some() => {
+                waiting.pop_front();
+                socket.write_all(&cloak.wrap_reply(&[], 0)).await
+            }
+        };
+        if result.is_err() { break; }
+    }
+    socket.shutdown().await.ok();
+}
+
+// --- PUMPS ---
+
+/// Messages off the socket into the pipe, reporting each (and whether it
+/// carried a frame) as it goes by
+async fn incoming<R: AsyncRead + Unpin>(mut socket: R, mut pipe: WriteHalf<DuplexStream>, cloak: Arc<dyn Cloak>, seen: mpsc::UnboundedSender<bool>) {
+    let mut buf = Vec::new();
+    while let Ok(Some((message, full))) = next_message(&mut socket, &mut buf, cloak.as_ref()).await {
+        if pipe.write_all(&message).await.is_err() || seen.send(full).is_err() { break; }
+    }
+    pipe.shutdown().await.ok();
+}
+
+/// The stream's messages out of the pipe, one at a time (a separate task:
+/// the outgoing pump must not be cancelled halfway through reading one)
+async fn read_messages(mut pipe: ReadHalf<DuplexStream>, cloak: Arc<dyn Cloak>, messages: mpsc::Sender<Vec<u8>>) {
+    let mut buf = Vec::new();
+    while let Ok(Some((message, _))) = next_message(&mut pipe, &mut buf, cloak.as_ref()).await {
+        if messages.send(message).await.is_err() { break; }
+    }
+}
+
+/// The next whole message in `cloak` off `reader`, and whether it carried a frame
+async fn next_message<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>, cloak: &dyn Cloak) -> io::Result<Option<(Vec<u8>, bool)>> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match cloak.unwrap(buf) {
+            Unwrapped::Message { len, frame } => return Ok(Some((buf.drain(..len).collect(), frame.is_some()))),
+            Unwrapped::Invalid => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("not {} traffic", cloak.name()))),
+            Unwrapped::Incomplete => {}
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 { return Ok(None); }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// How many messages in `cloak` make up `bytes`
+pub fn count(bytes: &[u8], cloak: &dyn Cloak) -> usize {
+    let mut count = 0;
+    let mut rest = bytes;
+    while let Unwrapped::Message { len, .. } = cloak.unwrap(rest) {
+        rest = &rest[len..];
+        count += 1;
+    }
+    count
+}
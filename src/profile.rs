@@ -0,0 +1,427 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use base64::{Engine as _, engine::general_purpose};
+use data_encoding::{BASE32_NOPAD, HEXLOWER_PERMISSIVE};
+use rand::Rng;
+use serde::Deserialize;
+use crate::SYMBOL_SIZE;
+use crate::cloak::{self, Cloak, HttpMessage, Parsed, StartLine, Unwrapped};
+use crate::shape::Histogram;
+
+// --- TRAFFIC PROFILES ---
+// A profile is an HTTP/1.1 cloak written in TOML instead of Rust: the
+// requests a client sends, the responses the relay answers with, and where
+// in each the frame travels. A frame is cut across the payload slots in
+// order, each slot encoded its own way, so operators can dress traffic up as
+// whatever service suits their network without rebuilding. Paths, header and
+// cookie values may use the placeholders {seq}, {rand} and {date}. Each
+// direction may also bring a histogram of on-wire lengths (see `shape`).
+// Messages are read back with the same strict parser as the `http` cloak.
+
+/// Most frame bytes a cloak must fit in one message (a datagram or a stream
+/// frame, with room to spare)
+pub const MAX_FRAME: usize = 64 + SYMBOL_SIZE as usize;
+
+const PLACEHOLDERS: [&str; 3] = ["{seq}", "{rand}", "{date}"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    name: String,
+    /// Largest message accepted from the peer
+    #[serde(default = "default_max_message")]
+    max_message: usize,
+    request: RequestTemplate,
+    response: ResponseTemplate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RequestTemplate {
+    #[serde(default = "default_method")]
+    method: String,
+    /// One is picked at random per request; may carry a fixed query string
+    paths: Vec<String>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    cookies: Vec<(String, String)>,
+    payload: Vec<Slot>,
+    /// On-wire lengths to shape requests to (see `shape`)
+    lengths: Option<Histogram>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResponseTemplate {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default = "default_reason")]
+    reason: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    payload: Vec<Slot>,
+    lengths: Option<Histogram>,
+}
+
+/// Where a piece of the frame goes, and how it is written there
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Slot {
+    #[serde(rename = "in")]
+    place: Place,
+    /// Query parameter, header or cookie name (not used for the body)
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    encoding: Encoding,
+    /// Frame bytes this slot takes at most; only the last slot may omit it
+    max: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Place {
+    Query,
+    Header,
+    Cookie,
+    Body,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Raw,
+    Base64,
+    #[default]
+    Base64url,
+    Base32,
+    Hex,
+}
+
+fn default_max_message() -> usize { cloak::MAX_MESSAGE }
+fn default_method() -> String { "POST".to_string() }
+fn default_status() -> u16 { 200 }
+fn default_reason() -> String { "OK".to_string() }
+
+impl Profile {
+    /// Read a profile and refuse it unless it checks out
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let profile = Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        let problems = profile.problems();
+        if !problems.is_empty() {
+            let message = format!("{}: {}", path.display(), problems.join("; "));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(profile)
+    }
+
+    /// Syntax only; see `problems` for the rest
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string().trim_end().to_string())
+    }
+
+    /// Everything that would make this profile misbehave on the wire
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.is_empty() || !self.name.bytes().all(cloak::is_token) {
+            problems.push(format!("name '{}' must be a non-empty token", self.name));
+        }
+        if self.max_message < MAX_FRAME || self.max_message > cloak::MAX_MESSAGE {
+            problems.push(format!("max_message must be between {} and {}", MAX_FRAME, cloak::MAX_MESSAGE));
+        }
+
+        let request = &self.request;
+        if request.method.is_empty() || !request.method.bytes().all(|b| b.is_ascii_uppercase()) {
+            problems.push(format!("request method '{}' must be upper-case letters", request.method));
+        }
+        if request.paths.is_empty() { problems.push("request needs at least one path".to_string()); }
+        for path in &request.paths {
+            if !path.starts_with('/') || !path.bytes().all(|b| b.is_ascii_graphic()) || path.contains('#') {
+                problems.push(format!("path '{}' must start with '/' and hold no spaces, controls or '#'", path));
+            }
+            check_placeholders(path, &mut problems);
+            if path.contains("{date}") { problems.push(format!("path '{}' cannot hold {{date}}", path)); }
+        }
+        for (name, value) in &request.cookies {
+            if name.is_empty() || !name.bytes().all(cloak::is_token) {
+                problems.push(format!("cookie name '{}' must be a token", name));
+            }
+            if value.bytes().any(|b| !b.is_ascii_graphic() || b"\",;\\".contains(&b)) {
+                problems.push(format!("cookie '{}' has a value no browser would send", name));
+            }
+            check_placeholders(value, &mut problems);
+        }
+        check_headers("request", &request.headers, &mut problems);
+        if request.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("cookie")) {
+            problems.push("request cookies go under `cookies`, not in a Cookie header".to_string());
+        }
+        check_slots("request", &request.payload, &[Place::Query, Place::Header, Place::Cookie, Place::Body], &mut problems);
+
+        let response = &self.response;
+        if !(100..=599).contains(&response.status) {
+            problems.push(format!("response status {} is not an HTTP status", response.status));
+        }
+        if !response.reason.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+            problems.push("response reason must be printable".to_string());
+        }
+        check_headers("response", &response.headers, &mut problems);
+        check_slots("response", &response.payload, &[Place::Header, Place::Body], &mut problems);
+        for (direction, lengths) in [("request", &request.lengths), ("response", &response.lengths)] {
+            if let Some(lengths) = lengths && lengths.longest() > self.max_message {
+                problems.push(format!("{} lengths run to {} bytes, over max_message", direction, lengths.longest()));
+            }
+        }
+
+        // Only a profile that is otherwise sound can be taken for a test drive
+        if problems.is_empty() { self.round_trip(&mut problems); }
+        problems
+    }
+
+    /// Push a largest-size frame through both directions and read it back
+    fn round_trip(&self, problems: &mut Vec<String>) {
+        let frame: Vec<u8> = (0..MAX_FRAME).map(|_| rand::rng().random()).collect();
+        for (direction, message) in [("request", self.wrap(&frame, u32::MAX)), ("response", self.wrap_reply(&frame, u32::MAX))] {
+            if message.len() > self.max_message {
+                problems.push(format!("a full-size {} is {} bytes, over max_message", direction, message.len()));
+                continue;
+            }
+            match self.unwrap(&message) {
+                Unwrapped::Message { len, frame: Some(back) } if len == message.len() && back == frame => {}
+                Unwrapped::Invalid => problems.push(format!("a full-size {} does not parse as HTTP/1.1 (headers over {} bytes?)", direction, cloak::MAX_HEAD)),
+                _ => problems.push(format!("a full-size {} does not give its frame back", direction)),
+            }
+        }
+    }
+
+    /// Read the frame back out of the slots of a parsed message
+    fn gather(&self, slots: &[Slot], message: &HttpMessage, target: &str) -> Option<Vec<u8>> {
+        let mut frame = Vec::new();
+        for slot in slots {
+            let value = match slot.place {
+                Place::Query => target.split_once('?')
+                    .and_then(|(_, query)| query.split('&').find_map(|param| param.strip_prefix(slot.name.as_str())?.strip_prefix('=')))?
+                    .as_bytes(),
+                Place::Header => message.header(&slot.name)?.as_bytes(),
+                Place::Cookie => message.header("cookie")
+                    .and_then(|cookies| cookies.split(';').find_map(|cookie| cookie.trim().strip_prefix(slot.name.as_str())?.strip_prefix('=')))?
+                    .as_bytes(),
+                Place::Body => message.body,
+            };
+            frame.extend(decode(slot.encoding, value)?);
+        }
+        // An empty one is a poll, or an answer with nothing in it
+        Some(frame).filter(|frame| !frame.is_empty())
+    }
+}
+
+impl Cloak for Profile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        let request = &self.request;
+        let pieces = cut(&request.payload, frame);
+
+        let path = &request.paths[rand::rng().random_range(0..request.paths.len())];
+        let mut target = expand(path, seq);
+        let mut cookies: Vec<String> = request.cookies.iter()
+            .map(|(name, value)| format!("{}={}", name, expand(value, seq)))
+            .collect();
+        let mut extra_headers = Vec::new();
+        let mut body = Vec::new();
+        for (slot, piece) in &pieces {
+            match slot.place {
+                Place::Query => {
+                    target.push(if target.contains('?') { '&' } else { '?' });
+                    target.push_str(&format!("{}={}", slot.name, encode_text(slot.encoding, piece)));
+                }
+                Place::Header => extra_headers.push((slot.name.clone(), encode_text(slot.encoding, piece))),
+                Place::Cookie => cookies.push(format!("{}={}", slot.name, encode_text(slot.encoding, piece))),
+                Place::Body => body = encode(slot.encoding, piece),
+            }
+        }
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", request.method, target);
+        for (name, value) in &request.headers {
+            head.push_str(&format!("{}: {}\r\n", name, expand(value, seq)));
+        }
+        for (name, value) in &extra_headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !cookies.is_empty() { head.push_str(&format!("Cookie: {}\r\n", cookies.join("; "))); }
+        finish(head, body)
+    }
+
+    fn wrap_reply(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        let response = &self.response;
+        let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, expand(value, seq)));
+        }
+        let mut body = Vec::new();
+        for (slot, piece) in cut(&response.payload, frame) {
+            match slot.place {
+                Place::Body => body = encode(slot.encoding, piece),
+                _ => head.push_str(&format!("{}: {}\r\n", slot.name, encode_text(slot.encoding, piece))),
+            }
+        }
+        finish(head, body)
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let message = match cloak::parse_http(buf) {
+            Parsed::Incomplete => return Unwrapped::Incomplete,
+            Parsed::Invalid => return Unwrapped::Invalid,
+            Parsed::Message(message) => message,
+        };
+        if message.len > self.max_message { return Unwrapped::Invalid; }
+        let frame = match message.start {
+            StartLine::Request { target } => self.gather(&self.request.payload, &message, target),
+            StartLine::Response { status } if status == self.response.status => self.gather(&self.response.payload, &message, ""),
+            StartLine::Response { .. } => None,
+        };
+        Unwrapped::Message { len: message.len, frame }
+    }
+
+    fn capacity(&self) -> usize {
+        slot_capacity(&self.request.payload).min(slot_capacity(&self.response.payload)).min(self.max_message / 2)
+    }
+
+    fn lengths(&self) -> Option<&Histogram> {
+        self.request.lengths.as_ref()
+    }
+
+    fn reply_lengths(&self) -> Option<&Histogram> {
+        self.response.lengths.as_ref()
+    }
+
+    fn paired(&self) -> bool {
+        true
+    }
+}
+
+/// Frame bytes the slots hold between them
+fn slot_capacity(slots: &[Slot]) -> usize {
+    slots.iter().map(|slot| slot.max.unwrap_or(usize::MAX)).fold(0, usize::saturating_add)
+}
+
+fn check_placeholders(template: &str, problems: &mut Vec<String>) {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let known = PLACEHOLDERS.iter().find(|placeholder| rest[start..].starts_with(*placeholder));
+        let Some(placeholder) = known else {
+            problems.push(format!("'{}' uses an unknown placeholder (have {})", template, PLACEHOLDERS.join(", ")));
+            return;
+        };
+        rest = &rest[start + placeholder.len()..];
+    }
+}
+
+fn check_headers(direction: &str, headers: &[(String, String)], problems: &mut Vec<String>) {
+    for (name, value) in headers {
+        if name.is_empty() || !name.bytes().all(cloak::is_token) {
+            problems.push(format!("{} header name '{}' must be a token", direction, name));
+        }
+        if !value.bytes().all(|b| b == b' ' || b == b'\t' || b.is_ascii_graphic()) || value.trim() != value {
+            problems.push(format!("{} header '{}' must have a printable, trimmed value", direction, name));
+        }
+        // We frame the body ourselves
+        if name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("transfer-encoding") {
+            problems.push(format!("{} header '{}' is set by Proteus", direction, name));
+        }
+        check_placeholders(value, problems);
+    }
+}
+
+fn check_slots(direction: &str, slots: &[Slot], allowed: &[Place], problems: &mut Vec<String>) {
+    if slots.is_empty() {
+        problems.push(format!("{} payload needs at least one slot", direction));
+        return;
+    }
+    let mut capacity = 0usize;
+    for (index, slot) in slots.iter().enumerate() {
+        let label = format!("{} slot {} ({:?})", direction, index + 1, slot.place);
+        if !allowed.contains(&slot.place) {
+            problems.push(format!("{}: a {} cannot carry payload there", label, direction));
+        }
+        if slot.place == Place::Body {
+            if slots.iter().filter(|other| other.place == Place::Body).count() > 1 {
+                problems.push(format!("{}: only one slot can be the body", label));
+            }
+        } else {
+            if slot.name.is_empty() || !slot.name.bytes().all(cloak::is_token) {
+                problems.push(format!("{}: needs a name that is a token", label));
+            }
+            if slot.encoding == Encoding::Raw { problems.push(format!("{}: raw bytes only fit in the body", label)); }
+        }
+        if slot.place == Place::Query && slot.encoding == Encoding::Base64 {
+            problems.push(format!("{}: base64 is not URL-safe; use base64url", label));
+        }
+        match slot.max {
+            Some(0) => problems.push(format!("{}: max must be above 0", label)),
+            Some(max) => capacity = capacity.saturating_add(max),
+            None if index + 1 < slots.len() => problems.push(format!("{}: only the last slot can be unbounded", label)),
+            None => capacity = usize::MAX,
+        }
+    }
+    if capacity < MAX_FRAME {
+        problems.push(format!("{} slots hold {} bytes, but frames run up to {}", direction, capacity, MAX_FRAME));
+    }
+}
+
+/// The frame cut into one piece per slot (later slots may get nothing)
+fn cut<'a, 'b>(slots: &'a [Slot], frame: &'b [u8]) -> Vec<(&'a Slot, &'b [u8])> {
+    let mut rest = frame;
+    slots.iter().map(|slot| {
+        let (piece, tail) = rest.split_at(slot.max.unwrap_or(usize::MAX).min(rest.len()));
+        rest = tail;
+        (slot, piece)
+    }).collect()
+}
+
+fn expand(template: &str, seq: u32) -> String {
+    template
+        .replace("{seq}", &seq.to_string())
+        .replace("{rand}", &rand::rng().random::<u32>().to_string())
+        .replace("{date}", &cloak::http_date(SystemTime::now()))
+}
+
+fn finish(mut head: String, body: Vec<u8>) -> Vec<u8> {
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut message = head.into_bytes();
+    message.extend(body);
+    message
+}
+
+fn encode_text(encoding: Encoding, bytes: &[u8]) -> String {
+    match encoding {
+        // Only ever in the body (see `check_slots`)
+        Encoding::Raw => String::from_utf8_lossy(bytes).into_owned(),
+        Encoding::Base64 => general_purpose::STANDARD.encode(bytes),
+        Encoding::Base64url => general_purpose::URL_SAFE_NO_PAD.encode(bytes),
+        Encoding::Base32 => BASE32_NOPAD.encode(bytes),
+        Encoding::Hex => HEXLOWER_PERMISSIVE.encode(bytes),
+    }
+}
+
+fn encode(encoding: Encoding, bytes: &[u8]) -> Vec<u8> {
+    match encoding {
+        Encoding::Raw => bytes.to_vec(),
+        _ => encode_text(encoding, bytes).into_bytes(),
+    }
+}
+
+fn decode(encoding: Encoding, text: &[u8]) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::Raw => Some(text.to_vec()),
+        Encoding::Base64 => general_purpose::STANDARD.decode(text).ok(),
+        Encoding::Base64url => general_purpose::URL_SAFE_NO_PAD.decode(text).ok(),
+        Encoding::Base32 => BASE32_NOPAD.decode(text).ok(),
+        Encoding::Hex => HEXLOWER_PERMISSIVE.decode(text).ok(),
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use crate::cloak::{self, Cloak, Deframer};
+use crate::{auth, dns_tunnel, egress, http2, pairing, shape, tls, websocket};
+use crate::mux::Side;
+use crate::stream::{self, Carrier, ProteusStream, ResumeHandle, Ticket};
+
+// --- SESSION RESUMPTION ---
+// Every carrier starts with one handshake frame, in the carrier's cloak like
+// everything after it. The client sends RESUME with
+// its ticket (all zeros for a new session) and proof of the relay's secret;
+// the relay answers TICKET with
+// the ticket that names the session, or all zeros if it has no such session. A known ticket reattaches the new
+// carrier to the parked `ProteusStream`, so the mux, its streams and every
+// flow riding them carry on as if nothing happened. A carrier without a
+// valid proof never gets an answer at all (see `Sessions::admit`).
+
+const NEW_SESSION: Ticket = [0u8; 16];
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+// --- CLIENT SIDE ---
+
+/// How to reach the relay: where, over which carrier, dressed how
+#[derive(Clone)]
+pub struct Relay {
+    /// `HOST:PORT`, `tls://HOST:PORT`, `ws://HOST:PORT/PATH`, `wss://HOST:PORT/PATH`,
+    /// `h2c://HOST:PORT/PATH`, `h2://HOST:PORT/PATH` or `dns://RESOLVER:PORT/DOMAIN`
+    pub target: String,
+    pub cloak: Arc<dyn Cloak>,
+    /// Needed for `tls://`, `wss://` and `h2://`
+    pub tls: Option<tls::Client>,
+    pub secret: auth::Secret,
+}
+
+impl Relay {
+    pub fn new(target: String, cloak: Arc<dyn Cloak>, tls: Option<tls::Client>, secret: auth::Secret) -> io::Result<Self> {
+        let scheme = target.split_once("://").map_or("tcp", |(scheme, _)| scheme);
+        match scheme {
+            "tcp" | "ws" | "h2c" => {}
+            "dns" if target.contains('/') => {}
+            "dns" => return Err(io::Error::new(io::ErrorKind::InvalidInput, "dns:// needs the relay's domain: dns://RESOLVER:PORT/DOMAIN")),
+            "tls" | "wss" | "h2" if tls.is_some() => {}
+            "tls" | "wss" | "h2" => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}:// needs the relay's pin", scheme))),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown carrier '{}://'", scheme))),
+        }
+        Ok(Relay { target, cloak, tls, secret })
+    }
+
+    /// Whether the carrier rides UDP (a DNS tunnel) rather than TCP
+    pub fn over_udp(&self) -> bool {
+        self.target.starts_with("dns://")
+    }
+
+    /// The HOST:PORT of the relay (of the resolver, for DNS), whatever carrier the target asks for
+    pub fn address(&self) -> &str {
+        let target = self.target.split_once("://").map_or(self.target.as_str(), |(_, rest)| rest);
+        target.split('/').next().unwrap_or(target)
+    }
+}
+
+/// Connect to the relay and present `ticket` (None = start a new session).
+/// Returns the carrier and the ticket the relay answered with.
+pub async fn dial(relay: &Relay, ticket: Option<Ticket>) -> io::Result<(Carrier, Ticket)> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let address = relay.address();
+        let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+        let scheme = relay.target.split_once("://").map(|(scheme, _)| scheme);
+        let (reader, writer, cloak): (BoxedReader, BoxedWriter, Arc<dyn Cloak>) = match (scheme, &relay.tls) {
+            // A WebSocket is disguise enough: frames ride its messages bare
+            (Some("ws"), _) => {
+                let (reader, writer) = websocket::connect(connect_tcp(address).await?, &relay.target, &relay.secret).await?;
+                (Box::new(reader), Box::new(writer), cloak::framed())
+            }
+            (Some("wss"), Some(tls)) => {
+                let socket = tls.connect(connect_tcp(address).await?, host, tls::HTTP1).await?;
+                let (reader, writer) = websocket::connect(socket, &relay.target, &relay.secret).await?;
+                (Box::new(reader), Box::new(writer), cloak::framed())
+            }
+            // As is HTTP/2
+            (Some("h2c"), _) => {
+                let (reader, writer) = http2::connect(connect_tcp(address).await?, &relay.target, &relay.secret).await?;
+                (Box::new(reader), Box::new(writer), cloak::framed())
+            }
+            (Some("h2"), Some(tls)) => {
+                let socket = tls.connect(connect_tcp(address).await?, host, tls::HTTP2).await?;
+                let (reader, writer) = http2::connect(socket, &relay.target, &relay.secret).await?;
+                (Box::new(reader), Box::new(writer), cloak::framed())
+            }
+            // So is DNS
+            (Some("dns"), _) => {
+                let domain = relay.target.rsplit_once('/').map_or("", |(_, domain)| domain);
+                let (reader, writer) = dns_tunnel::connect(address, domain, &relay.secret).await?;
+                (Box::new(reader), Box::new(writer), cloak::framed())
+            }
+            (Some("tls"), Some(tls)) => {
+                let (reader, writer) = tokio::io::split(tls.connect(connect_tcp(address).await?, host, tls::HTTP1).await?);
+                (Box::new(reader), Box::new(writer), relay.cloak.clone())
+            }
+            (None, _) => {
+                let (reader, writer) = connect_tcp(address).await?.into_split();
+                (Box::new(reader), Box::new(writer), relay.cloak.clone())
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "carrier needs TLS settings")),
+        };
+        // HTTP/1.1 lookalikes keep a request waiting for every answer
+        let (mut reader, mut writer): (BoxedReader, BoxedWriter) = if cloak.paired() {
+            let (reader, writer) = pairing::client(reader, writer, cloak.clone());
+            (Box::new(reader), Box::new(writer))
+        } else {
+            (reader, writer)
+        };
+        // Whatever the carrier, no message length gives the frames away
+        let cloak = shape::shaped(cloak);
+
+        let ticket = ticket.unwrap_or(NEW_SESSION);
+        let proof = relay.secret.prove(auth::RESUME, &ticket);
+        writer.write_all(&cloak.wrap(&stream::resume_frame(&ticket, &proof), 0)).await?;
+        let mut deframer = Deframer::new(cloak.clone());
+        let answer = deframer.read(&mut reader).await?;
+        match answer.as_deref().and_then(stream::issued_ticket) {
+            Some(NEW_SESSION) => Err(io::Error::new(io::ErrorKind::NotFound, "relay does not know this session")),
+            Some(issued) => {
+                // Keep what came behind the answer: the relay may already be resending
+                let reader = io::Cursor::new(deframer.into_buffered()).chain(reader);
+                Ok((Carrier::from_parts(reader, writer, cloak), issued))
+            }
+            // Hung up without answering (a middlebox, or a relay going down): worth retrying
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no handshake from relay")),
+        }
+    }).await.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+async fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+    let socket = TcpStream::connect(address).await?;
+    socket.set_nodelay(true).ok();
+    Ok(socket)
+}
+
+/// Keep a resumable stream supplied with carriers: redial with exponential
+/// backoff and jitter each time it loses one. Gives up (and lets the stream
+/// die) once the relay has surely dropped the session or refuses the ticket.
+pub async fn supervise(relay: Relay, ticket: Ticket, mut handle: ResumeHandle) {
+    while handle.lost.recv().await.is_some() {
+        println!("[RESUME] Carrier to {} lost. Reconnecting...", relay.target);
+        let started = Instant::now();
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+
+        let carrier = loop {
+            match dial(&relay, Some(ticket)).await {
+                Ok((carrier, _)) => break Some(carrier),
+                // The relay answered but has no such session: redialing will not help
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    println!("[RESUME] {}. Session lost.", e);
+                    break None;
+                }
+                Err(e) => {
+                    if started.elapsed() >= stream::RESUME_TIMEOUT {
+                        println!("[RESUME] Relay unreachable for {:?}. Session lost.", stream::RESUME_TIMEOUT);
+                        break None;
+                    }
+                    // Equal jitter: half the backoff fixed, half random
+                    let delay = backoff / 2 + backoff.mul_f64(rand::rng().random_range(0.0..0.5));
+                    println!("[RESUME] Attempt {} failed ({}). Retrying in {:?}.", attempt, e, delay);
+                    tokio::time::sleep(delay).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+            }
+        };
+
+        let Some(carrier) = carrier else { return; };
+        if handle.carriers.send(carrier).await.is_err() { return; }
+        println!("[RESUME] Reattached after {} attempt(s).", attempt);
+        // Losses reported while we were dialing are covered by this carrier
+        while handle.lost.try_recv().is_ok() {}
+    }
+}
+
+// --- RELAY SIDE ---
+
+/// Sessions parked or running on the relay, by ticket
+#[derive(Clone)]
+pub struct Sessions {
+    live: Arc<Mutex<HashMap<Ticket, mpsc::Sender<Carrier>>>>,
+    secret: auth::Secret,
+    path: Arc<str>,
+    policy: Arc<egress::Policy>,
+}
+
+impl Sessions {
+    /// Sessions for clients that know `secret` (and, over WebSocket and
+    /// HTTP/2, ask for `path`), whose flows go wherever `policy` lets them
+    pub fn new(secret: auth::Secret, path: &str, policy: Arc<egress::Policy>) -> Self {
+        Sessions { live: Arc::default(), secret, path: path.into(), policy }
+    }
+
+    /// The ticket a carrier's first frame asks for, if the frame is a
+    /// RESUME with a fresh proof of our secret; None means a stranger
+    pub fn admit(&self, frame: &[u8]) -> Option<Ticket> {
+        let (ticket, proof) = stream::resume_ticket(frame)?;
+        self.secret.accept(&proof, auth::RESUME, &ticket).then_some(ticket)
+    }
+
+    pub fn secret(&self) -> &auth::Secret {
+        &self.secret
+    }
+
+    /// The path WebSocket and HTTP/2 clients ask for
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Where these sessions' flows may go
+    pub fn policy(&self) -> Arc<egress::Policy> {
+        self.policy.clone()
+    }
+
+    /// Handle a carrier that opened with RESUME `ticket`, answering in its cloak
+    pub async fn serve<R, W>(&self, ticket: Ticket, reader: R, mut writer: W, cloak: Arc<dyn Cloak>) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        if ticket == NEW_SESSION {
+            let ticket: Ticket = rand::rng().random();
+            writer.write_all(&cloak.wrap(&stream::ticket_frame(&ticket), 0)).await?;
+
+            let key = self.secret.derive(auth::STREAM_KEY);
+            let (stream, handle) = ProteusStream::resumable(Carrier::from_parts(reader, writer, cloak), key, ticket, Side::Server);
+            self.live.lock().unwrap().insert(ticket, handle.carriers);
+            egress::serve_stream(stream, self.policy.clone()).await;
+            self.live.lock().unwrap().remove(&ticket);
+            return Ok(());
+        }
+
+        let Some(carriers) = self.live.lock().unwrap().get(&ticket).cloned() else {
+            // Unknown or expired: tell the client not to bother retrying
+            println!("[RESUME] Unknown ticket. Refusing carrier.");
+            return writer.write_all(&cloak.wrap(&stream::ticket_frame(&NEW_SESSION), 0)).await;
+        };
+        writer.write_all(&cloak.wrap(&stream::ticket_frame(&ticket), 0)).await?;
+        carriers.send(Carrier::from_parts(reader, writer, cloak)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "session ended"))?;
+        println!("[RESUME] Session reattached to a new carrier.");
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+use rand::Rng;
+use serde::Deserialize;
+use crate::cloak::{Cloak, MAX_MESSAGE, Unwrapped};
+
+// --- LENGTH SHAPING ---
+// Frames come in a handful of fixed sizes (a DATA frame is always one
+// symbol long, an ACK a few bytes), and a cloak's encoding passes that
+// straight through to the wire. Shaping takes the lengths back: for every
+// message it draws a length from a histogram of real traffic and cuts the
+// frame into pieces that come out that long once cloaked, padding the last.
+// Piece: [data len u16][flags u8][data][padding]. A frame ends with the
+// piece marked LAST, so the other side joins pieces without any state of
+// its own. Both ends shape every carrier, streams and datagrams alike (a
+// datagram holds every piece of its frame); the histogram is the cloak's
+// own (a profile's), or the built-in one.
+
+const PIECE_HEADER: usize = 3;
+const LAST: u8 = 1;
+// A length drawn below what the cloak can produce is drawn again this often
+const REDRAWS: usize = 8;
+// A piece that wraps longer than its length is trimmed and wrapped again this often
+const REWRAPS: usize = 8;
+
+/// Longest on-wire length a histogram may ask for
+pub const MAX_LENGTH: usize = 16 * 1024;
+
+/// Roughly what a browser's requests and responses on a busy page come to:
+/// many small, many full-MTU, the rest spread in between
+const BUILTIN: [(usize, usize, u32); 5] = [
+    (80, 200, 15),
+    (200, 500, 20),
+    (500, 900, 20),
+    (900, 1300, 15),
+    (1300, 1460, 30),
+];
+
+/// On-wire message lengths to aim for: bins of `[from, to]` bytes, each
+/// drawn as often as its weight says and uniformly within
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<(usize, usize, u32)>")]
+pub struct Histogram {
+    bins: Vec<(usize, usize, u32)>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new(bins: Vec<(usize, usize, u32)>) -> Result<Self, String> {
+        if bins.is_empty() { return Err("needs at least one bin".to_string()); }
+        for &(from, to, weight) in &bins {
+            if from == 0 || from > to || to > MAX_LENGTH {
+                return Err(format!("bin [{}, {}] must run upwards from 1 to at most {}", from, to, MAX_LENGTH));
+            }
+            if weight == 0 { return Err(format!("bin [{}, {}] needs a weight above 0", from, to)); }
+        }
+        let total = bins.iter().map(|&(_, _, weight)| weight as u64).sum();
+        Ok(Histogram { bins, total })
+    }
+
+    /// What cloaks without a histogram of their own get
+    pub fn builtin() -> Self {
+        Self::new(BUILTIN.to_vec()).unwrap()
+    }
+
+    /// Longest length any bin asks for
+    pub fn longest(&self) -> usize {
+        self.bins.iter().map(|&(_, to, _)| to).max().unwrap_or(0)
+    }
+
+    pub fn draw(&self) -> usize {
+        let mut rng = rand::rng();
+        let mut pick = rng.random_range(0..self.total);
+        for &(from, to, weight) in &self.bins {
+            if pick < weight as u64 { return rng.random_range(from..=to); }
+            pick -= weight as u64;
+        }
+        unreachable!("weights add up to the total")
+    }
+}
+
+impl TryFrom<Vec<(usize, usize, u32)>> for Histogram {
+    type Error = String;
+
+    fn try_from(bins: Vec<(usize, usize, u32)>) -> Result<Self, String> {
+        Self::new(bins)
+    }
+}
+
+/// `cloak`, with every message's length drawn from its histogram
+pub fn shaped(cloak: Arc<dyn Cloak>) -> Arc<dyn Cloak> {
+    let requests = cloak.lengths().cloned().unwrap_or_else(Histogram::builtin);
+    let replies = cloak.reply_lengths().cloned().unwrap_or_else(Histogram::builtin);
+    Arc::new(Shaped { inner: cloak, requests, replies })
+}
+
+struct Shaped {
+    inner: Arc<dyn Cloak>,
+    requests: Histogram,
+    replies: Histogram,
+}
+
+impl Cloak for Shaped {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn wrap(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        self.cut(frame, &self.requests, |piece| self.inner.wrap(piece, seq))
+    }
+
+    fn wrap_reply(&self, frame: &[u8], seq: u32) -> Vec<u8> {
+        self.cut(frame, &self.replies, |piece| self.inner.wrap_reply(piece, seq))
+    }
+
+    fn unwrap(&self, buf: &[u8]) -> Unwrapped {
+        let mut frame = Vec::new();
+        let mut used = 0;
+        loop {
+            match self.inner.unwrap(&buf[used..]) {
+                Unwrapped::Incomplete => return Unwrapped::Incomplete,
+                Unwrapped::Invalid => return Unwrapped::Invalid,
+                // Skipped like any message without a frame, even between pieces
+                Unwrapped::Message { len, frame: None } if used == 0 => return Unwrapped::Message { len, frame: None },
+                Unwrapped::Message { len, frame: None } => used += len,
+                Unwrapped::Message { len, frame: Some(piece) } => {
+                    used += len;
+                    let Some((data, last)) = open_piece(&piece) else { return Unwrapped::Invalid; };
+                    frame.extend_from_slice(data);
+                    if last { return Unwrapped::Message { len: used, frame: Some(frame) }; }
+                    if frame.len() > MAX_MESSAGE { return Unwrapped::Invalid; }
+                }
+            }
+        }
+    }
+}
+
+impl Shaped {
+    /// `frame` as pieces, each wrapped to a length drawn from `lengths`
+    fn cut(&self, frame: &[u8], lengths: &Histogram, wrap: impl Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        // The smallest message the cloak makes: one byte of data
+        let shortest = wrap(&[0; PIECE_HEADER + 1]).len();
+        let mut message = Vec::new();
+        let mut rest = frame;
+        loop {
+            let target = (0..REDRAWS).map(|_| lengths.draw()).find(|&target| target >= shortest).unwrap_or(shortest);
+            let mut size = self.piece_size(target, &wrap);
+            // A cloak need not wrap the same length twice ({rand}, a path
+            // picked at random): check the real thing, and trim the piece by
+            // what it overshot until it fits
+            let mut tries = 1;
+            let (wrapped, taken) = loop {
+                let taken = rest.len().min(size - PIECE_HEADER);
+                let wrapped = wrap(&piece(&rest[..taken], taken == rest.len(), size));
+                if wrapped.len() <= target || size == PIECE_HEADER + 1 || tries == REWRAPS { break (wrapped, taken); }
+                size = size.saturating_sub(wrapped.len() - target).max(PIECE_HEADER + 1);
+                tries += 1;
+            };
+            message.extend(wrapped);
+            rest = &rest[taken..];
+            if rest.is_empty() { return message; }
+        }
+    }
+
+    /// The biggest piece that wraps to at most `target` bytes (one data
+    /// byte at the least), as far as one wrap of each size can tell.
+    /// Encodings only ever grow, so a piece is never longer than its message.
+    fn piece_size(&self, target: usize, wrap: &impl Fn(&[u8]) -> Vec<u8>) -> usize {
+        let probe = vec![0u8; target.min(self.inner.capacity()).min(u16::MAX as usize).max(PIECE_HEADER + 1)];
+        let (mut low, mut high) = (PIECE_HEADER + 1, probe.len());
+        while low < high {
+            let middle = (low + high).div_ceil(2);
+            if wrap(&probe[..middle]).len() <= target { low = middle; } else { high = middle - 1; }
+        }
+        low
+    }
+}
+
+/// `data` as a piece `size` bytes long, padded at random
+fn piece(data: &[u8], last: bool, size: usize) -> Vec<u8> {
+    let mut piece = Vec::with_capacity(size);
+    piece.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    piece.push(if last { LAST } else { 0 });
+    piece.extend_from_slice(data);
+    piece.resize_with(size, || rand::rng().random());
+    piece
+}
+
+/// The data in a piece, and whether it ends its frame
+fn open_piece(piece: &[u8]) -> Option<(&[u8], bool)> {
+    let (header, rest) = piece.split_at_checked(PIECE_HEADER)?;
+    let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+    Some((rest.get(..len)?, header[2] & LAST != 0))
+}
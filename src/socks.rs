@@ -0,0 +1,143 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use crate::egress::{self, Address};
+use crate::mux::Mux;
+
+// --- SOCKS5 (RFC 1928) ---
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+/// Accept SOCKS5 clients on `listen` and carry every flow over `mux`.
+/// Runs until the listener fails.
+pub async fn run_socks(listen: &str, mux: Mux) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    println!("[SOCKS] Listening on {}", listen);
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let mux = mux.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(socket, mux).await {
+                println!("[SOCKS] {} -> {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(mut socket: TcpStream, mux: Mux) -> io::Result<()> {
+    socket.set_nodelay(true).ok();
+
+    // 1. GREETING: we only offer "no authentication" (the proxy is local)
+    let version = socket.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; socket.read_u8().await? as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        socket.write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "client requires authentication"));
+    }
+    socket.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+
+    // 2. REQUEST: [ver][cmd][rsv][address]
+    let mut request = [0u8; 3];
+    socket.read_exact(&mut request).await?;
+    let addr = match Address::read_from(&mut socket).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            send_reply(&mut socket, egress::REPLY_ADDRESS_NOT_SUPPORTED, &Address::unspecified()).await.ok();
+            return Err(e);
+        }
+    };
+
+    match request[1] {
+        egress::CMD_CONNECT => connect(socket, &mux, addr).await,
+        egress::CMD_UDP_ASSOCIATE => associate(socket, &mux).await,
+        _ => {
+            send_reply(&mut socket, egress::REPLY_COMMAND_NOT_SUPPORTED, &Address::unspecified()).await?;
+            Err(io::Error::new(io::ErrorKind::Unsupported, "unsupported SOCKS command"))
+        }
+    }
+}
+
+async fn send_reply(socket: &mut TcpStream, code: u8, bound: &Address) -> io::Result<()> {
+    let mut reply = vec![SOCKS_VERSION, code, 0x00];
+    reply.extend(bound.to_bytes());
+    socket.write_all(&reply).await
+}
+
+async fn connect(mut socket: TcpStream, mux: &Mux, addr: Address) -> io::Result<()> {
+    let mut stream = match egress::connect(mux, egress::CMD_CONNECT, &addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            send_reply(&mut socket, egress::reply_code(&e), &Address::unspecified()).await.ok();
+            return Err(e);
+        }
+    };
+    send_reply(&mut socket, egress::REPLY_SUCCEEDED, &Address::unspecified()).await?;
+    tokio::io::copy_bidirectional(&mut socket, &mut stream).await?;
+    Ok(())
+}
+
+/// UDP ASSOCIATE: relay datagrams through one mux stream for as long as the
+/// control connection stays open
+async fn associate(mut socket: TcpStream, mux: &Mux) -> io::Result<()> {
+    let local_ip = socket.local_addr()?.ip();
+    let udp = Arc::new(UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?);
+
+    let stream = match egress::connect(mux, egress::CMD_UDP_ASSOCIATE, &Address::unspecified()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            send_reply(&mut socket, egress::reply_code(&e), &Address::unspecified()).await.ok();
+            return Err(e);
+        }
+    };
+    send_reply(&mut socket, egress::REPLY_SUCCEEDED, &Address::Socket(udp.local_addr()?)).await?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Replies go to whoever sent the first datagram on this association
+    let (client_tx, mut client_rx) = tokio::sync::watch::channel(None::<SocketAddr>);
+
+    // Relay -> Application
+    let downlink_socket = udp.clone();
+    let downlink = tokio::spawn(async move {
+        while let Ok((src, payload)) = egress::read_datagram(&mut reader).await {
+            let Some(client) = *client_rx.borrow_and_update() else { continue; };
+            // [rsv u16][frag][address][data]
+            let mut packet = vec![0x00, 0x00, 0x00];
+            packet.extend(src.to_bytes());
+            packet.extend(payload);
+            downlink_socket.send_to(&packet, client).await.ok();
+        }
+    });
+
+    // Application -> Relay, until the control connection closes
+    let mut buf = vec![0u8; 65535];
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            received = udp.recv_from(&mut buf) => {
+                let (n, src) = received?;
+                // Fragmented datagrams are optional in the RFC; drop them
+                if n < 4 || buf[2] != 0x00 { continue; }
+                let Some((dest, used)) = Address::from_bytes(&buf[3..n]) else { continue; };
+                client_tx.send_if_modified(|client| {
+                    if client.is_none() { *client = Some(src); true } else { false }
+                });
+                egress::write_datagram(&mut writer, &dest, &buf[3 + used..n]).await?;
+            }
+            closed = socket.read(&mut control) => {
+                if matches!(closed, Ok(0) | Err(_)) { break; }
+            }
+        }
+    }
+
+    downlink.abort();
+    writer.shutdown().await.ok();
+    Ok(())
+}
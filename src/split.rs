@@ -0,0 +1,244 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use futures::TryStreamExt;
+use rtnetlink::{Handle, IpVersion};
+use netlink_packet_route::RouteMessage;
+
+// --- SPLIT TUNNELING ---
+
+// The main routing table (RT_TABLE_MAIN)
+const MAIN_TABLE: u8 = 254;
+
+/// What happens to traffic for a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Carried over the tunnel
+    Tunnel,
+    /// Left to the normal network (never touches the tunnel)
+    Direct,
+    /// Routed into the tunnel and dropped there
+    Block,
+}
+
+/// An IPv4 network, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        let mask = Self::mask(prefix);
+        Self { addr: Ipv4Addr::from(u32::from(addr) & mask), prefix }
+    }
+
+    /// `a.b.c.d/n`, or a bare address as a /32
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse().ok()?),
+            None => (s, 32),
+        };
+        if prefix > 32 { return None; }
+        Some(Self::new(addr.parse().ok()?, prefix))
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask(self.prefix) == u32::from(self.addr)
+    }
+
+    fn mask(prefix: u8) -> u32 {
+        if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    pub cidr: Cidr,
+    pub policy: Policy,
+}
+
+/// The rule engine. The most specific matching rule wins; on a tie Block
+/// beats Direct beats Tunnel. Anything unmatched gets the default policy:
+/// Tunnel when no include rules were given (full tunnel), Direct otherwise.
+pub struct SplitRules {
+    rules: Vec<Rule>,
+    default: Policy,
+}
+
+impl SplitRules {
+    /// Each entry is a CIDR, an address, or a domain name. Domains are
+    /// resolved once, now, and pinned as /32 rules.
+    pub async fn build(include: &[String], exclude: &[String], block: &[String]) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for (entries, policy) in [(include, Policy::Tunnel), (exclude, Policy::Direct), (block, Policy::Block)] {
+            for entry in entries {
+                for cidr in resolve(entry).await? {
+                    rules.push(Rule { cidr, policy });
+                }
+            }
+        }
+        let default = if include.is_empty() { Policy::Tunnel } else { Policy::Direct };
+        Ok(Self { rules, default })
+    }
+
+    pub fn decide(&self, dst: Ipv4Addr) -> Policy {
+        self.rules.iter()
+            .filter(|rule| rule.cidr.contains(dst))
+            .max_by_key(|rule| (rule.cidr.prefix, precedence(rule.policy)))
+            .map(|rule| rule.policy)
+            .unwrap_or(self.default)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn default_policy(&self) -> Policy {
+        self.default
+    }
+}
+
+/// Tie-breaker between equally specific rules
+pub(crate) fn precedence(policy: Policy) -> u8 {
+    match policy {
+        Policy::Tunnel => 0,
+        Policy::Direct => 1,
+        Policy::Block => 2,
+    }
+}
+
+async fn resolve(entry: &str) -> io::Result<Vec<Cidr>> {
+    if let Some(cidr) = Cidr::parse(entry) {
+        return Ok(vec![cidr]);
+    }
+    let cidrs: Vec<Cidr> = tokio::net::lookup_host((entry, 0)).await?
+        .filter_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(Cidr::new(ip, 32)),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    if cidrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no IPv4 address", entry)));
+    }
+    println!("[SPLIT] {} -> {:?}", entry, cidrs.iter().map(|c| c.addr).collect::<Vec<_>>());
+    Ok(cidrs)
+}
+
+/// Destination of a raw IPv4 packet, as read from the TUN
+pub fn packet_destination(packet: &[u8]) -> Option<Ipv4Addr> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 { return None; }
+    Some(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]))
+}
+
+/// Kernel routes that send the right destinations into the TUN.
+/// Installed over netlink; `remove()` takes them all back out.
+pub struct SplitRoutes {
+    handle: Handle,
+    installed: Vec<RouteMessage>,
+}
+
+impl SplitRoutes {
+    pub async fn install(rules: &SplitRules, tun_name: &str, relay: IpAddr) -> io::Result<Self> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        let mut routes = Self { handle, installed: Vec::new() };
+        // Never leave half a routing setup behind
+        if let Err(e) = routes.populate(rules, tun_name, relay).await {
+            routes.remove().await;
+            return Err(e);
+        }
+        println!("[SPLIT] Installed {} routes via {}", routes.installed.len(), tun_name);
+        Ok(routes)
+    }
+
+    async fn populate(&mut self, rules: &SplitRules, tun_name: &str, relay: IpAddr) -> io::Result<()> {
+        let routes = self;
+        let tun = routes.link_index(tun_name).await?;
+        // Where traffic went before we got here: Direct rules keep using it
+        let uplink = routes.default_gateway().await?;
+
+        // 1. KEEP THE CARRIER OUT OF ITS OWN TUNNEL
+        if let IpAddr::V4(relay) = relay
+            && rules.decide(relay) != Policy::Direct
+        {
+            match uplink {
+                Some((gateway, oif)) => routes.add(Cidr::new(relay, 32), Some(gateway), oif).await?,
+                None => println!("[SPLIT] WARNING: no default gateway, relay {} may loop into the tunnel", relay),
+            }
+        }
+
+        // 2. FULL TUNNEL: two /1s beat the default route without replacing it
+        if rules.default_policy() == Policy::Tunnel {
+            routes.add(Cidr::new(Ipv4Addr::new(0, 0, 0, 0), 1), None, tun).await?;
+            routes.add(Cidr::new(Ipv4Addr::new(128, 0, 0, 0), 1), None, tun).await?;
+        }
+
+        // 3. ONE ROUTE PER RULE
+        for rule in rules.rules() {
+            match rule.policy {
+                Policy::Tunnel | Policy::Block => routes.add(rule.cidr, None, tun).await?,
+                Policy::Direct => match uplink {
+                    Some((gateway, oif)) => routes.add(rule.cidr, Some(gateway), oif).await?,
+                    None => println!("[SPLIT] WARNING: no default gateway for direct route {}", rule.cidr),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn remove(self) {
+        for route in self.installed.into_iter().rev() {
+            if let Err(e) = self.handle.route().del(route).execute().await {
+                println!("[SPLIT] Failed to remove route: {}", e);
+            }
+        }
+        println!("[SPLIT] Routes removed.");
+    }
+
+    async fn add(&mut self, cidr: Cidr, gateway: Option<Ipv4Addr>, oif: u32) -> io::Result<()> {
+        let mut request = self.handle.route().add().v4()
+            .destination_prefix(cidr.addr, cidr.prefix)
+            .output_interface(oif);
+        if let Some(gateway) = gateway {
+            request = request.gateway(gateway);
+        }
+        let message = request.message_mut().clone();
+        match request.execute().await {
+            Ok(()) => self.installed.push(message),
+            // Someone already routes this exact prefix; leave theirs alone
+            Err(rtnetlink::Error::NetlinkError(e)) if e.to_io().kind() == io::ErrorKind::AlreadyExists => {
+                println!("[SPLIT] {} already routed, skipping", cidr);
+            }
+            Err(e) => return Err(io::Error::other(format!("route {}: {}", cidr, e))),
+        }
+        Ok(())
+    }
+
+    async fn link_index(&self, name: &str) -> io::Result<u32> {
+        let link = self.handle.link().get().match_name(name.to_string()).execute()
+            .try_next().await
+            .map_err(|e| io::Error::other(format!("link {}: {}", name, e)))?;
+        link.map(|link| link.header.index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no interface {}", name)))
+    }
+
+    async fn default_gateway(&self) -> io::Result<Option<(Ipv4Addr, u32)>> {
+        let mut routes = self.handle.route().get(IpVersion::V4).execute();
+        while let Some(route) = routes.try_next().await.map_err(io::Error::other)? {
+            if route.header.table != MAIN_TABLE || route.header.destination_prefix_length != 0 { continue; }
+            if let (Some(IpAddr::V4(gateway)), Some(oif)) = (route.gateway(), route.output_interface()) {
+                return Ok(Some((gateway, oif)));
+            }
+        }
+        Ok(None)
+    }
+}
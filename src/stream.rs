@@ -0,0 +1,708 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce
+};
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+use crate::{SYMBOL_SIZE, framing, oracle::NetworkOracle};
+use crate::auth::{self, Proof};
+use crate::cloak::{Cloak, Deframer, SearchCloak};
+use crate::mux::Side;
+
+// --- STREAM CONFIGURATION ---
+
+/// Plaintext bytes carried by a single FEC object.
+pub const SEGMENT_SIZE: usize = 1024;
+
+/// Objects the receiver is willing to buffer ahead of the application.
+pub const WINDOW_OBJECTS: u32 = 64;
+
+// Largest sealed object: length prefix + nonce + segment + Poly1305 tag.
+const MAX_OBJECT_SIZE: usize = 2 + 24 + SEGMENT_SIZE + 16;
+
+// Bytes buffered between the application handle and the driver task.
+const LOCAL_BUFFER: usize = 64 * 1024;
+
+// After this many unanswered repair rounds the peer is considered gone.
+const MAX_REPAIRS: u32 = 10;
+
+// ACK "seq_id" used for pure window updates (nothing newly decoded).
+const NO_OBJECT: u32 = u32::MAX;
+
+/// How long a resumable stream waits for a replacement carrier before giving up.
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+
+// --- FRAME KINDS ---
+// The kind byte sits right after the 12-byte header. Kind 0 is never used:
+// on the legacy VPN path that byte is the RaptorQ source block number.
+pub const FRAME_DATA: u8 = 1;
+pub const FRAME_ACK: u8 = 2;
+pub const FRAME_PROBE: u8 = 3;
+// Carrier handshake, first frame only (see `resume`)
+pub const FRAME_RESUME: u8 = 4;
+pub const FRAME_TICKET: u8 = 5;
+
+/// Names a resumable session on the relay. All zeros asks for a new one.
+pub type Ticket = [u8; 16];
+
+/// One unit on the wire, before cloaking.
+///
+/// DATA:   [PacketHeader][1][transfer_len u32][RaptorQ symbol]
+/// ACK:    [AckPacket][2][cumulative u32][window u32]
+/// PROBE:  [PacketHeader][3]
+/// RESUME: [PacketHeader][4][ticket][proof]  client -> relay
+/// TICKET: [PacketHeader][5][ticket]         relay -> client
+#[derive(Debug)]
+enum Frame {
+    Data { header: framing::PacketHeader, transfer_length: u32, symbol: EncodingPacket },
+    Ack { ack: framing::AckPacket, cumulative: u32, window: u32 },
+    Probe,
+    Resume { ticket: Ticket, proof: Proof },
+    Ticket { ticket: Ticket },
+}
+
+impl Frame {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Frame::Data { header, transfer_length, symbol } => {
+                let mut bytes = header.to_bytes().to_vec();
+                bytes.push(FRAME_DATA);
+                bytes.extend_from_slice(&transfer_length.to_be_bytes());
+                bytes.extend(symbol.serialize());
+                bytes
+            }
+            Frame::Ack { ack, cumulative, window } => {
+                let mut bytes = ack.to_bytes().to_vec();
+                bytes.push(FRAME_ACK);
+                bytes.extend_from_slice(&cumulative.to_be_bytes());
+                bytes.extend_from_slice(&window.to_be_bytes());
+                bytes
+            }
+            Frame::Probe => {
+                let mut bytes = framing::PacketHeader::new(0).to_bytes().to_vec();
+                bytes.push(FRAME_PROBE);
+                bytes
+            }
+            Frame::Resume { ticket, proof } => {
+                let mut bytes = framing::PacketHeader::new(0).to_bytes().to_vec();
+                bytes.push(FRAME_RESUME);
+                bytes.extend_from_slice(ticket);
+                bytes.extend_from_slice(proof);
+                bytes
+            }
+            Frame::Ticket { ticket } => {
+                let mut bytes = framing::PacketHeader::new(0).to_bytes().to_vec();
+                bytes.push(FRAME_TICKET);
+                bytes.extend_from_slice(ticket);
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (head, rest) = bytes.split_at_checked(framing::HEADER_SIZE)?;
+        let (&kind, body) = rest.split_first()?;
+        match kind {
+            FRAME_DATA => {
+                let header = framing::PacketHeader::from_bytes(head)?;
+                let (len_bytes, symbol) = body.split_at_checked(4)?;
+                // A RaptorQ packet is a 4-byte payload id plus exactly one symbol
+                if symbol.len() != 4 + SYMBOL_SIZE as usize { return None; }
+                Some(Frame::Data {
+                    header,
+                    transfer_length: u32::from_be_bytes(len_bytes.try_into().ok()?),
+                    symbol: EncodingPacket::deserialize(symbol),
+                })
+            }
+            FRAME_ACK => {
+                if body.len() < 8 { return None; }
+                Some(Frame::Ack {
+                    ack: framing::AckPacket::from_bytes(head)?,
+                    cumulative: u32::from_be_bytes(body[0..4].try_into().ok()?),
+                    window: u32::from_be_bytes(body[4..8].try_into().ok()?),
+                })
+            }
+            FRAME_PROBE => Some(Frame::Probe),
+            FRAME_RESUME => Some(Frame::Resume {
+                ticket: body.get(..16)?.try_into().ok()?,
+                proof: body.get(16..16 + auth::PROOF_SIZE)?.try_into().ok()?,
+            }),
+            FRAME_TICKET => Some(Frame::Ticket { ticket: body.get(..16)?.try_into().ok()? }),
+            _ => None,
+        }
+    }
+
+    fn seq(&self) -> u32 {
+        match self {
+            Frame::Data { header, .. } => header.seq_id,
+            Frame::Ack { ack, .. } => ack.seq_id,
+            Frame::Probe | Frame::Resume { .. } | Frame::Ticket { .. } => 0,
+        }
+    }
+}
+
+// --- HANDSHAKE FRAMES ---
+// The carrier's cloak decides what frames look like on the wire; these are
+// the raw frames, for whoever speaks the handshake (see `resume`).
+
+/// The handshake frame a client opens a carrier with: the ticket, and
+/// proof that the client knows the relay's secret (see `auth`)
+pub fn resume_frame(ticket: &Ticket, proof: &Proof) -> Vec<u8> {
+    Frame::Resume { ticket: *ticket, proof: *proof }.to_bytes()
+}
+
+/// The relay's answer: the ticket that now names this session
+pub fn ticket_frame(ticket: &Ticket) -> Vec<u8> {
+    Frame::Ticket { ticket: *ticket }.to_bytes()
+}
+
+/// Ticket and proof of a RESUME frame, if that is what `frame` is
+pub fn resume_ticket(frame: &[u8]) -> Option<(Ticket, Proof)> {
+    match Frame::from_bytes(frame)? {
+        Frame::Resume { ticket, proof } => Some((ticket, proof)),
+        _ => None,
+    }
+}
+
+/// Ticket of a TICKET frame, if that is what `frame` is
+pub fn issued_ticket(frame: &[u8]) -> Option<Ticket> {
+    match Frame::from_bytes(frame)? {
+        Frame::Ticket { ticket } => Some(ticket),
+        _ => None,
+    }
+}
+
+/// One connection a stream runs over. A resumable stream swaps in a new one
+/// when the old one dies (see `ProteusStream::resumable`).
+pub struct Carrier {
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    cloak: Arc<dyn Cloak>,
+}
+
+impl Carrier {
+    pub fn new<T>(carrier: T, cloak: Arc<dyn Cloak>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(carrier);
+        Self::from_parts(reader, writer, cloak)
+    }
+
+    pub fn from_parts<R, W>(reader: R, writer: W, cloak: Arc<dyn Cloak>) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self { reader: Box::new(reader), writer: Box::new(writer), cloak }
+    }
+}
+
+/// The owner's end of a resumable stream: `lost` fires whenever the stream
+/// needs a new carrier, `carriers` delivers one. Dropping `carriers` while the
+/// stream waits ends it.
+pub struct ResumeHandle {
+    pub carriers: mpsc::Sender<Carrier>,
+    pub lost: mpsc::UnboundedReceiver<()>,
+}
+
+// The driver's end of the same pair
+struct Resume {
+    carriers: mpsc::Receiver<Carrier>,
+    lost: mpsc::UnboundedSender<()>,
+}
+
+/// A reliable, ordered byte stream carried as encrypted RaptorQ objects.
+///
+/// Writes are cut into `SEGMENT_SIZE` objects, sealed with XChaCha20-Poly1305
+/// (bound to the session's ticket, the sender's side and the object's id, so
+/// no object passes in another session or back towards its sender) and sent
+/// as FEC symbols. Objects the peer cannot decode in time get fresh
+/// repair symbols; decoded objects are reordered and released in sequence.
+/// Must be created inside a Tokio runtime: the protocol runs on a spawned task.
+pub struct ProteusStream {
+    app: DuplexStream,
+}
+
+impl ProteusStream {
+    /// A stream that outlives its carrier: `side`'s end of the session
+    /// `ticket` names. When the carrier dies the stream
+    /// parks for up to `RESUME_TIMEOUT`; a replacement sent through the handle
+    /// picks up where it left off, with every unacknowledged object resent.
+    pub fn resumable(carrier: Carrier, key: [u8; 32], ticket: Ticket, side: Side) -> (Self, ResumeHandle) {
+        let (carriers_tx, carriers) = mpsc::channel(1);
+        let (lost, lost_rx) = mpsc::unbounded_channel();
+        let stream = Self::start(carrier, Sealing { key, ticket, side }, Some(Resume { carriers, lost }));
+        (stream, ResumeHandle { carriers: carriers_tx, lost: lost_rx })
+    }
+
+    fn start(carrier: Carrier, sealing: Sealing, resume: Option<Resume>) -> Self {
+        let (app, inner) = tokio::io::duplex(LOCAL_BUFFER);
+        let (app_reader, app_writer) = tokio::io::split(inner);
+
+        let mut driver = Driver::new(sealing, app_reader, app_writer, resume);
+        driver.attach(carrier);
+        tokio::spawn(driver.run());
+
+        Self { app }
+    }
+}
+
+impl AsyncRead for ProteusStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.app).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProteusStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.app).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.app).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.app).poll_shutdown(cx)
+    }
+}
+
+/// Pull cloaked messages off the carrier and hand parsed frames to the driver
+async fn read_frames<R: AsyncRead + Unpin>(mut reader: R, cloak: Arc<dyn Cloak>, frames: mpsc::Sender<Frame>) {
+    let mut deframer = Deframer::new(cloak);
+    while let Ok(Some(bytes)) = deframer.read(&mut reader).await {
+        // Garbage is ignored, exactly like the relay does
+        if let Some(frame) = Frame::from_bytes(&bytes) && frames.send(frame).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// What a stream's objects are sealed with, and what they are bound to
+struct Sealing {
+    key: [u8; 32],
+    ticket: Ticket,
+    side: Side,
+}
+
+/// What an object's seal covers besides the plaintext: which side sent it,
+/// in which session, as which object
+fn object_aad(sender: Side, ticket: &Ticket, id: u32) -> [u8; 21] {
+    let mut aad = [0u8; 21];
+    aad[0] = match sender { Side::Client => 0, Side::Server => 1 };
+    aad[1..17].copy_from_slice(ticket);
+    aad[17..].copy_from_slice(&id.to_be_bytes());
+    aad
+}
+
+/// An object we sent that the peer has not acknowledged yet
+struct InFlight {
+    encoder: Encoder,
+    transfer_length: u32,
+    sent_at: Instant,
+    next_repair: u32,
+    repairs: u32,
+}
+
+impl InFlight {
+    fn deadline(&self, rto: Duration) -> Instant {
+        // Exponential backoff on every unanswered repair round
+        self.sent_at + rto * (1 << self.repairs.min(6))
+    }
+}
+
+struct Driver<A, B> {
+    cipher: XChaCha20Poly1305,
+    ticket: Ticket,
+    side: Side,
+    brain: NetworkOracle,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    cloak: Arc<dyn Cloak>,
+    frames: mpsc::Receiver<Frame>,
+    app_reader: A,
+    app_writer: B,
+    resume: Option<Resume>,
+
+    // SEND SIDE
+    next_object: u32,
+    in_flight: BTreeMap<u32, InFlight>,
+    peer_cumulative: u32,
+    peer_window: u32,
+    last_probe: Instant,
+    local_eof: bool,
+
+    // RECEIVE SIDE
+    next_expected: u32,
+    decoders: HashMap<u32, Decoder>,
+    reorder: BTreeMap<u32, Vec<u8>>,
+    deliver: VecDeque<Vec<u8>>,
+    advertised: u32,
+    remote_eof: bool,
+    app_gone: bool,
+}
+
+impl<A, B> Driver<A, B>
+where
+    A: AsyncRead + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    fn new(sealing: Sealing, app_reader: A, app_writer: B, resume: Option<Resume>) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&sealing.key.into()),
+            ticket: sealing.ticket,
+            side: sealing.side,
+            brain: NetworkOracle::new(),
+            // Placeholders until `attach()` hands us a real carrier
+            writer: Box::new(tokio::io::sink()),
+            cloak: Arc::new(SearchCloak),
+            frames: mpsc::channel(1).1,
+            app_reader,
+            app_writer,
+            resume,
+            next_object: 0,
+            in_flight: BTreeMap::new(),
+            peer_cumulative: 0,
+            peer_window: WINDOW_OBJECTS,
+            last_probe: Instant::now(),
+            local_eof: false,
+            next_expected: 0,
+            decoders: HashMap::new(),
+            reorder: BTreeMap::new(),
+            deliver: VecDeque::new(),
+            advertised: WINDOW_OBJECTS,
+            remote_eof: false,
+            app_gone: false,
+        }
+    }
+
+    async fn run(mut self) {
+        let mut chunk = vec![0u8; SEGMENT_SIZE];
+
+        let mut app_closed = false;
+
+        loop {
+            let local_done = self.local_eof && self.in_flight.is_empty();
+            let remote_done = (self.remote_eof && self.deliver.is_empty()) || self.app_gone;
+            if remote_done && !app_closed {
+                // Peer finished: let the application see EOF right away
+                self.app_writer.shutdown().await.ok();
+                app_closed = true;
+            }
+            if local_done && remote_done { break; }
+
+            let can_send = !self.local_eof && self.window_open();
+            let has_timer = !self.in_flight.is_empty() || self.window_blocked();
+            let deadline = self.next_deadline();
+
+            tokio::select! {
+                frame = self.frames.recv() => match frame {
+                    Some(frame) => {
+                        if self.on_frame(frame).await.is_err() && !self.reattach().await { break; }
+                    }
+                    None => {
+                        println!("[STREAM] Carrier closed.");
+                        if !self.reattach().await { break; }
+                    }
+                },
+                // The owner found a better carrier before we noticed this one die
+                carrier = next_carrier(&mut self.resume), if self.resume.is_some() => match carrier {
+                    Some(carrier) => {
+                        self.attach(carrier);
+                        if self.catch_up().await.is_err() && !self.reattach().await { break; }
+                    }
+                    None => self.resume = None,
+                },
+                read = self.app_reader.read(&mut chunk), if can_send => {
+                    let n = read.unwrap_or(0);
+                    // An empty object marks the end of our half of the stream
+                    if n == 0 { self.local_eof = true; }
+                    if self.send_object(&chunk[..n]).await.is_err() && !self.reattach().await { break; }
+                },
+                written = self.app_writer.write(self.deliver.front().map(|c| c.as_slice()).unwrap_or(&[])), if !self.deliver.is_empty() => {
+                    match written {
+                        Ok(n) if n > 0 => {
+                            let front = self.deliver.front_mut().unwrap();
+                            front.drain(..n);
+                            if front.is_empty() {
+                                self.deliver.pop_front();
+                                if self.advertised < WINDOW_OBJECTS / 2
+                                    && self.send_ack(NO_OBJECT, 0).await.is_err()
+                                    && !self.reattach().await
+                                {
+                                    break;
+                                }
+                            }
+                        }
+                        _ => {
+                            // The application dropped its handle; nobody is listening anymore
+                            self.deliver.clear();
+                            self.app_gone = true;
+                        }
+                    }
+                },
+                _ = sleep_until(deadline), if has_timer => {
+                    if self.on_timer().await.is_err() && !self.reattach().await { break; }
+                },
+            }
+        }
+
+        if !app_closed { self.app_writer.shutdown().await.ok(); }
+        self.writer.shutdown().await.ok();
+    }
+
+    // --- CARRIER ---
+
+    fn attach(&mut self, carrier: Carrier) {
+        let (frame_tx, frames) = mpsc::channel(WINDOW_OBJECTS as usize * 4);
+        tokio::spawn(read_frames(carrier.reader, carrier.cloak.clone(), frame_tx));
+        // Dropping the old halves closes whatever is left of the old carrier
+        self.frames = frames;
+        self.writer = carrier.writer;
+        self.cloak = carrier.cloak;
+    }
+
+    /// A frame as the carrier's cloak puts it on the wire
+    fn encode(&self, frame: &Frame) -> Vec<u8> {
+        self.cloak.wrap(&frame.to_bytes(), frame.seq())
+    }
+
+    /// The carrier failed: park until a replacement arrives. False if the
+    /// stream is not resumable or nothing came within `RESUME_TIMEOUT`.
+    async fn reattach(&mut self) -> bool {
+        loop {
+            let Some(resume) = self.resume.as_mut() else { return false; };
+            resume.lost.send(()).ok();
+            let carrier = match tokio::time::timeout(RESUME_TIMEOUT, resume.carriers.recv()).await {
+                Ok(Some(carrier)) => carrier,
+                _ => {
+                    println!("[STREAM] No carrier to resume on. Giving up.");
+                    return false;
+                }
+            };
+            self.attach(carrier);
+            if self.catch_up().await.is_ok() {
+                println!("[STREAM] Resumed on a new carrier ({} objects resent).", self.in_flight.len());
+                return true;
+            }
+        }
+    }
+
+    /// A fresh carrier has none of our history: resend every unacknowledged
+    /// object in full and tell the peer where our receive side stands.
+    async fn catch_up(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let ids: Vec<u32> = self.in_flight.keys().copied().collect();
+        for id in ids {
+            let flight = self.in_flight.get_mut(&id).unwrap();
+            flight.repairs = 0;
+            flight.sent_at = now;
+            let packets = flight.encoder.get_encoded_packets(0);
+            let transfer_length = flight.transfer_length;
+            self.send_symbols(id, transfer_length, packets).await?;
+        }
+        self.send_ack(NO_OBJECT, 0).await
+    }
+
+    // --- SEND SIDE ---
+
+    fn window_open(&self) -> bool {
+        let limit = self.peer_cumulative.saturating_add(self.peer_window);
+        self.next_object < limit && (self.in_flight.len() as u32) < WINDOW_OBJECTS
+    }
+
+    fn window_blocked(&self) -> bool {
+        !self.local_eof && self.in_flight.is_empty() && !self.window_open()
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let rto = self.brain.get_retransmit_timeout();
+        match self.in_flight.values().map(|f| f.deadline(rto)).min() {
+            Some(deadline) => deadline,
+            None => self.last_probe + rto,
+        }
+    }
+
+    /// Seal one segment and push its source (+ redundancy) symbols
+    async fn send_object(&mut self, plaintext: &[u8]) -> io::Result<()> {
+        let id = self.next_object;
+        self.next_object += 1;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = object_aad(self.side, &self.ticket, id);
+        let encrypted = self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad: &aad }).unwrap();
+        let mut blob = nonce.to_vec();
+        blob.extend(encrypted);
+        let mut final_payload = (blob.len() as u16).to_be_bytes().to_vec();
+        final_payload.extend(blob);
+
+        let encoder = Encoder::with_defaults(&final_payload, SYMBOL_SIZE);
+        let source_symbols = final_payload.len().div_ceil(SYMBOL_SIZE as usize) as u32;
+        let redundancy = (source_symbols as f64 * self.brain.loss_rate).ceil() as u32;
+        let packets = encoder.get_encoded_packets(redundancy);
+
+        let flight = InFlight {
+            encoder,
+            transfer_length: final_payload.len() as u32,
+            sent_at: Instant::now(),
+            next_repair: redundancy,
+            repairs: 0,
+        };
+        // Tracked before it hits the wire, so a carrier dying mid-send loses nothing
+        let transfer_length = flight.transfer_length;
+        self.in_flight.insert(id, flight);
+        self.send_symbols(id, transfer_length, packets).await
+    }
+
+    async fn send_symbols(&mut self, id: u32, transfer_length: u32, packets: Vec<EncodingPacket>) -> io::Result<()> {
+        for symbol in packets {
+            let frame = Frame::Data { header: framing::PacketHeader::new(id), transfer_length, symbol };
+            let message = self.encode(&frame);
+            self.writer.write_all(&message).await?;
+        }
+        self.writer.flush().await
+    }
+
+    /// Repair every object whose ACK is overdue, or probe a closed window
+    async fn on_timer(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let rto = self.brain.get_retransmit_timeout();
+
+        if self.in_flight.is_empty() {
+            self.last_probe = now;
+            let message = self.encode(&Frame::Probe);
+            self.writer.write_all(&message).await?;
+            return self.writer.flush().await;
+        }
+
+        let overdue: Vec<u32> = self.in_flight.iter()
+            .filter(|(_, f)| f.deadline(rto) <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in overdue {
+            self.brain.record_loss();
+            let flight = self.in_flight.get_mut(&id).unwrap();
+            if flight.repairs >= MAX_REPAIRS {
+                println!("[STREAM] Object {} undecodable after {} repair rounds. Giving up.", id, flight.repairs);
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            // Rateless: fresh repair symbols help no matter which ones were lost
+            let source_symbols = flight.transfer_length.div_ceil(SYMBOL_SIZE as u32);
+            let count = source_symbols.div_ceil(2) + 1;
+            let mut packets = Vec::new();
+            for block in flight.encoder.get_block_encoders() {
+                packets.extend(block.repair_packets(flight.next_repair, count));
+            }
+            flight.next_repair += count;
+            flight.repairs += 1;
+            flight.sent_at = now;
+            let transfer_length = flight.transfer_length;
+            self.send_symbols(id, transfer_length, packets).await?;
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, ack: framing::AckPacket, cumulative: u32, window: u32) {
+        if ack.timestamp != 0 {
+            let rtt = framing::now_micros().saturating_sub(ack.timestamp);
+            self.brain.update_rtt(Duration::from_micros(rtt));
+        }
+        if ack.seq_id != NO_OBJECT {
+            self.in_flight.remove(&ack.seq_id);
+        }
+        if cumulative >= self.peer_cumulative {
+            self.peer_cumulative = cumulative;
+            self.peer_window = window;
+            self.in_flight = self.in_flight.split_off(&cumulative);
+        }
+    }
+
+    // --- RECEIVE SIDE ---
+
+    async fn on_frame(&mut self, frame: Frame) -> io::Result<()> {
+        match frame {
+            Frame::Data { header, transfer_length, symbol } => {
+                if self.on_data(header.seq_id, transfer_length, symbol) {
+                    self.send_ack(header.seq_id, header.timestamp).await?;
+                }
+            }
+            Frame::Ack { ack, cumulative, window } => self.on_ack(ack, cumulative, window),
+            Frame::Probe => self.send_ack(NO_OBJECT, 0).await?,
+            // Handshake lines belong to whoever set the carrier up
+            Frame::Resume { .. } | Frame::Ticket { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Feed one symbol to its object's decoder. Returns true if the object should be ACKed.
+    fn on_data(&mut self, id: u32, transfer_length: u32, symbol: EncodingPacket) -> bool {
+        // Already have it: the ACK was probably lost, so repeat it
+        if id < self.next_expected || self.reorder.contains_key(&id) { return true; }
+        if id >= self.next_expected.saturating_add(WINDOW_OBJECTS) { return false; }
+        if transfer_length as usize > MAX_OBJECT_SIZE { return false; }
+
+        let config = ObjectTransmissionInformation::with_defaults(transfer_length as u64, SYMBOL_SIZE);
+        // The decoder indexes blocks by this byte and would panic on a bogus one
+        if symbol.payload_id().source_block_number() >= config.source_blocks() { return false; }
+
+        let decoder = self.decoders.entry(id).or_insert_with(|| Decoder::new(config));
+        let Some(decoded) = decoder.decode(symbol) else { return false; };
+        self.decoders.remove(&id);
+
+        match self.open_object(id, &decoded) {
+            Some(plaintext) => { self.reorder.insert(id, plaintext); }
+            None => return false,
+        }
+
+        while let Some(plaintext) = self.reorder.remove(&self.next_expected) {
+            self.next_expected += 1;
+            if plaintext.is_empty() {
+                self.remote_eof = true;
+            } else if !self.app_gone {
+                self.deliver.push_back(plaintext);
+            }
+        }
+        true
+    }
+
+    fn open_object(&self, id: u32, decoded: &[u8]) -> Option<Vec<u8>> {
+        let real_len = u16::from_be_bytes(decoded.get(0..2)?.try_into().ok()?) as usize;
+        let valid_payload = decoded.get(2..2 + real_len)?;
+        let (nonce_bytes, ciphertext) = valid_payload.split_at_checked(24)?;
+        let nonce = XNonce::from_slice(nonce_bytes);
+        // Sealed by the other side
+        let sender = match self.side { Side::Client => Side::Server, Side::Server => Side::Client };
+        let aad = object_aad(sender, &self.ticket, id);
+        self.cipher.decrypt(nonce, Payload { msg: ciphertext, aad: &aad }).ok()
+    }
+
+    async fn send_ack(&mut self, seq_id: u32, timestamp: u64) -> io::Result<()> {
+        let window = WINDOW_OBJECTS.saturating_sub(self.deliver.len() as u32);
+        self.advertised = window;
+        let frame = Frame::Ack {
+            ack: framing::AckPacket::new(seq_id, timestamp),
+            cumulative: self.next_expected,
+            window,
+        };
+        let message = self.encode(&frame);
+        self.writer.write_all(&message).await?;
+        self.writer.flush().await
+    }
+}
+
+async fn next_carrier(resume: &mut Option<Resume>) -> Option<Carrier> {
+    match resume {
+        Some(resume) => resume.carriers.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use base64::{Engine as _, engine::general_purpose};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector, client, server};
+
+// --- TLS CARRIER ---
+// An optional TLS layer under the cloak, so a carrier looks like any HTTPS
+// connection and the framing inside never touches the wire. There is no CA:
+// the relay presents whatever certificate it was given (self-signed is
+// fine) and the client trusts exactly one public key, the one it was told
+// to pin. A pin is `sha256/` and the base64 SHA-256 of the certificate's
+// SubjectPublicKeyInfo, as HPKP spelled them, so it survives renewals that
+// keep the key.
+
+/// First byte of every TLS handshake record: how the relay tells a
+/// ClientHello from a cloak on the same port
+pub const HANDSHAKE_RECORD: u8 = 0x16;
+/// What a client asks for, like any browser talking to any web server;
+/// the relay offers both
+pub const HTTP1: &[u8] = b"http/1.1";
+pub const HTTP2: &[u8] = b"h2";
+const PIN_PREFIX: &str = "sha256/";
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// The pin of a certificate: `sha256/` + base64(SHA-256(SPKI))
+pub fn pin_of(cert: &CertificateDer) -> io::Result<String> {
+    Ok(format!("{}{}", PIN_PREFIX, general_purpose::STANDARD.encode(spki_digest(cert)?)))
+}
+
+fn spki_digest(cert: &CertificateDer) -> io::Result<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).map_err(invalid)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.subject_public_key_info());
+    Ok(digest.as_ref().try_into().unwrap())
+}
+
+// --- CLIENT SIDE ---
+
+/// Dials TLS to a relay whose key we pinned
+#[derive(Clone)]
+pub struct Client {
+    config: Arc<ClientConfig>,
+    sni: Option<String>,
+}
+
+impl Client {
+    /// Trust only the key behind `pin`. `sni` is the name to ask for
+    /// (default: the relay's host; an IP address sends none).
+    pub fn pinned(pin: &str, sni: Option<String>) -> io::Result<Self> {
+        let digest = general_purpose::STANDARD.decode(pin.strip_prefix(PIN_PREFIX).unwrap_or(pin))
+            .ok()
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .ok_or_else(|| invalid(format!("bad pin '{}': expected {}<base64 SHA-256>", pin, PIN_PREFIX)))?;
+
+        let provider = provider();
+        let verifier = PinnedKey { digest, algorithms: provider.signature_verification_algorithms };
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Client { config: Arc::new(config), sni })
+    }
+
+    /// Run the handshake over `socket`, a connection to `host`, asking for
+    /// `alpn` (`HTTP1` or `HTTP2`)
+    pub async fn connect(&self, socket: TcpStream, host: &str, alpn: &[u8]) -> io::Result<client::TlsStream<TcpStream>> {
+        let name = self.sni.as_deref().unwrap_or(host).trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        TlsConnector::from(Arc::new(config)).connect(name, socket).await
+    }
+}
+
+/// Accepts the pinned key and nothing else. Names, issuers and dates do not
+/// matter: whoever proves they hold the key is our relay.
+#[derive(Debug)]
+struct PinnedKey {
+    digest: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match spki_digest(end_entity) {
+            Ok(digest) if digest == self.digest => Ok(ServerCertVerified::assertion()),
+            Ok(_) => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+            Err(_) => Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding)),
+        }
+    }
+
+    // The handshake must still be signed by the pinned key itself
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// --- RELAY SIDE ---
+
+/// Terminates TLS with the relay's certificate
+#[derive(Clone)]
+pub struct Server {
+    acceptor: TlsAcceptor,
+    pin: String,
+}
+
+impl Server {
+    /// Load a PEM certificate chain (leaf first) and its private key
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Self> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(format!("{}: {}", cert.display(), e)))?;
+        let leaf = chain.first().ok_or_else(|| invalid(format!("{}: no certificate", cert.display())))?;
+        let pin = pin_of(leaf)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(format!("{}: {}", key.display(), e)))?;
+
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(invalid)?;
+        config.alpn_protocols = vec![HTTP2.to_vec(), HTTP1.to_vec()];
+        Ok(Server { acceptor: TlsAcceptor::from(Arc::new(config)), pin })
+    }
+
+    /// What clients pass as `--pin`
+    pub fn pin(&self) -> &str {
+        &self.pin
+    }
+
+    pub async fn accept(&self, socket: TcpStream) -> io::Result<server::TlsStream<TcpStream>> {
+        self.acceptor.accept(socket).await
+    }
+}
@@ -0,0 +1,593 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{HardwareAddress, Icmpv4Packet, Icmpv4Repr, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket, UdpPacket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, mpsc};
+use crate::dns::{self, DnsResolver};
+use crate::egress::{self, Address};
+use crate::mux::Mux;
+use crate::nat;
+
+// --- STACK CONFIGURATION ---
+
+/// Our side of the point-to-point link (the peer `ProteusVpn` routes towards).
+pub const STACK_ADDR: Ipv4Address = Ipv4Address::new(10, 0, 0, 254);
+pub const STACK_PREFIX: u8 = 24;
+pub const MTU: usize = 1500;
+
+const TCP_BUFFER: usize = 64 * 1024;
+const UDP_PACKETS: usize = 64;
+// Each endpoint socket buffers this many MTU-sized packets both ways
+const ENDPOINT_PACKETS: usize = 16;
+const FLOW_CHANNEL: usize = 16;
+
+// Table limits: a client (or, on the relay, anyone writing to a NAT port)
+// cannot make the stack hold more than this. New TCP flows past the limit
+// are refused; UDP makes room by dropping whichever entry was idle longest.
+const MAX_TCP_FLOWS: usize = 1024;
+const MAX_UDP_FLOWS: usize = 256;
+const MAX_UDP_ENDPOINTS: usize = 512;
+const MAX_ICMP_FLOWS: usize = 64;
+
+// A TCP peer silent for this long is aborted; UDP flows expire when idle.
+const TCP_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_IDLE: Duration = Duration::from_secs(60);
+// A socket still listening this long after its SYN never took it: give the slot back
+const LISTEN_GRACE: Duration = Duration::from_secs(5);
+
+/// Where terminated flows are re-originated
+#[derive(Clone)]
+pub enum Egress {
+    /// VPN client: every flow becomes a stream on the tunnel
+    Mux(Mux),
+    /// Relay gateway: flows leave from our own sockets (userspace NAT, see
+    /// `nat`), to wherever the policy lets them
+    Direct(Arc<egress::Policy>),
+}
+
+// A relayed TCP flow, whichever way it left
+trait FlowStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FlowStream for T {}
+
+/// What travels from a flow task back to the stack
+enum FlowEvent {
+    Data(Vec<u8>),
+    Eof,
+}
+
+/// Packet queues smoltcp reads from and writes to. We fill `rx` ourselves so
+/// every packet can be inspected (and a socket allocated) before the stack sees it.
+struct QueueDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct QueueRxToken(Vec<u8>);
+struct QueueTxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::RxToken for QueueRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for QueueTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = QueueRxToken where Self: 'a;
+    type TxToken<'a> = QueueTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((QueueRxToken(packet), QueueTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(QueueTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+}
+
+/// One terminated TCP connection and the channels to its relay task
+struct TcpFlow {
+    handle: SocketHandle,
+    uplink: Option<mpsc::Sender<Vec<u8>>>,
+    downlink: mpsc::Receiver<FlowEvent>,
+    pending: Vec<u8>,
+    relay_done: bool,
+    opened: Instant,
+}
+
+/// One local UDP source and its association on the relay
+struct UdpFlow {
+    uplink: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    last_active: Instant,
+}
+
+/// One ping session: (client, remote, echo identifier)
+struct IcmpFlow {
+    uplink: mpsc::Sender<(u16, Vec<u8>)>,
+    last_active: Instant,
+}
+
+/// A smoltcp socket answering for one remote UDP endpoint
+struct UdpEndpoint {
+    handle: SocketHandle,
+    last_active: Instant,
+}
+
+/// Userspace TCP/UDP termination.
+///
+/// Raw IP packets go in, every TCP connection and UDP flow is terminated
+/// locally by smoltcp and re-originated through `Egress`, and the stack's
+/// replies come back out as raw IP packets. On the VPN client only
+/// application payload crosses the tunnel; on the relay this is the NAT.
+pub struct NetStack {
+    iface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    via: Egress,
+    // Answers queries to the virtual resolver (mux egress only)
+    dns: Option<Arc<DnsResolver>>,
+    notify: Arc<Notify>,
+    tcp_flows: HashMap<(IpEndpoint, IpEndpoint), TcpFlow>,
+    udp_flows: HashMap<SocketAddr, UdpFlow>,
+    udp_endpoints: HashMap<IpEndpoint, UdpEndpoint>,
+    udp_down_tx: mpsc::Sender<(SocketAddr, SocketAddr, Vec<u8>)>,
+    udp_down_rx: mpsc::Receiver<(SocketAddr, SocketAddr, Vec<u8>)>,
+    icmp_flows: HashMap<(Ipv4Address, Ipv4Address, u16), IcmpFlow>,
+    // Complete packets built outside smoltcp (ICMP replies)
+    raw_down_tx: mpsc::Sender<Vec<u8>>,
+    raw_down_rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl NetStack {
+    pub fn new(via: Egress) -> Self {
+        let mut device = QueueDevice { rx: VecDeque::new(), tx: VecDeque::new() };
+        let config = Config::new(HardwareAddress::Ip);
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+
+        iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.push(IpCidr::new(STACK_ADDR.into(), STACK_PREFIX)).unwrap();
+        });
+        // AnyIP + a default route through ourselves = accept traffic for every destination
+        iface.set_any_ip(true);
+        iface.routes_mut().add_default_ipv4_route(STACK_ADDR).unwrap();
+
+        let (udp_down_tx, udp_down_rx) = mpsc::channel(UDP_PACKETS);
+        let (raw_down_tx, raw_down_rx) = mpsc::channel(UDP_PACKETS);
+        let dns = match &via {
+            Egress::Mux(mux) => Some(Arc::new(DnsResolver::new(mux.clone()))),
+            Egress::Direct(_) => None,
+        };
+
+        Self {
+            iface,
+            device,
+            sockets: SocketSet::new(vec![]),
+            via,
+            dns,
+            notify: Arc::new(Notify::new()),
+            tcp_flows: HashMap::new(),
+            udp_flows: HashMap::new(),
+            udp_endpoints: HashMap::new(),
+            udp_down_tx,
+            udp_down_rx,
+            icmp_flows: HashMap::new(),
+            raw_down_tx,
+            raw_down_rx,
+        }
+    }
+
+    /// Pump packets until the input side closes
+    pub async fn run(mut self, mut packets_in: mpsc::Receiver<Vec<u8>>, packets_out: mpsc::Sender<Vec<u8>>) {
+        println!("[STACK] Userspace TCP/UDP termination active on {}", STACK_ADDR);
+
+        loop {
+            let timestamp = Instant::now();
+            self.iface.poll(timestamp, &mut self.device, &mut self.sockets);
+            self.service_tcp(timestamp);
+            self.service_udp(timestamp);
+            self.service_icmp(timestamp);
+            // Servicing queues data into sockets; let smoltcp emit it right away
+            self.iface.poll(timestamp, &mut self.device, &mut self.sockets);
+
+            while let Some(packet) = self.device.tx.pop_front() {
+                if packets_out.send(packet).await.is_err() { return; }
+            }
+
+            let delay = self.iface.poll_delay(Instant::now(), &self.sockets)
+                .map(Duration::from)
+                .unwrap_or(Duration::from_secs(1));
+
+            tokio::select! {
+                packet = packets_in.recv() => match packet {
+                    Some(packet) => self.inbound(packet, Instant::now()),
+                    None => break,
+                },
+                _ = self.notify.notified() => {},
+                _ = tokio::time::sleep(delay) => {},
+            }
+        }
+        println!("[STACK] Packet source closed.");
+    }
+
+    /// Look at a packet before smoltcp does and allocate whatever socket it needs
+    fn inbound(&mut self, packet: Vec<u8>, now: Instant) {
+        if let Ok(ip) = Ipv4Packet::new_checked(&packet[..]) {
+            let (src_addr, dst_addr) = (IpAddress::Ipv4(ip.src_addr()), IpAddress::Ipv4(ip.dst_addr()));
+            match ip.next_header() {
+                IpProtocol::Tcp => {
+                    if let Ok(tcp) = TcpPacket::new_checked(ip.payload())
+                        && tcp.syn() && !tcp.ack()
+                    {
+                        let src = IpEndpoint::new(src_addr, tcp.src_port());
+                        let dst = IpEndpoint::new(dst_addr, tcp.dst_port());
+                        self.open_tcp(src, dst, now);
+                    }
+                }
+                IpProtocol::Udp => {
+                    if let Ok(udp) = UdpPacket::new_checked(ip.payload()) {
+                        let dst = IpEndpoint::new(dst_addr, udp.dst_port());
+                        let src = IpEndpoint::new(src_addr, udp.src_port());
+                        if ip.dst_addr() == dns::RESOLVER_ADDR && udp.dst_port() == dns::DNS_PORT
+                            && let Some(resolver) = self.dns.clone()
+                        {
+                            self.query(resolver, src, dst, udp.payload().to_vec());
+                            return;
+                        }
+                        self.udp_endpoint(dst, now);
+                        self.open_udp(to_socket_addr(src), now);
+                    }
+                }
+                // Pings to the outside only make sense where we own the egress
+                IpProtocol::Icmp if matches!(self.via, Egress::Direct(_)) && ip.dst_addr() != STACK_ADDR => {
+                    let checksums = ChecksumCapabilities::default();
+                    if let Ok(icmp) = Icmpv4Packet::new_checked(ip.payload())
+                        && let Ok(Icmpv4Repr::EchoRequest { ident, seq_no, data }) = Icmpv4Repr::parse(&icmp, &checksums)
+                    {
+                        self.echo(ip.src_addr(), ip.dst_addr(), ident, seq_no, data.to_vec(), now);
+                    }
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.device.rx.push_back(packet);
+    }
+
+    // --- TCP ---
+
+    fn open_tcp(&mut self, src: IpEndpoint, dst: IpEndpoint, now: Instant) {
+        // A retransmitted SYN must not allocate a second socket
+        if self.tcp_flows.contains_key(&(src, dst)) { return; }
+        // With no socket listening, smoltcp answers the SYN with a RST
+        if self.tcp_flows.len() >= MAX_TCP_FLOWS { return; }
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+        );
+        socket.set_nagle_enabled(false);
+        socket.set_timeout(Some(TCP_TIMEOUT.into()));
+        if socket.listen(IpListenEndpoint { addr: Some(dst.addr), port: dst.port }).is_err() { return; }
+        let handle = self.sockets.add(socket);
+
+        let (uplink_tx, uplink_rx) = mpsc::channel(FLOW_CHANNEL);
+        let (downlink_tx, downlink_rx) = mpsc::channel(FLOW_CHANNEL);
+        tokio::spawn(relay_tcp(self.via.clone(), to_socket_addr(dst), uplink_rx, downlink_tx, self.notify.clone()));
+
+        self.tcp_flows.insert((src, dst), TcpFlow {
+            handle,
+            uplink: Some(uplink_tx),
+            downlink: downlink_rx,
+            pending: Vec::new(),
+            relay_done: false,
+            opened: now,
+        });
+    }
+
+    fn service_tcp(&mut self, now: Instant) {
+        let mut finished = Vec::new();
+
+        for (key, flow) in self.tcp_flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(flow.handle);
+
+            // Reap only after a poll, so a RST queued by abort() still goes out
+            if socket.state() == tcp::State::Closed || socket.state() == tcp::State::TimeWait {
+                finished.push(*key);
+                continue;
+            }
+            // The SYN that opened the flow was never taken (and won't be)
+            if socket.state() == tcp::State::Listen && now - flow.opened > LISTEN_GRACE.into() {
+                finished.push(*key);
+                continue;
+            }
+
+            // Application -> Relay (stop reading when the flow task is backed up)
+            while socket.can_recv() {
+                let Some(uplink) = &flow.uplink else { break; };
+                let Ok(permit) = uplink.try_reserve() else { break; };
+                if let Ok(chunk) = socket.recv(|data| (data.len(), data.to_vec())) {
+                    permit.send(chunk);
+                }
+            }
+            // The application closed its side: pass the FIN along
+            if !socket.may_recv() && socket.state() != tcp::State::Listen && socket.state() != tcp::State::SynReceived {
+                flow.uplink = None;
+            }
+
+            // Relay -> Application
+            loop {
+                if !flow.pending.is_empty() {
+                    match socket.send_slice(&flow.pending) {
+                        Ok(n) => { flow.pending.drain(..n); }
+                        Err(_) => break,
+                    }
+                    if !flow.pending.is_empty() { break; }
+                }
+                if flow.relay_done || !socket.may_send() { break; }
+                match flow.downlink.try_recv() {
+                    Ok(FlowEvent::Data(data)) => flow.pending = data,
+                    Ok(FlowEvent::Eof) => {
+                        flow.relay_done = true;
+                        socket.close();
+                    }
+                    Err(mpsc::error::TryRecvError::Empty) => break,
+                    Err(mpsc::error::TryRecvError::Disconnected) => {
+                        // Relay refused or dropped the flow
+                        flow.relay_done = true;
+                        socket.abort();
+                    }
+                }
+            }
+
+        }
+
+        for key in finished {
+            if let Some(flow) = self.tcp_flows.remove(&key) {
+                self.sockets.remove(flow.handle);
+            }
+        }
+    }
+
+    // --- UDP ---
+
+    fn udp_endpoint(&mut self, remote: IpEndpoint, now: Instant) -> SocketHandle {
+        if let Some(endpoint) = self.udp_endpoints.get_mut(&remote) {
+            endpoint.last_active = now;
+            return endpoint.handle;
+        }
+        if self.udp_endpoints.len() >= MAX_UDP_ENDPOINTS
+            && let Some(oldest) = self.udp_endpoints.iter().min_by_key(|(_, endpoint)| endpoint.last_active).map(|(remote, _)| *remote)
+            && let Some(endpoint) = self.udp_endpoints.remove(&oldest)
+        {
+            self.sockets.remove(endpoint.handle);
+        }
+        let mut socket = udp::Socket::new(
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; ENDPOINT_PACKETS], vec![0; ENDPOINT_PACKETS * MTU]),
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; ENDPOINT_PACKETS], vec![0; ENDPOINT_PACKETS * MTU]),
+        );
+        socket.bind(IpListenEndpoint { addr: Some(remote.addr), port: remote.port }).ok();
+        let handle = self.sockets.add(socket);
+        self.udp_endpoints.insert(remote, UdpEndpoint { handle, last_active: now });
+        handle
+    }
+
+    fn open_udp(&mut self, local: SocketAddr, now: Instant) {
+        if let Some(flow) = self.udp_flows.get_mut(&local) {
+            flow.last_active = now;
+            return;
+        }
+        // Dropping the longest idle flow's sender ends its relay task
+        if self.udp_flows.len() >= MAX_UDP_FLOWS
+            && let Some(oldest) = self.udp_flows.iter().min_by_key(|(_, flow)| flow.last_active).map(|(local, _)| *local)
+        {
+            self.udp_flows.remove(&oldest);
+        }
+        let (uplink_tx, uplink_rx) = mpsc::channel(UDP_PACKETS);
+        let (downlink, notify) = (self.udp_down_tx.clone(), self.notify.clone());
+        match &self.via {
+            Egress::Mux(mux) => tokio::spawn(relay_udp(mux.clone(), local, uplink_rx, downlink, notify)),
+            Egress::Direct(policy) => tokio::spawn(nat::relay_udp(local, uplink_rx, downlink, notify, policy.clone())),
+        };
+        self.udp_flows.insert(local, UdpFlow { uplink: uplink_tx, last_active: now });
+    }
+
+    fn service_udp(&mut self, now: Instant) {
+        // Application -> Relay
+        for (remote, endpoint) in self.udp_endpoints.iter() {
+            let socket = self.sockets.get_mut::<udp::Socket>(endpoint.handle);
+            while let Ok((data, meta)) = socket.recv() {
+                let local = to_socket_addr(meta.endpoint);
+                if let Some(flow) = self.udp_flows.get(&local) {
+                    flow.uplink.try_send((to_socket_addr(*remote), data.to_vec())).ok();
+                }
+            }
+        }
+
+        // Relay -> Application: answer from the remote's own address
+        while let Ok((local, remote, data)) = self.udp_down_rx.try_recv() {
+            let handle = self.udp_endpoint(remote.into(), now);
+            let socket = self.sockets.get_mut::<udp::Socket>(handle);
+            let mut meta = udp::UdpMetadata::from(IpEndpoint::from(local));
+            meta.local_address = Some(IpEndpoint::from(remote).addr);
+            socket.send_slice(&data, meta).ok();
+            if let Some(flow) = self.udp_flows.get_mut(&local) { flow.last_active = now; }
+        }
+
+        // Expire idle flows; dropping the sender ends the relay task
+        let idle = |last: Instant| now - last > UDP_IDLE.into();
+        self.udp_flows.retain(|_, flow| !idle(flow.last_active));
+        let expired: Vec<IpEndpoint> = self.udp_endpoints.iter()
+            .filter(|(_, endpoint)| idle(endpoint.last_active))
+            .map(|(remote, _)| *remote)
+            .collect();
+        for remote in expired {
+            if let Some(endpoint) = self.udp_endpoints.remove(&remote) {
+                self.sockets.remove(endpoint.handle);
+            }
+        }
+    }
+
+    // --- DNS ---
+
+    /// A query to the virtual resolver: answered over the session, never relayed as UDP
+    fn query(&self, resolver: Arc<DnsResolver>, client: IpEndpoint, resolver_addr: IpEndpoint, query: Vec<u8>) {
+        let (downlink, notify) = (self.udp_down_tx.clone(), self.notify.clone());
+        tokio::spawn(async move {
+            match resolver.resolve(&query).await {
+                Ok(response) => {
+                    if downlink.send((to_socket_addr(client), to_socket_addr(resolver_addr), response)).await.is_ok() {
+                        notify.notify_one();
+                    }
+                }
+                Err(e) => println!("[DNS] {} -> {}", to_socket_addr(client), e),
+            }
+        });
+    }
+
+    // --- ICMP (Direct egress only) ---
+
+    fn echo(&mut self, client: Ipv4Address, remote: Ipv4Address, ident: u16, seq_no: u16, data: Vec<u8>, now: Instant) {
+        let Egress::Direct(policy) = &self.via else { return; };
+        if policy.check(SocketAddr::new(IpAddr::V4(remote), 0)).is_err() { return; }
+        if !self.icmp_flows.contains_key(&(client, remote, ident)) && self.icmp_flows.len() >= MAX_ICMP_FLOWS { return; }
+        let flow = self.icmp_flows.entry((client, remote, ident)).or_insert_with(|| {
+            let (uplink_tx, uplink_rx) = mpsc::channel(UDP_PACKETS);
+            tokio::spawn(nat::relay_icmp(client, remote, ident, uplink_rx, self.raw_down_tx.clone(), self.notify.clone()));
+            IcmpFlow { uplink: uplink_tx, last_active: now }
+        });
+        flow.last_active = now;
+        flow.uplink.try_send((seq_no, data)).ok();
+    }
+
+    fn service_icmp(&mut self, now: Instant) {
+        while let Ok(packet) = self.raw_down_rx.try_recv() {
+            self.device.tx.push_back(packet);
+        }
+        self.icmp_flows.retain(|_, flow| now - flow.last_active <= nat::ICMP_IDLE.into());
+    }
+}
+
+fn to_socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    let ip = match endpoint.addr {
+        IpAddress::Ipv4(addr) => IpAddr::V4(addr),
+        IpAddress::Ipv6(addr) => IpAddr::V6(addr),
+    };
+    SocketAddr::new(ip, endpoint.port)
+}
+
+/// Carry one TCP flow over its own mux stream, or straight out of a local socket
+async fn relay_tcp(via: Egress, dst: SocketAddr, mut uplink: mpsc::Receiver<Vec<u8>>, downlink: mpsc::Sender<FlowEvent>, notify: Arc<Notify>) {
+    let opened: std::io::Result<Box<dyn FlowStream>> = match via {
+        Egress::Mux(mux) => egress::connect(&mux, egress::CMD_CONNECT, &Address::Socket(dst)).await
+            .map(|stream| Box::new(stream) as Box<dyn FlowStream>),
+        Egress::Direct(policy) => egress::dial(&Address::Socket(dst), &policy).await
+            .map(|stream| Box::new(stream) as Box<dyn FlowStream>),
+    };
+    let stream = match opened {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("[STACK] {} -> {}", dst, e);
+            // Dropping `downlink` makes the stack reset the connection
+            notify.notify_one();
+            return;
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let reaped = downlink.clone();
+
+    let up_notify = notify.clone();
+    let up = async move {
+        while let Some(chunk) = uplink.recv().await {
+            // Room in the channel again: the stack can read more from the socket
+            up_notify.notify_one();
+            if writer.write_all(&chunk).await.is_err() { return; }
+        }
+        writer.shutdown().await.ok();
+    };
+
+    let down = async move {
+        let mut buf = vec![0u8; TCP_BUFFER / 4];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => {
+                    downlink.send(FlowEvent::Eof).await.ok();
+                    break;
+                }
+                Ok(n) => {
+                    if downlink.send(FlowEvent::Data(buf[..n].to_vec())).await.is_err() { break; }
+                }
+                Err(_) => break,
+            }
+            notify.notify_one();
+        }
+        notify.notify_one();
+    };
+
+    // Once the stack reaps the flow, nothing is left to deliver to: end both halves
+    // (and the stream) rather than wait on the remote
+    tokio::select! {
+        _ = async { tokio::join!(up, down) } => {},
+        _ = reaped.closed() => {},
+    }
+}
+
+/// Carry every datagram from one local UDP source over a single association
+async fn relay_udp(mux: Mux, local: SocketAddr, mut uplink: mpsc::Receiver<(SocketAddr, Vec<u8>)>, downlink: mpsc::Sender<(SocketAddr, SocketAddr, Vec<u8>)>, notify: Arc<Notify>) {
+    let stream = match egress::connect(&mux, egress::CMD_UDP_ASSOCIATE, &Address::unspecified()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("[STACK] UDP from {} -> {}", local, e);
+            return;
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let up = async move {
+        while let Some((remote, payload)) = uplink.recv().await {
+            if egress::write_datagram(&mut writer, &Address::Socket(remote), &payload).await.is_err() { return; }
+        }
+        writer.shutdown().await.ok();
+    };
+
+    let down = async move {
+        while let Ok((Address::Socket(remote), payload)) = egress::read_datagram(&mut reader).await {
+            if downlink.send((local, remote, payload)).await.is_err() { break; }
+            notify.notify_one();
+        }
+    };
+
+    // The association lives until the stack expires the flow
+    tokio::select! {
+        _ = up => {},
+        _ = down => {},
+    }
+}
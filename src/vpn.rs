@@ -1,11 +1,18 @@
-use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+
+pub const TUN_NAME: &str = "proteus0";
 
 /// The "Tank" Interface.
 /// Interacts directly with the OS Kernel to capture traffic.
 pub struct ProteusVpn {
     // FIXED: Removed 'dyn' because tun::Device is a Struct, not a Trait.
-    device: Arc<Mutex<tun::Device>>,
+    // No lock: a reader blocked in the kernel must not hold up writes.
+    device: tun::Device,
+}
+
+impl Default for ProteusVpn {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProteusVpn {
@@ -16,6 +23,7 @@ impl ProteusVpn {
         
         let mut config = tun::Configuration::default();
         config
+            .tun_name(TUN_NAME)
             .address((10, 0, 0, 1))       // The Virtual IP of this machine
             .destination((10, 0, 0, 254)) // The Gateway (Peer)
             .netmask((255, 255, 255, 0))
@@ -29,19 +37,17 @@ impl ProteusVpn {
         println!("[SUCCESS] Interface 'proteus0' is UP. System-wide routing active.");
         
         Self {
-            device: Arc::new(Mutex::new(dev)),
+            device: dev,
         }
     }
 
     /// Pull a raw packet from the OS (e.g., a browser request)
     pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut dev = self.device.lock().unwrap();
-        dev.read(buf)
+        self.device.recv(buf)
     }
 
     /// Inject a packet back into the OS (e.g., a website response)
     pub fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut dev = self.device.lock().unwrap();
-        dev.write(buf)
+        self.device.send(buf)
     }
 }
\ No newline at end of file
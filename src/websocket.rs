@@ -0,0 +1,292 @@
+use std::io;
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use crate::auth;
+use crate::cloak::{self, Cloak, Framed, Halves, Parsed, StartLine};
+
+// --- WEBSOCKET CARRIER ---
+// RFC 6455 over an HTTP/1.1 upgrade, on the relay's usual port. The relay
+// upgrades only on its own path and only for a request with proof of its
+// secret in the cookie; anyone else is talking to the decoy. Every
+// binary message carries exactly one Proteus frame: the WebSocket is the
+// whole disguise, so there is no cloak inside it (see `cloak::framed`). Two pumps per connection
+// keep the protocol away from the stream: one turns messages into frames
+// (answering pings and closes on the way), the other turns frames into
+// messages, masked when we are the client, and keeps the connection warm
+// with pings of its own.
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const PING_EVERY: Duration = Duration::from_secs(30);
+const PIPE_BUFFER: usize = 256 * 1024;
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// --- CLIENT SIDE ---
+
+/// Upgrade `socket`, already connected to the relay `url` names
+/// (`ws://HOST:PORT/PATH`, or `wss://` once TLS is up underneath), proving
+/// we know its `secret`
+pub async fn connect<S>(mut socket: S, url: &str, secret: &auth::Secret) -> io::Result<Halves>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (scheme, url) = url.split_once("://").unwrap_or(("ws", url));
+    let origin = if scheme == "wss" { "https" } else { "http" };
+    let (authority, path) = match url.find('/') {
+        Some(slash) => (&url[..slash], &url[slash..]),
+        None => (url, "/"),
+    };
+
+    let key = general_purpose::STANDARD.encode(rand::rng().random::<[u8; 16]>());
+    // The relay checks the proof against the path alone, not the query
+    let cookie = secret.cookie(path.split('?').next().unwrap_or(path));
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: {}\r\n\
+         Origin: {}://{}\r\n\
+         Cookie: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         \r\n",
+        path, authority, USER_AGENT, origin, authority, cookie, key
+    );
+    socket.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    let len = loop {
+        let mut chunk = [0u8; 4096];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "relay hung up during the upgrade")); }
+        buf.extend_from_slice(&chunk[..n]);
+
+        match cloak::parse_http(&buf) {
+            Parsed::Incomplete => continue,
+            Parsed::Invalid => return Err(io::Error::new(io::ErrorKind::InvalidData, "relay sent no HTTP response")),
+            Parsed::Message(response) => {
+                let switched = matches!(response.start, StartLine::Response { status: 101 })
+                    && response.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+                    && response.header("sec-websocket-accept") == Some(accept_key(&key).as_str());
+                if !switched { return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "relay refused the WebSocket upgrade")); }
+                break response.len;
+            }
+        }
+    };
+    let (reader, writer) = tokio::io::split(socket);
+    Ok(start(reader, writer, buf.split_off(len), true))
+}
+
+// --- RELAY SIDE ---
+
+/// True once `buf` holds a whole HTTP request asking for a WebSocket
+pub fn is_upgrade(buf: &[u8]) -> bool {
+    match cloak::parse_http(buf) {
+        Parsed::Message(request) => matches!(request.start, StartLine::Request { .. })
+            && request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")),
+        _ => false,
+    }
+}
+
+/// Whether the upgrade request in `buffered` (see `is_upgrade`) is for
+/// `path` and proves it knows `secret`
+pub fn admits(buf: &[u8], path: &str, secret: &auth::Secret) -> bool {
+    let Parsed::Message(request) = cloak::parse_http(buf) else { return false; };
+    let StartLine::Request { target } = request.start else { return false; };
+    let target = target.split('?').next().unwrap_or(target);
+    target == path && request.header("cookie").is_some_and(|cookie| secret.admits(path, cookie))
+}
+
+/// Complete the upgrade a client asked for in `buffered` (see `is_upgrade`)
+pub async fn accept<R, W>(reader: R, mut writer: W, mut buffered: Vec<u8>) -> io::Result<Halves>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let Parsed::Message(request) = cloak::parse_http(&buffered) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no upgrade request"));
+    };
+    let method_ok = matches!(request.start, StartLine::Request { .. }) && buffered.starts_with(b"GET ");
+    let version_ok = request.header("sec-websocket-version") == Some("13");
+    let key = request.header("sec-websocket-key")
+        .filter(|key| general_purpose::STANDARD.decode(key).is_ok_and(|key| key.len() == 16));
+    let (Some(key), true, true) = (key, method_ok, version_ok) else {
+        writer.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.ok();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed WebSocket upgrade"));
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         \r\n",
+        accept_key(key)
+    );
+    let len = request.len;
+    writer.write_all(response.as_bytes()).await?;
+    Ok(start(reader, writer, buffered.split_off(len), false))
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, ACCEPT_GUID)).digest().bytes();
+    general_purpose::STANDARD.encode(digest)
+}
+
+// --- PUMPS ---
+
+/// Hand the upgraded connection to the pumps; `leftover` is what arrived
+/// behind the handshake
+fn start<R, W>(socket_reader: R, socket_writer: W, leftover: Vec<u8>, client: bool) -> Halves
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (ours, theirs) = tokio::io::duplex(PIPE_BUFFER);
+    let (pipe_reader, pipe_writer) = tokio::io::split(ours);
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (frames_tx, frames_rx) = mpsc::channel(64);
+
+    tokio::spawn(read_pump(io::Cursor::new(leftover).chain(socket_reader), pipe_writer, control_tx, client));
+    tokio::spawn(read_frames(pipe_reader, frames_tx));
+    tokio::spawn(write_pump(socket_writer, frames_rx, control_rx, client));
+    tokio::io::split(theirs)
+}
+
+/// Messages off the socket, into the pipe as frames
+async fn read_pump<R, W>(mut socket: R, mut pipe: W, control: mpsc::UnboundedSender<(u8, Vec<u8>)>, client: bool)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut message: Option<Vec<u8>> = None;
+    // Clients must mask, servers must not
+    while let Ok((fin, opcode, payload)) = read_frame(&mut socket, !client).await {
+        match opcode {
+            OP_PING => { control.send((OP_PONG, payload)).ok(); }
+            OP_PONG => {}
+            OP_CLOSE => {
+                // Echo the status code back, as the closing handshake wants
+                control.send((OP_CLOSE, payload.get(..2).unwrap_or_default().to_vec())).ok();
+                break;
+            }
+            OP_BINARY if message.is_none() => message = Some(payload),
+            OP_CONTINUATION if message.is_some() => {
+                let whole = message.as_mut().unwrap();
+                whole.extend(payload);
+                if whole.len() > cloak::MAX_MESSAGE { break; }
+            }
+            // Text, or fragments out of order: nothing a peer of ours sends
+            _ => break,
+        }
+        if fin && let Some(frame) = message.take()
+            && pipe.write_all(&Framed.wrap(&frame, 0)).await.is_err() {
+            break;
+        }
+    }
+    pipe.shutdown().await.ok();
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(socket: &mut R, masked: bool) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    socket.read_exact(&mut head).await?;
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0f);
+    let len = match head[1] & 0x7f {
+        126 => socket.read_u16().await? as usize,
+        127 => socket.read_u64().await? as usize,
+        len => len as usize,
+    };
+
+    let control = opcode & 0x08 != 0;
+    let well_formed = head[0] & 0x70 == 0
+        && (head[1] & 0x80 != 0) == masked
+        && len <= cloak::MAX_MESSAGE
+        && (!control || (fin && len <= 125));
+    if !well_formed { return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed WebSocket frame")); }
+
+    let mut mask = [0u8; 4];
+    if masked { socket.read_exact(&mut mask).await?; }
+    let mut payload = vec![0u8; len];
+    socket.read_exact(&mut payload).await?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() { *byte ^= mask[i % 4]; }
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// Frames out of the pipe, one at a time (a separate task: the write pump
+/// must not be cancelled halfway through reading one)
+async fn read_frames<R: AsyncRead + Unpin>(pipe: R, frames: mpsc::Sender<Vec<u8>>) {
+    let mut pipe = pipe;
+    let mut deframer = cloak::Deframer::new(cloak::framed());
+    while let Ok(Some(frame)) = deframer.read(&mut pipe).await {
+        if frames.send(frame).await.is_err() { break; }
+    }
+}
+
+/// Frames (and control replies) onto the socket as messages
+async fn write_pump<W: AsyncWrite + Unpin>(mut socket: W, mut frames: mpsc::Receiver<Vec<u8>>, mut control: mpsc::UnboundedReceiver<(u8, Vec<u8>)>, client: bool) {
+    let mut ping = tokio::time::interval(PING_EVERY);
+    ping.tick().await;
+    loop {
+        let result = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => write_frame(&mut socket, OP_BINARY, &frame, client).await,
+                // The stream let go of its carrier: close politely
+                None => {
+                    write_frame(&mut socket, OP_CLOSE, &1000u16.to_be_bytes(), client).await.ok();
+                    break;
+                }
+            },
+            reply = control.recv() => match reply {
+                Some((OP_CLOSE, status)) => {
+                    write_frame(&mut socket, OP_CLOSE, &status, client).await.ok();
+                    break;
+                }
+                Some((opcode, payload)) => write_frame(&mut socket, opcode, &payload, client).await,
+                // The read pump is gone, so is the connection
+                None => break,
+            },
+            _ = ping.tick(), if client => {
+                let nonce: [u8; 4] = rand::rng().random();
+                write_frame(&mut socket, OP_PING, &nonce, client).await
+            }
+        };
+        if result.is_err() { break; }
+    }
+    socket.shutdown().await.ok();
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(socket: &mut W, opcode: u8, payload: &[u8], masked: bool) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        let mask: [u8; 4] = rand::rng().random();
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+    socket.write_all(&frame).await?;
+    socket.flush().await
+}